@@ -1,6 +1,15 @@
 use clap::Parser;
 use agent::server;
+use agent::bmc::bmc::BMC;
+use agent::bmc::vendor::Vendor;
+use agent::firestarter::FIRESTARTER_PATH;
+use agent::rapl::msr::{MsrConfig, CPU_ROOT, MSR_ROOT};
+use agent::rapl::power_limit::RAPL_LIMITS_FILE;
+use agent::rapl::rapl::{PowercapConfig, RaplBackend, RaplSource, POWERCAP_ROOT, RAPL_CONTROL_TYPES};
+use log::warn;
 use simple_logger::SimpleLogger;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 
 #[allow(clippy::upper_case_acronyms)]
@@ -9,6 +18,109 @@ use simple_logger::SimpleLogger;
 struct CLI {
     #[arg(long, short, help="eg: '0.0.0.0:8000' or 'oahu10000.local:8080'")]
     listen_address: String,
+
+    #[arg(
+        long,
+        name = "serve power control",
+        help = "Serve this host's BMC to clients, letting them read and cap it, and let them change the RAPL limits. Anyone reaching the agent can then cap the host"
+    )]
+    serve_power_control: bool,
+
+    #[arg(
+        long,
+        requires = "serve power control",
+        help = "Let clients make the BMC power the host off when a cap isn't met in time"
+    )]
+    allow_hard_power_off: bool,
+
+    #[arg(
+        long,
+        default_value = "/usr/bin/ipmitool",
+        name = "ipmi path",
+        help = "Path to the ipmitool used to reach this host's BMC in-band"
+    )]
+    ipmi: String,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        name = "ipmi device",
+        help = "Number of the local IPMI device, eg: 0 for /dev/ipmi0"
+    )]
+    ipmi_device: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        name = "bmc vendor",
+        help = "Vendor profile to parse the ipmitool output with. Detected from the BMC manufacturer ID when not given"
+    )]
+    bmc_vendor: Option<Vendor>,
+
+    #[arg(
+        long,
+        default_value = POWERCAP_ROOT,
+        name = "powercap root",
+        help = "Powercap class directory holding the RAPL zones"
+    )]
+    powercap_root: PathBuf,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values_t = RAPL_CONTROL_TYPES.map(String::from),
+        name = "powercap control types",
+        help = "Powercap control types to read RAPL zones from, comma separated"
+    )]
+    powercap_control_types: Vec<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "powercap",
+        name = "rapl backend",
+        help = "Read RAPL from the powercap zones, or from the msr devices where the powercap driver is missing or locked down"
+    )]
+    rapl_backend: RaplBackend,
+
+    #[arg(
+        long,
+        default_value = RAPL_LIMITS_FILE,
+        name = "rapl limits file",
+        help = "Where the RAPL limits are kept from the first change until they're restored, so they survive a restart of the agent"
+    )]
+    rapl_limits_file: PathBuf,
+
+    #[arg(
+        long,
+        default_value = MSR_ROOT,
+        name = "msr root",
+        help = "Directory of the per CPU msr devices"
+    )]
+    msr_root: PathBuf,
+
+    #[arg(
+        long,
+        default_value = CPU_ROOT,
+        name = "cpu root",
+        help = "Directory of the per CPU sysfs entries, telling the package of each CPU"
+    )]
+    cpu_root: PathBuf,
+
+    #[arg(
+        long,
+        name = "msr dram energy units",
+        help = "Energy status units of the dram registers, overriding those of the CPU model: 16 on Xeons since Haswell, the package's otherwise"
+    )]
+    msr_dram_energy_units: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = FIRESTARTER_PATH,
+        name = "firestarter path",
+        help = "Path to firestarter executable (relative or absolute)"
+    )]
+    firestarter: String,
 }
 
 
@@ -16,7 +128,36 @@ struct CLI {
 async fn main() {
     SimpleLogger::new().env().init().unwrap();
     let args = CLI::parse();
-    let server = server::Server::new(&args.listen_address);
+    let rapl = match args.rapl_backend {
+        RaplBackend::Powercap => RaplSource::Powercap(PowercapConfig {
+            root: args.powercap_root,
+            control_types: args.powercap_control_types,
+        }),
+        RaplBackend::Msr => RaplSource::Msr(
+            MsrConfig::new(&args.msr_root, &args.cpu_root).with_dram_energy_status_units(args.msr_dram_energy_units)
+        ),
+    };
+    let mut server = server::Server::new(&args.listen_address)
+        .with_rapl(rapl)
+        .with_firestarter(&args.firestarter);
+    if args.serve_power_control {
+        server = server.with_rapl_limits(&args.rapl_limits_file);
+        let bmc = BMC::in_band(args.ipmi_device, &args.ipmi);
+        let vendor = match args.bmc_vendor {
+            Some(vendor) => vendor,
+            None => {
+                let detecting = bmc.clone();
+                tokio::task::spawn_blocking(move || detecting.detect_vendor())
+                    .await
+                    .expect("Vendor detection panicked")
+                    .unwrap_or_else(|e| {
+                        warn!("No in-band BMC vendor, using the generic profile: {e}");
+                        Vendor::Generic
+                    })
+            }
+        };
+        server = server.with_bmc(Arc::new(bmc.with_vendor(vendor)), args.allow_hard_power_off);
+    }
     server.run()
     .await;
 }
@@ -1,20 +1,36 @@
 use simple_logger::SimpleLogger;
-use log::{trace, info};
-use std::sync::mpsc::{self, Receiver};
+use log::{trace, info, warn, error};
 use std::path::{Path, PathBuf};
 use std::fs::{self, OpenOptions};
-use std::thread; // OK to mix threads with Tokio
+use std::sync::Arc;
 use tokio::task;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::{Duration, sleep};
+use tokio_util::sync::CancellationToken;
 use reqwest::Client;
 use chrono::Utc;
 use serde::Serialize;
+use enum_iterator::all;
 
 use agent::Timestamps;
 use agent::model::{FirestarterParams, RaplRecord, ServerInfo};
-use agent::bmc::monitor_bmc::monitor_bmc;
-use agent::bmc::{bmc::BMC, BMCStats};
-use agent::test::{load_iterator::LoadTestSuite, thread_iterator::ThreadTestSuite, Test, TestRun, CappingOrder, Operation, TestSuiteInfo, CapStep};
+use agent::bmc::monitor_bmc::{monitor_bmc, monitor_sensors, Monitored};
+use agent::bmc::{bmc::BMC, estimate_clock_offset, BMCStats};
+use agent::bmc::agent_proxy::AgentProxyBMC;
+use agent::bmc::controller::{ControllerType, PowerCapController};
+use agent::bmc::error::{BMCError, BMCResult};
+use agent::bmc::interlock::ensure_safe_exception_action;
+use agent::bmc::lanplus::LanplusBMC;
+use agent::bmc::node_manager::{is_policy_not_found, NmCapController, NmStatistics, NM_CAMPAIGN_POLICY_ID};
+use agent::bmc::rapl_cap::RaplCapController;
+use agent::bmc::redfish::Redfish;
+use agent::bmc::simulated::SimulatedBMC;
+use agent::bmc::sel::{entries_between, SelEntry};
+use agent::bmc::sensors::SensorRecord;
+use agent::bmc::snapshot::CapSnapshot;
+use agent::bmc::verify::{CapAcceptance, VerifiedController};
+use agent::route::RAPL_RESTORE_PATH;
+use agent::test::{load_iterator::LoadTestSuite, thread_iterator::ThreadTestSuite, Test, TestRun, CappingOrder, Operation, TestSuiteInfo, CapStep, CapMechanism};
 use agent::CONFIGURATION;
 
 
@@ -25,19 +41,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     SimpleLogger::new().env().init()?;
 
     let client = make_http_client();
-    let bmc = make_bmc();
 
-    let server_info = get_server_info(&client).await;
+    if let Some(path) = &CONFIGURATION.restore_snapshot {
+        let snapshot = CapSnapshot::load(Path::new(path))?;
+        let hostname = if CONFIGURATION.bmc_hostname.is_empty() { &snapshot.bmc_hostname } else { &CONFIGURATION.bmc_hostname };
+        let bmc = make_controller(hostname).await?;
+        restore_node(&client, bmc.as_ref(), &snapshot).await?;
+        return Ok(());
+    }
+
+    let bmc = make_controller(&CONFIGURATION.bmc_hostname).await?;
+    // Whatever happens to the campaign, the node gets its original cap settings back
+    let snapshot = CapSnapshot::take(bmc.as_ref(), &CONFIGURATION.bmc_hostname).await?;
+    save_snapshot(&snapshot);
+
+    let server_info = get_server_info(&client).await?;
     info!("Host info:\n{server_info:?}");
 
+    let mut campaign = task::spawn(run_campaign(client.clone(), bmc.clone(), server_info));
+    let outcome: Result<(), Box<dyn std::error::Error>> = tokio::select! {
+        result = &mut campaign => match result {
+            Ok(result) => result.map_err(Into::into),
+            Err(e) => Err(format!("Campaign failed: {e}").into()),
+        },
+        signal = shutdown_signal() => {
+            campaign.abort();
+            Err(format!("Campaign interrupted by {signal}").into())
+        }
+    };
+
+    if let Err(e) = restore_node(&client, bmc.as_ref(), &snapshot).await {
+        error!("Restore the node with the `restore` subcommand: {e}");
+    }
+    outcome
+}
+
+/// Runs every test of the load and thread suites, then saves the collected statistics
+///
+/// # Errors
+/// If a BMC error means no further test can succeed
+async fn run_campaign(client: Client, bmc: Arc<dyn PowerCapController>, server_info: ServerInfo) -> BMCResult<()> {
     // buffers to hold the collected statistics
     let mut runs: Vec<TestRun> = Vec::new();
     let mut all_bmc_stats: Vec<Vec<BMCStats>> = Vec::new();
+    let mut all_sensor_stats: Vec<Vec<SensorRecord>> = Vec::new();
+    let mut all_sel_entries: Vec<Vec<SelEntry>> = Vec::new();
     let mut all_rapl_stats: Vec<Vec<RaplRecord>> = Vec::new();
 
 
-    let load_tests = LoadTestSuite::new();
-    let thread_tests = ThreadTestSuite::new(server_info.system_info.online_cpus);
+    ensure_safe_exception_action(bmc.as_ref(), CONFIGURATION.campaign_exception_action, CONFIGURATION.allow_hard_power_off).await?;
+    check_test_plan(&bmc, server_info.system_info.online_cpus).await?;
+
+    let load_tests = LoadTestSuite::new().filter(is_selected);
+    let thread_tests = ThreadTestSuite::new(server_info.system_info.online_cpus).filter(is_selected);
 
     // Calculate extra time required for stepped tests
 
@@ -49,61 +105,245 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut total_runtime_secs = CONFIGURATION.warmup_secs + CONFIGURATION.test_time_secs;
 
 
-    for test in load_tests {
+    for test in load_tests.chain(thread_tests) {
         info!("{test:?}");
 
         if test.step == CapStep::Step {
             total_runtime_secs += step_time;
         }
 
-        let (rapl_stats, bmc_stats, timestamps) = run_test(&test, total_runtime_secs, &client, &bmc).await?;
+        let results = match run_test(&test, total_runtime_secs, &client, &bmc).await {
+            Ok(results) => results,
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => {
+                error!("Test failed, moving on to the next one: {e}");
+                continue;
+            }
+        };
+        let TestResults { rapl_stats, bmc_stats, sensor_stats, timestamps, cap_acceptances, nm_statistics } = results;
         let (start_timestamp, cap_timestamp, end_timestamp) = timestamps;
+        let Monitored { samples: bmc_stats, ticks: bmc_ticks } = bmc_stats;
+        let Monitored { samples: sensor_stats, ticks: sensor_ticks } = sensor_stats;
 
         info!("RAPL stats\n{rapl_stats:?}");
         info!("BMC Stats\n{bmc_stats:?}");
+        info!("BMC ticks: {bmc_ticks:?}");
+        info!("Sensor ticks: {sensor_ticks:?}");
         info!("Start, cap, end timestamps: {start_timestamp}, {cap_timestamp}, {end_timestamp}");
 
-        let test_run = TestRun::new(timestamps, test);
+        let clock_offset = estimate_clock_offset(&bmc_stats);
+        info!("BMC clock offset: {clock_offset:?}");
+
+        let sel_entries = test_sel_entries(bmc.as_ref(), timestamps, clock_offset).await;
+
+        let test_run = TestRun {
+            cap_acceptances,
+            bmc_clock_offset_millis: clock_offset.map(|offset| offset.num_milliseconds()),
+            bmc_ticks,
+            sensor_ticks,
+            nm_statistics,
+            ..TestRun::new(timestamps, test)
+        };
         runs.push(test_run);
         all_bmc_stats.push(bmc_stats);
+        all_sensor_stats.push(sensor_stats);
+        all_sel_entries.push(sel_entries);
         all_rapl_stats.push(rapl_stats);
     }
 
-    for test in thread_tests {
-        info!("{test:?}");
+    // All done, so OK to pass ownership here
+    save_logs(runs, all_rapl_stats, all_bmc_stats, all_sensor_stats, all_sel_entries);
+    log_server_info(server_info);
 
-        if test.step == CapStep::Step {
-            total_runtime_secs += step_time;
+    Ok(())
+}
+
+/// Rejects the campaign up front if the platform can't cap, or won't take one of the
+/// caps of the test plan. Each mechanism's caps are checked against its own range, a
+/// Node Manager domain's against that domain's. A BMC that can't say what it supports
+/// is given the benefit of the doubt. RAPL caps are per package, checked against the
+/// range the agent's kernel gives its packages.
+async fn check_test_plan(bmc: &Arc<dyn PowerCapController>, online_cpus: u64) -> BMCResult<()> {
+    let mechanisms = all::<CapMechanism>().filter(|mechanism| CONFIGURATION.cap_mechanisms.contains(mechanism));
+    for mechanism in mechanisms {
+        let tests: Vec<Test> = LoadTestSuite::new()
+            .chain(ThreadTestSuite::new(online_cpus))
+            .filter(|test| test.cap_mechanism == mechanism)
+            .collect();
+        let capabilities = match (mechanism, mechanism.nm_domain()) {
+            (CapMechanism::Rapl, _) => RaplCapController::new(bmc.clone(), &CONFIGURATION.agent_url).cap_capabilities().await,
+            (_, Some(domain)) => NmCapController::new(bmc.clone(), domain, NM_CAMPAIGN_POLICY_ID).cap_capabilities().await,
+            (_, None) => bmc.cap_capabilities().await,
+        };
+        let capabilities = match capabilities {
+            Ok(capabilities) => capabilities,
+            Err(BMCError::Unsupported(e)) => {
+                warn!("Can't check the {mechanism:?} tests against the platform capabilities: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        info!("{mechanism:?} capabilities: {capabilities:?}");
+        capabilities.check_plan(tests)?;
+    }
+    Ok(())
+}
+
+/// Whether the test caps through one of the mechanisms asked for
+fn is_selected(test: &Test) -> bool {
+    CONFIGURATION.cap_mechanisms.contains(&test.cap_mechanism)
+}
+
+/// The SEL entries logged while the test ran. A platform event would explain an odd test.
+async fn test_sel_entries(bmc: &dyn PowerCapController, timestamps: Timestamps, clock_offset: Option<chrono::Duration>) -> Vec<SelEntry> {
+    let (start_timestamp, _, end_timestamp) = timestamps;
+    match bmc.sel_entries().await {
+        Ok(entries) => {
+            let entries = entries_between(&entries, start_timestamp, end_timestamp, clock_offset);
+            if !entries.is_empty() {
+                warn!("SEL entries during the test:\n{entries:#?}");
+            }
+            entries
+        }
+        Err(BMCError::Unsupported(e)) => {
+            trace!("{e}");
+            Vec::new()
         }
+        Err(e) => {
+            error!("Failed to read the SEL: {e}");
+            Vec::new()
+        }
+    }
+}
 
-        let (rapl_stats, bmc_stats, timestamps) = run_test(&test, total_runtime_secs, &client, &bmc).await?;
-        let (start_timestamp, cap_timestamp, end_timestamp) = timestamps;
+async fn shutdown_signal() -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    }
+}
 
-        info!("RAPL stats\n{rapl_stats:?}");
-        info!("BMC Stats\n{bmc_stats:?}");
-        info!("Start, cap, end timestamps: {start_timestamp}, {cap_timestamp}, {end_timestamp}");
+fn save_snapshot(snapshot: &CapSnapshot) {
+    let stats_path = Path::new(&CONFIGURATION.stats_dir);
+    fs::create_dir_all(stats_path).expect("Failed to create stats directory");
 
-        let test_run = TestRun::new(timestamps, test);
-        runs.push(test_run);
-        all_bmc_stats.push(bmc_stats);
-        all_rapl_stats.push(rapl_stats);
+    let mut path = PathBuf::from(stats_path);
+    path.push(format!("cap_snapshot_{}.json", CONFIGURATION.log_timestamp()));
+    snapshot.save(&path).expect("Failed to save cap settings snapshot");
+    info!("Cap settings saved to {}", path.display());
+}
+
+/// Stops any load still running on the agent, then puts back the snapshot cap settings
+/// and the RAPL limits, and removes the campaign's Node Manager policies
+async fn restore_node(client: &Client, bmc: &dyn PowerCapController, snapshot: &CapSnapshot) -> BMCResult<()> {
+    if CONFIGURATION.agent_url.is_empty() {
+        warn!("No agent given, its load and RAPL limits are left as they are");
+    } else {
+        stop_agent(client).await;
+        restore_agent_rapl_limits(client).await;
     }
+    let nm_result = remove_nm_policies(bmc).await;
+    snapshot.restore(bmc).await?;
+    info!("Restored cap settings {:?}", snapshot.settings);
+    nm_result
+}
 
-    // All done, so OK to pass ownership here
-    save_logs(runs, all_rapl_stats, all_bmc_stats);
-    log_server_info(server_info);
+/// Removes the campaign's policy from every Node Manager domain tested, in case an
+/// interrupted test left one limiting the node. A domain without the policy is fine.
+async fn remove_nm_policies(bmc: &dyn PowerCapController) -> BMCResult<()> {
+    let mut result = Ok(());
+    for domain in CONFIGURATION.cap_mechanisms.iter().filter_map(|mechanism| mechanism.nm_domain()) {
+        match bmc.nm_remove_policy(domain, NM_CAMPAIGN_POLICY_ID).await {
+            Ok(()) => warn!("Removed the Node Manager policy of the {domain:?} domain"),
+            Err(e) if is_policy_not_found(&e) => trace!("No Node Manager policy in the {domain:?} domain"),
+            Err(e) => {
+                error!("Failed to remove the Node Manager policy of the {domain:?} domain: {e}");
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    result
+}
 
-    Ok(())
+async fn stop_agent(client: &Client) {
+    trace!("stop_agent endpoint: {}", &CONFIGURATION.agent_stop_test_endpoint);
+    let response = client.post(&CONFIGURATION.agent_stop_test_endpoint)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match response {
+        Ok(response) => match response.json::<bool>().await {
+            Ok(true) => warn!("Stopped the load running on the agent"),
+            Ok(false) => trace!("No load running on the agent"),
+            Err(e) => error!("Unexpected response stopping the agent load: {e}"),
+        },
+        Err(e) => error!("Failed to stop the agent load: {e}"),
+    }
 }
 
-async fn run_test(config: &Test, runtime_secs: u64, client: &Client, bmc: &BMC) ->
-    Result<(Vec<RaplRecord>, Vec<BMCStats>, Timestamps), Box<dyn std::error::Error>> {
+async fn restore_agent_rapl_limits(client: &Client) {
+    let endpoint = format!("{}{RAPL_RESTORE_PATH}", CONFIGURATION.agent_url);
+    trace!("restore RAPL limits endpoint: {endpoint}");
+    let response = client.post(&endpoint)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match response {
+        Ok(response) => match response.json::<bool>().await {
+            Ok(true) => warn!("Restored the RAPL limits of the agent"),
+            Ok(false) => trace!("No RAPL limits changed on the agent"),
+            Err(e) => error!("Unexpected response restoring the agent RAPL limits: {e}"),
+        },
+        Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => trace!("The agent doesn't let its RAPL limits be changed"),
+        Err(e) => error!("Failed to restore the agent RAPL limits: {e}"),
+    }
+}
+
+/// # Errors
+/// If the BMC fails to set up or perform the capping operation. The agent and the
+/// BMC monitors are always wound down first.
+/// What a test run collected
+struct TestResults {
+    rapl_stats: Vec<RaplRecord>,
+    bmc_stats: Monitored<BMCStats>,
+    sensor_stats: Monitored<SensorRecord>,
+    timestamps: Timestamps,
+    cap_acceptances: Vec<CapAcceptance>,
+    nm_statistics: Option<NmStatistics>,
+}
+
+async fn run_test(config: &Test, runtime_secs: u64, client: &Client, bmc: &Arc<dyn PowerCapController>) -> BMCResult<TestResults> {
 
     trace!("Running test: {config:?}");
+    // A Node Manager test caps through a policy of its own, with the DCMI limit out of the way
+    let nm_capper = match config.cap_mechanism.nm_domain() {
+        Some(domain) => {
+            bmc.deactivate_power_cap().await?;
+            Some(Arc::new(NmCapController::new(bmc.clone(), domain, NM_CAMPAIGN_POLICY_ID)))
+        }
+        None => None,
+    };
+    // So does a RAPL test, through the limits of the agent's packages
+    let rapl_capper = if config.cap_mechanism == CapMechanism::Rapl {
+        bmc.deactivate_power_cap().await?;
+        Some(Arc::new(RaplCapController::new(bmc.clone(), &CONFIGURATION.agent_url)))
+    } else {
+        None
+    };
+    // Drop any measurements left over by a failed test
+    bmc.take_cap_acceptances();
+    let bmc: Arc<dyn PowerCapController> = match (&nm_capper, &rapl_capper) {
+        (Some(nm_capper), _) => with_verification(nm_capper.clone()),
+        (None, Some(rapl_capper)) => with_verification(rapl_capper.clone()),
+        (None, None) => bmc.clone(),
+    };
+    let bmc = &bmc;
 
     let start_timestamp = Utc::now();
-    let (bmc_tx, bmc_rx) = mpsc::channel();
-    let bmc_thread = start_bmc_monitor(bmc_rx);
+    let (monitors_cancel, bmc_thread, sensor_thread) = start_bmc_monitors(bmc);
 
     let fs_params = FirestarterParams {
         runtime_secs,
@@ -113,7 +353,15 @@ async fn run_test(config: &Test, runtime_secs: u64, client: &Client, bmc: &BMC)
     };
 
     trace!("Setting initial conditions");
-    set_initial_conditions(config, bmc).await;
+    if let Err(e) = set_initial_conditions(config, bmc.as_ref()).await {
+        monitors_cancel.cancel();
+        bmc_thread.await.expect("Failed to join BMC thread");
+        sensor_thread.await.expect("Failed to join sensor thread");
+        release_nm_policy(nm_capper.as_deref()).await;
+        release_rapl_limits(rapl_capper.as_deref()).await;
+        return Err(e);
+    }
+    bmc.set_workload(&fs_params).await;
     trace!("launching agent");
     let agent_thread = launch_agent(client.clone(), fs_params);
 
@@ -122,23 +370,79 @@ async fn run_test(config: &Test, runtime_secs: u64, client: &Client, bmc: &BMC)
     sleep(Duration::from_secs(CONFIGURATION.warmup_secs)).await;
     trace!("Doing cap_operation");
     let cap_timestamp = Utc::now();
-    do_cap_operation(config, bmc);
+    let cap_result = do_cap_operation(config, bmc.as_ref()).await;
 
     trace!("Joining agent thread (firestarter exit)");
-    let rapl_stats: Vec<RaplRecord> = agent_thread.await.expect("");
-
-    bmc_tx.send(()).expect("Failed to signal BMC thread");
-    let bmc_stats: Vec<BMCStats> = bmc_thread.await.expect("Failed to join BMC thread");
+    // A failed agent request still leaves the monitors and the cap to wind down
+    let rapl_stats = agent_thread
+        .await
+        .unwrap_or_else(|e| Err(BMCError::Transport(format!("Agent thread failed: {e}"))));
+
+    monitors_cancel.cancel();
+    let bmc_stats: Monitored<BMCStats> = bmc_thread.await.expect("Failed to join BMC thread");
+    let sensor_stats: Monitored<SensorRecord> = sensor_thread.await.expect("Failed to join sensor thread");
     let end_timestamp = Utc::now();
+    let nm_statistics = release_nm_policy(nm_capper.as_deref()).await;
+    release_rapl_limits(rapl_capper.as_deref()).await;
+    cap_result?;
+    let rapl_stats = rapl_stats?;
+
+    let cap_acceptances = bmc.take_cap_acceptances();
+    info!("Cap acceptances\n{cap_acceptances:?}");
+
+    Ok(TestResults {
+        rapl_stats,
+        bmc_stats,
+        sensor_stats,
+        timestamps: (start_timestamp, cap_timestamp, end_timestamp),
+        cap_acceptances,
+        nm_statistics,
+    })
+}
 
-    Ok((rapl_stats, bmc_stats, (start_timestamp, cap_timestamp, end_timestamp)))
+/// Reads the statistics of the Node Manager policy of a test, then removes the policy
+/// so it can't limit the tests that follow
+async fn release_nm_policy(nm_capper: Option<&NmCapController>) -> Option<NmStatistics> {
+    let nm_capper = nm_capper?;
+    let statistics = match nm_capper.statistics().await {
+        Ok(statistics) => {
+            info!("Node Manager statistics\n{statistics:?}");
+            Some(statistics)
+        }
+        Err(e) => {
+            error!("Failed to read the Node Manager statistics: {e}");
+            None
+        }
+    };
+    if let Err(e) = nm_capper.remove_policy().await {
+        error!("Failed to remove the Node Manager policy: {e}");
+    }
+    statistics
 }
 
-fn start_bmc_monitor(rx_channel: Receiver<()>) -> task::JoinHandle<Vec<BMCStats>> {
-    task::spawn(async {monitor_bmc(rx_channel)})
+/// Puts back the RAPL limits changed by a test, so they can't limit the tests that follow
+async fn release_rapl_limits(rapl_capper: Option<&RaplCapController>) {
+    let Some(rapl_capper) = rapl_capper else { return };
+    if let Err(e) = rapl_capper.restore().await {
+        error!("Failed to restore the RAPL limits: {e}");
+    }
 }
 
-fn launch_agent(client: Client, fs_params: FirestarterParams ) ->  task::JoinHandle<Vec<RaplRecord>> {
+/// Starts the power and sensor monitors, both stopped by cancelling the returned token
+fn start_bmc_monitors(bmc: &Arc<dyn PowerCapController>) ->
+    (CancellationToken, task::JoinHandle<Monitored<BMCStats>>, task::JoinHandle<Monitored<SensorRecord>>) {
+
+    let cancel = CancellationToken::new();
+    let bmc_period = Duration::from_millis(CONFIGURATION.bmc_poll_interval_millis);
+    let sensor_period = Duration::from_millis(CONFIGURATION.sensor_poll_interval_millis);
+    let bmc_thread = task::spawn(monitor_bmc(bmc.clone(), bmc_period, cancel.clone()));
+    let sensor_thread = task::spawn(monitor_sensors(bmc.clone(), sensor_period, cancel.clone()));
+    (cancel, bmc_thread, sensor_thread)
+}
+
+/// Runs the load on the agent, returning the RAPL stats it collected. An unreachable
+/// agent fails the test, not the campaign.
+fn launch_agent(client: Client, fs_params: FirestarterParams ) ->  task::JoinHandle<BMCResult<Vec<RaplRecord>>> {
     trace!("Sending request to agent: {}", &CONFIGURATION.agent_run_test_endpoint);
     task::spawn(async move {
         trace!("Agent thread posting to {}", &CONFIGURATION.agent_run_test_endpoint);
@@ -147,66 +451,73 @@ fn launch_agent(client: Client, fs_params: FirestarterParams ) ->  task::JoinHan
             .json(&fs_params)
             .send()
             .await
-            .expect("launch_agent failed to post request")
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| BMCError::Transport(format!("launch_agent failed to post request: {e}")))?
             .json()
             .await
-            .expect("launch_agent failed to extract json")
+            .map_err(|e| BMCError::Transport(format!("launch_agent failed to extract json: {e}")))
     })
 }
 
 
-async fn set_initial_conditions(config: &Test, bmc: &BMC) {
+async fn set_initial_conditions(config: &Test, bmc: &dyn PowerCapController) -> BMCResult<()> {
     trace!("starting setup_initial_conditions()");
+    // Not every mechanism lets us choose - the test then runs with the BMC's own correction time
+    match bmc.set_correction_time(Duration::from_millis(config.correction_time_millis)).await {
+        Err(BMCError::Unsupported(e)) => warn!("Correction time not set: {e}"),
+        result => result?,
+    }
     match config.capping_order {
         CappingOrder::LevelBeforeActivate => {
             // Set the level to the "cap_to" value, and the
             // capping activation to the opposite of the test
 
-            bmc.set_cap_power_level(config.cap_to);
+            bmc.set_cap_power_level(config.cap_to).await?;
             // sleep(Duration::from_secs(CONFIGURATION.setup_pause_millis));
 
             match config.operation {
-                Operation::Activate => bmc.deactivate_power_cap(),
-                Operation::Deactivate => bmc.activate_power_cap(),
+                Operation::Activate => bmc.deactivate_power_cap().await?,
+                Operation::Deactivate => bmc.activate_power_cap().await?,
             };
         }
         CappingOrder::LevelAfterActivate => {
             // set the capping level to the "cap_from" value
             // and the capping activation to the value for the test
-            bmc.set_cap_power_level(config.cap_from);
+            bmc.set_cap_power_level(config.cap_from).await?;
             // sleep(Duration::from_secs(CONFIGURATION.setup_pause_millis));
 
             match config.operation {
-                Operation::Activate => bmc.activate_power_cap(),
-                Operation::Deactivate => bmc.deactivate_power_cap(),
+                Operation::Activate => bmc.activate_power_cap().await?,
+                Operation::Deactivate => bmc.deactivate_power_cap().await?,
             }
         }
         CappingOrder::LevelToLevel | CappingOrder::LevelToLevelActivate => {
             // set cap level and activate capping
-            bmc.set_cap_power_level(config.cap_from);
+            bmc.set_cap_power_level(config.cap_from).await?;
             // sleep(Duration::from_secs(CONFIGURATION.setup_pause_millis));
-            bmc.activate_power_cap();
+            bmc.activate_power_cap().await?;
         }
     };
     trace!("initial_conditions set - pause before return");
     // sleep(Duration::from_secs(CONFIGURATION.setup_pause_millis)).await;
     trace!("exiting setup_initial_conditions()");
+    Ok(())
 }
 
-fn do_cap_operation(config: &Test, bmc: &BMC) {
+async fn do_cap_operation(config: &Test, bmc: &dyn PowerCapController) -> BMCResult<()> {
     trace!("do_cap_operation()");
     match config.capping_order {
         CappingOrder::LevelBeforeActivate => {
             // The capping level is set by set_initial_conditions
             // just need to perform the operation
             match config.operation {
-                Operation::Activate => bmc.activate_power_cap(),
-                Operation::Deactivate => bmc.deactivate_power_cap(),
+                Operation::Activate => bmc.activate_power_cap().await?,
+                Operation::Deactivate => bmc.deactivate_power_cap().await?,
             }
         }
         CappingOrder::LevelAfterActivate | CappingOrder::LevelToLevel => {
             if config.step == CapStep::OneShot {
-                bmc.set_cap_power_level(config.cap_to);
+                bmc.set_cap_power_level(config.cap_to).await?;
             } else {
                 // Step up/down the cap
                 let mut current_cap = config.cap_from;
@@ -218,34 +529,36 @@ fn do_cap_operation(config: &Test, bmc: &BMC) {
                     } else {
                         current_cap += CONFIGURATION.cap_step_size_watts;
                     }
-                    bmc.set_cap_power_level(current_cap);
-                    thread::sleep(Duration::from_secs(CONFIGURATION.cap_step_interval_secs));
+                    bmc.set_cap_power_level(current_cap).await?;
+                    sleep(Duration::from_secs(CONFIGURATION.cap_step_interval_secs)).await;
                 }
                 // set the final value
-                bmc.set_cap_power_level(config.cap_to);
+                bmc.set_cap_power_level(config.cap_to).await?;
             }
         }
         CappingOrder::LevelToLevelActivate => {
-            bmc.set_cap_power_level(config.cap_to);
-            bmc.activate_power_cap();
+            bmc.set_cap_power_level(config.cap_to).await?;
+            bmc.activate_power_cap().await?;
         }
     }
+    Ok(())
 }
 
 
-async fn get_server_info(client: &Client ) -> ServerInfo {
+async fn get_server_info(client: &Client ) -> BMCResult<ServerInfo> {
     trace!("get_server_info endpoint: {}", &CONFIGURATION.agent_info_endpoint);
     client.get(&CONFIGURATION.agent_info_endpoint)
     .send()
     .await
-    .expect("Failed to get server info")
+    .and_then(reqwest::Response::error_for_status)
+    .map_err(|e| BMCError::Transport(format!("Failed to get server info: {e}")))?
     .json()
     .await
-    .expect("Failed to get JSON from ServerInfo")
+    .map_err(|e| BMCError::Transport(format!("Failed to get JSON from ServerInfo: {e}")))
 }
 
 
-fn save_logs(tests: Vec<TestRun>, rapl_stats: Vec<Vec<RaplRecord>>, bmc_stats: Vec<Vec<BMCStats>>) {
+fn save_logs(tests: Vec<TestRun>, rapl_stats: Vec<Vec<RaplRecord>>, bmc_stats: Vec<Vec<BMCStats>>, sensor_stats: Vec<Vec<SensorRecord>>, sel_entries: Vec<Vec<SelEntry>>) {
     // create the stats directory
     let stats_path = Path::new(&CONFIGURATION.stats_dir);
     fs::create_dir_all(stats_path).expect("Failed to create stats directory");
@@ -258,9 +571,17 @@ fn save_logs(tests: Vec<TestRun>, rapl_stats: Vec<Vec<RaplRecord>>, bmc_stats: V
     path.push(format!("bmc_stats_{}.json", CONFIGURATION.log_timestamp()));
     write_json_file(&path, &bmc_stats);
 
+    path = PathBuf::from(stats_path);
+    path.push(format!("sensor_stats_{}.json", CONFIGURATION.log_timestamp()));
+    write_json_file(&path, &sensor_stats);
+
     path = PathBuf::from(stats_path);
     path.push(format!("rapl_stats_{}.json", CONFIGURATION.log_timestamp()));
     write_json_file(&path, &rapl_stats);
+
+    path = PathBuf::from(stats_path);
+    path.push(format!("sel_entries_{}.json", CONFIGURATION.log_timestamp()));
+    write_json_file(&path, &sel_entries);
 }
 
 fn log_server_info(server_info: ServerInfo)  {
@@ -288,13 +609,73 @@ fn write_json_file<T>(path: &PathBuf, json: &T) where T: ?Sized + Serialize {
 }
 
 
-fn make_bmc() -> BMC {
-    BMC::new(
-        &CONFIGURATION.bmc_hostname,
-        &CONFIGURATION.bmc_username,
-        &CONFIGURATION.bmc_password,
+/// An ipmitool BMC, parsing the output with the vendor profile given on the command
+/// line or matching the BMC's manufacturer
+async fn make_ipmitool_bmc(hostname: &str, shell: bool) -> BMCResult<BMC> {
+    let mut bmc = BMC::new(
+        hostname,
+        bmc_username()?,
+        CONFIGURATION.bmc_credentials.clone(),
         &CONFIGURATION.ipmi
-    )
+    );
+    if shell {
+        bmc = bmc.with_shell()?;
+    }
+
+    if let Some(vendor) = CONFIGURATION.bmc_vendor {
+        return Ok(bmc.with_vendor(vendor));
+    }
+    let detecting = bmc.clone();
+    match task::spawn_blocking(move || detecting.detect_vendor()).await.expect("Vendor detection panicked") {
+        Ok(vendor) => Ok(bmc.with_vendor(vendor)),
+        Err(e) if matches!(e, BMCError::Credentials(_)) => Err(e),
+        Err(e) => {
+            warn!("Failed to detect the BMC vendor, using the generic profile: {e}");
+            Ok(bmc)
+        }
+    }
+}
+
+/// The BMC user, which `restore` only asks for when the BMC needs one
+fn bmc_username() -> BMCResult<&'static str> {
+    if CONFIGURATION.bmc_username.is_empty() {
+        return Err(BMCError::Authentication(format!("{:?} needs a user, give it with -U", CONFIGURATION.bmc_type)));
+    }
+    Ok(&CONFIGURATION.bmc_username)
+}
+
+async fn make_controller(hostname: &str) -> BMCResult<Arc<dyn PowerCapController>> {
+    let controller: Arc<dyn PowerCapController> = match CONFIGURATION.bmc_type {
+        ControllerType::Ipmi => Arc::new(make_ipmitool_bmc(hostname, false).await?),
+        ControllerType::IpmiShell => Arc::new(make_ipmitool_bmc(hostname, true).await?),
+        ControllerType::Lanplus => Arc::new(LanplusBMC::new(
+            hostname,
+            bmc_username()?,
+            CONFIGURATION.bmc_credentials.password()?.expose(),
+        )),
+        ControllerType::Redfish => Arc::new(Redfish::new(
+            hostname,
+            bmc_username()?,
+            CONFIGURATION.bmc_credentials.password()?.expose(),
+        ).await?),
+        ControllerType::Agent if CONFIGURATION.agent_url.is_empty() => {
+            return Err(BMCError::Transport(String::from("the agent's BMC needs the agent, give it with --agent")));
+        }
+        ControllerType::Agent => Arc::new(AgentProxyBMC::new(&CONFIGURATION.agent_url)),
+        ControllerType::Simulated => Arc::new(SimulatedBMC::default()),
+    };
+
+    Ok(with_verification(controller))
+}
+
+/// Reads back every cap change when asked to
+fn with_verification(controller: Arc<dyn PowerCapController>) -> Arc<dyn PowerCapController> {
+    if CONFIGURATION.verify_cap {
+        let timeout = Duration::from_millis(CONFIGURATION.verify_timeout_millis);
+        Arc::new(VerifiedController::new(controller, timeout))
+    } else {
+        controller
+    }
 }
 
 fn make_http_client() -> Client {
@@ -0,0 +1,34 @@
+use clap::Parser;
+use agent::bmc::redfish::RedfishSchema;
+use agent::bmc::redfish_mock::MockRedfish;
+use simple_logger::SimpleLogger;
+use std::net::TcpListener;
+
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Parser)]
+#[command(author, version, about = "Local mock Redfish service for testing without a BMC", long_about=None)]
+struct CLI {
+    #[arg(long, short, default_value = "127.0.0.1:8443", help="eg: '0.0.0.0:8443'")]
+    listen_address: String,
+
+    #[arg(long, short, value_enum, default_value_t = RedfishSchema::PowerControl)]
+    schema: RedfishSchema,
+
+    #[arg(long, short, default_value_t = 600, help = "Node power in Watts when no cap is active")]
+    watts: u64,
+}
+
+
+#[tokio::main]
+async fn main() {
+    SimpleLogger::new().env().init().unwrap();
+    let args = CLI::parse();
+    let listener = TcpListener::bind(&args.listen_address)
+        .expect("Failed to bind listen address");
+    let mock = MockRedfish::spawn_on(listener, args.schema, args.watts);
+    println!("🚀 Mock Redfish ({:?}) on {}", args.schema, mock.url());
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to wait for Ctrl-C");
+}
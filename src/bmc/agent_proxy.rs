@@ -0,0 +1,284 @@
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading, ExceptionAction};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::bmc::sel::SelEntry;
+use crate::bmc::sensors::SensorReading;
+use crate::model::BmcCapOperation;
+use crate::route::{
+    BMC_CAPABILITIES_PATH, BMC_CAP_PATH, BMC_CAP_SETTINGS_PATH, BMC_ENHANCED_PERIODS_PATH, BMC_ENHANCED_READING_PATH,
+    BMC_POWER_READING_PATH, BMC_SEL_PATH, BMC_SENSORS_PATH,
+};
+use async_trait::async_trait;
+use log::{trace, error};
+use reqwest::{Client, Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::{self, Debug};
+use std::time::Duration;
+
+/// The BMC of the agent's host, for BMCs on a network the client can't reach. The
+/// agent runs the DCMI commands in-band, through ipmitool's `open` interface.
+pub struct AgentProxyBMC {
+    agent: AgentClient,
+}
+
+/// Requests to the agent's endpoints that control its host, failing with the kind of
+/// `BMCError` the agent answered with
+pub(crate) struct AgentClient {
+    pub(crate) base_url: String,
+    client: Client,
+}
+
+impl AgentClient {
+    /// `agent` is the agent's URL, eg: `http://oahu10000:8000`
+    pub(crate) fn new(agent: &str) -> Self {
+        Self {
+            base_url: agent.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    /// Sends a request to the agent, returning the JSON body of the response
+    ///
+    /// # Errors
+    /// If the agent can't be reached, or its command failed
+    pub(crate) async fn request<T, B>(&self, method: Method, path: &str, operation: Option<B>) -> BMCResult<T>
+    where
+        T: DeserializeOwned,
+        B: Serialize + Debug,
+    {
+        let url = format!("{}{path}", self.base_url);
+        trace!("Agent {method} {url} {operation:?}");
+
+        let mut request = self.client.request(method.clone(), &url);
+        if let Some(operation) = operation {
+            request = request.json(&operation);
+        }
+
+        let response = request.send().await.map_err(|e| {
+            let message = format!("{method} {url}: {e}");
+            if e.is_timeout() { BMCError::Timeout(message) } else { BMCError::Transport(message) }
+        })?;
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            error!("Agent {method} {url} returned {status}: {text}");
+            return Err(error_from_status(status, format!("{method} {url}: {text}")));
+        }
+        serde_json::from_str(&text).map_err(|e| BMCError::Parse(format!("{method} {url}: {e}")))
+    }
+
+    pub(crate) async fn get<T: DeserializeOwned>(&self, path: &str) -> BMCResult<T> {
+        self.request(Method::GET, path, None::<()>).await
+    }
+}
+
+impl AgentProxyBMC {
+    /// `agent` is the agent's URL, eg: `http://oahu10000:8000`
+    #[must_use]
+    pub fn new(agent: &str) -> Self {
+        Self { agent: AgentClient::new(agent) }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> BMCResult<T> {
+        self.agent.get(path).await
+    }
+
+    async fn cap(&self, operation: BmcCapOperation) -> BMCResult<()> {
+        self.agent.request(Method::POST, BMC_CAP_PATH, Some(operation)).await
+    }
+}
+
+/// The status the agent answers a failed BMC command with. The client turns it back
+/// into the kind of error, so fatal errors stay fatal across the proxy.
+#[must_use]
+pub fn status_code(error: &BMCError) -> StatusCode {
+    match error {
+        BMCError::Launch { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        BMCError::Authentication(_) | BMCError::Credentials(_) => StatusCode::FORBIDDEN,
+        BMCError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        BMCError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+        BMCError::OutOfRange(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        BMCError::Unsafe(_) => StatusCode::CONFLICT,
+        BMCError::ExitStatus { .. } | BMCError::Parse(_) | BMCError::Transport(_) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// The inverse of `status_code`. An agent without ipmitool, or too old to have the
+/// BMC endpoints, can't do what's asked.
+fn error_from_status(status: StatusCode, message: String) -> BMCError {
+    match status {
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::NOT_IMPLEMENTED | StatusCode::NOT_FOUND => BMCError::Unsupported(message),
+        StatusCode::FORBIDDEN => BMCError::Authentication(message),
+        StatusCode::GATEWAY_TIMEOUT => BMCError::Timeout(message),
+        StatusCode::UNPROCESSABLE_ENTITY => BMCError::OutOfRange(message),
+        StatusCode::CONFLICT => BMCError::Unsafe(message),
+        _ => BMCError::Transport(message),
+    }
+}
+
+#[async_trait]
+impl PowerCapController for AgentProxyBMC {
+    async fn current_power(&self) -> BMCResult<u64> {
+        Ok(self.current_power_reading().await?.instant)
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        self.get(BMC_POWER_READING_PATH).await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        self.get(BMC_ENHANCED_PERIODS_PATH).await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        self.get(&format!("{BMC_ENHANCED_READING_PATH}/{}", period.as_secs())).await
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        self.get(BMC_CAP_SETTINGS_PATH).await
+    }
+
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        self.get(BMC_CAPABILITIES_PATH).await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        self.cap(BmcCapOperation::SetPowerLevel(cap)).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        self.cap(BmcCapOperation::Activate).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.cap(BmcCapOperation::Deactivate).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        self.cap(BmcCapOperation::SetCorrectionTime(correction_time)).await
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        self.cap(BmcCapOperation::SetSamplingPeriod(sampling_period)).await
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        self.cap(BmcCapOperation::SetExceptionAction(action)).await
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        self.get(BMC_SENSORS_PATH).await
+    }
+
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        self.get(BMC_SEL_PATH).await
+    }
+}
+
+impl Debug for AgentProxyBMC {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in-band BMC of {}", self.agent.base_url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::bmc::BMC;
+    use crate::route::{AgentState, BmcState};
+    use crate::test_util::{self, TempDir};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// An ipmitool in `dir` answering the DCMI power commands with output in its own format,
+    /// and logging its arguments to `args.log`
+    fn fake_ipmitool(dir: &TempDir) -> PathBuf {
+        fs::write(dir.join("reading.txt"), include_str!("fixtures/dcmi/ipmitool_power_reading.txt")).unwrap();
+        fs::write(dir.join("get_limit.txt"), include_str!("fixtures/dcmi/ipmitool_get_limit.txt")).unwrap();
+        let dir_path = dir.path().display();
+        let script = format!(
+            "echo \"$*\" >> {dir_path}/args.log\n\
+            case \"$*\" in\n\
+            *\"dcmi power reading\"*) cat {dir_path}/reading.txt ;;\n\
+            *\"dcmi power get_limit\"*) cat {dir_path}/get_limit.txt ;;\n\
+            *\"set_limit limit 90\"*) echo 'DCMI request failed because: Power Limit out of range (84)' >&2; exit 1 ;;\n\
+            *\"dcmi power\"*) ;;\n\
+            *) echo 'Invalid command' >&2; exit 1 ;;\n\
+            esac\n"
+        );
+        test_util::fake_ipmitool(dir, &script)
+    }
+
+    /// The in-band BMC going to `ipmitool`
+    fn in_band(ipmitool: &Path, allow_hard_power_off: bool) -> Option<BmcState> {
+        let bmc = Arc::new(BMC::in_band(0, ipmitool.to_str().unwrap()));
+        Some(BmcState { bmc, allow_hard_power_off })
+    }
+
+    /// Serves the agent's routes, with `bmc` if any
+    fn spawn_agent(bmc: Option<BmcState>) -> String {
+        test_util::spawn_router(AgentState { bmc, ..AgentState::default() })
+    }
+
+    #[tokio::test]
+    async fn test_proxied_capping() {
+        let dir = TempDir::new("proxy");
+        let ipmitool = fake_ipmitool(&dir);
+        let bmc = AgentProxyBMC::new(&spawn_agent(in_band(&ipmitool, false)));
+
+        let reading = bmc.current_power_reading().await.unwrap();
+        assert_eq!((reading.instant, reading.minimum, reading.maximum, reading.average), (262, 98, 431, 255));
+        assert_eq!(reading.sampling_period, Some(Duration::from_secs(1)));
+        let settings = bmc.current_cap_settings().await.unwrap();
+        assert!(settings.is_active);
+        assert_eq!(settings.power_limit, 400);
+        assert_eq!(settings.exception_action, Some(ExceptionAction::HardPowerOff));
+
+        bmc.set_cap_power_level(300).await.unwrap();
+        bmc.activate_power_cap().await.unwrap();
+        bmc.set_exception_action(ExceptionAction::LogEvent).await.unwrap();
+        let log = fs::read_to_string(dir.join("args.log")).unwrap();
+        assert!(log.contains("-I open -d 0 dcmi power set_limit limit 300\n"), "{log}");
+        assert!(log.contains("-I open -d 0 dcmi power activate\n"));
+        assert!(log.contains("-I open -d 0 dcmi power set_limit action sel_logging\n"));
+
+        // The kind of error makes it through the proxy
+        assert!(matches!(bmc.set_cap_power_level(90).await, Err(BMCError::OutOfRange(_))));
+        assert!(matches!(bmc.sel_entries().await, Err(BMCError::Unsupported(_))));
+        // Powering the host off needs the agent's say so
+        assert!(matches!(bmc.set_exception_action(ExceptionAction::HardPowerOff).await, Err(BMCError::Unsafe(_))));
+        assert!(!fs::read_to_string(dir.join("args.log")).unwrap().contains("power_off"));
+    }
+
+    #[tokio::test]
+    async fn test_hard_power_off_allowed() {
+        let dir = TempDir::new("proxy_power_off");
+        let ipmitool = fake_ipmitool(&dir);
+        let bmc = AgentProxyBMC::new(&spawn_agent(in_band(&ipmitool, true)));
+        bmc.set_exception_action(ExceptionAction::HardPowerOff).await.unwrap();
+        let log = fs::read_to_string(dir.join("args.log")).unwrap();
+        assert!(log.contains("-I open -d 0 dcmi power set_limit action power_off\n"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn test_bmc_not_served() {
+        let bmc = AgentProxyBMC::new(&spawn_agent(None));
+        assert!(matches!(bmc.current_power_reading().await, Err(BMCError::Unsupported(_))));
+        assert!(matches!(bmc.activate_power_cap().await, Err(BMCError::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let bmc = AgentProxyBMC::new(&format!("http://{address}"));
+        let error = bmc.current_power().await.unwrap_err();
+        assert!(matches!(error, BMCError::Transport(_)));
+        assert!(!error.is_fatal());
+    }
+}
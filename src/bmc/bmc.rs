@@ -1,40 +1,109 @@
-use chrono::NaiveDateTime;
-use log::{trace, error};
-use std::process::Command;
+use crate::bmc::credentials::{CredentialProvider, IpmitoolPassword};
+use crate::bmc::capabilities::{parse_dcmi_discover, parse_nm_capability, CapCapabilities};
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::bmc::enhanced_power::{
+    parse_enhanced_periods, period_to_dcmi, DCMI_ENHANCED_POWER_ATTRIBUTES, DCMI_ENHANCED_POWER_STATISTICS,
+};
+use crate::bmc::ipmi_shell::IpmiShell;
+use crate::bmc::lanplus::{LanplusBMC, CMD_DCMI_GET_CAPABILITIES, CMD_DCMI_GET_POWER_READING, DCMI_GROUP_ID, NETFN_GROUP_EXTENSION};
+use crate::bmc::node_manager::{
+    get_policy_request, parse_policy, parse_raw_output, parse_statistics, policy_control_request, remove_policy_request,
+    set_policy_request, statistics_request, NmDomain, NmPolicy, NmStatistics, NM_CHANNEL, NM_CMD_GET_POLICY,
+    NM_CMD_GET_STATISTICS, NM_CMD_POLICY_CONTROL, NM_CMD_SET_POLICY, NM_NETFN, NM_TARGET_ADDRESS,
+};
+use crate::bmc::sel::{parse_sel_elist, SelEntry};
+use crate::bmc::sensors::{parse_sdr_elist, SensorReading};
+use crate::bmc::vendor::{parse_manufacturer_id, Vendor, VendorProfile};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use log::{info, trace, warn, error};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::{Command, Stdio};
 use std::fmt::{self, Display, Debug};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 const BMC_READ_POWER_CMD: &str = "dcmi power reading";
 const BMC_CAP_SETTINGS_CMD: &str = "dcmi power get_limit";
 const BMC_SET_CAP_CMD: &str = "dcmi power set_limit limit";
+const BMC_SET_CORRECTION_CMD: &str = "dcmi power set_limit correction";
+const BMC_SET_SAMPLING_CMD: &str = "dcmi power set_limit sample";
+const BMC_SET_ACTION_CMD: &str = "dcmi power set_limit action";
 const BMC_ACTIVATE_CAP_CMD: &str = "dcmi power activate";
 const BMC_DEACTIVATE_CAP_CMD: &str = "dcmi power deactivate";
+const BMC_SEL_LIST_CMD: &str = "sel elist";
+const BMC_SDR_LIST_CMD: &str = "sdr elist full";
+const BMC_MC_INFO_CMD: &str = "mc info";
+const BMC_DCMI_DISCOVER_CMD: &str = "dcmi discover";
+const BMC_NM_CAPABILITY_CMD: &str = "nm capability";
+const BMC_COMMAND_TIMEOUT_SECS: u64 = 30;
+const BMC_COMMAND_POLL_MILLIS: u64 = 20;
+
+/// How ipmitool reaches the BMC
+#[derive(Clone)]
+pub enum IpmiInterface {
+    /// Over the network, logging in as `username`
+    Lan {
+        hostname: String,
+        username: String,
+        /// Read for each ipmitool run, and passed without showing in its arguments
+        credentials: Arc<dyn CredentialProvider>,
+    },
+    /// In-band, through the IPMI driver of the BMC's own host, `/dev/ipmi<device>`.
+    /// Needs root rather than credentials.
+    Open { device: u32 },
+}
 
 #[derive(Clone)]
 pub struct BMC {
-    pub hostname: String,
-    pub username: String,
-    pub password: String,
+    pub interface: IpmiInterface,
     pub ipmi: String,
+    /// When set, commands go to one long-lived `ipmitool shell` shared by all the clones
+    shell: Option<Arc<Mutex<IpmiShell>>>,
+    /// How this BMC's output is worded
+    profile: &'static VendorProfile,
 }
 
+/// A DCMI power reading. Minimum, maximum and average are over the sampling period.
+/// Backends that only know the instantaneous power report it for all four, and
+/// leave the rest as `None`.
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone)]
-struct BMC_PowerReading {
-    instant: u64,
-    minimum: u64,
-    maximum: u64,
-    average: u64,
-    timestamp: NaiveDateTime,
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BMC_PowerReading {
+    pub instant: u64,
+    pub minimum: u64,
+    pub maximum: u64,
+    pub average: u64,
+    /// The BMC's clock when it took the reading, to one second
+    pub timestamp: Option<DateTime<Utc>>,
+    pub sampling_period: Option<Duration>,
+    /// Whether the BMC reports power measurement as active
+    pub reading_is_active: Option<bool>,
 }
 
 impl BMC_PowerReading {
+    #[must_use]
     pub fn new() -> Self {
         Self {
             instant: 0,
             minimum: 0,
             maximum: 0,
             average: 0,
-            timestamp: NaiveDateTime::MIN,
+            timestamp: None,
+            sampling_period: None,
+            reading_is_active: None,
+        }
+    }
+
+    #[must_use]
+    pub fn from_instant(power: u64) -> Self {
+        Self {
+            instant: power,
+            minimum: power,
+            maximum: power,
+            average: power,
+            ..Self::new()
         }
     }
 }
@@ -45,20 +114,150 @@ impl Default for BMC_PowerReading {
     }
 }
 
+/// What the BMC does when it can't bring power under the limit within the correction time
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ExceptionAction {
+    NoAction,
+    HardPowerOff,
+    LogEvent,
+}
+
+impl ExceptionAction {
+    /// Decodes the exception action byte of a DCMI power limit
+    #[must_use]
+    pub fn from_dcmi(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(ExceptionAction::NoAction),
+            0x01 => Some(ExceptionAction::HardPowerOff),
+            0x11 => Some(ExceptionAction::LogEvent),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn dcmi_code(self) -> u8 {
+        match self {
+            ExceptionAction::NoAction => 0x00,
+            ExceptionAction::HardPowerOff => 0x01,
+            ExceptionAction::LogEvent => 0x11,
+        }
+    }
+
+    /// The value taken by `dcmi power set_limit action`
+    #[must_use]
+    pub fn ipmitool_arg(self) -> &'static str {
+        match self {
+            ExceptionAction::NoAction => "no_action",
+            ExceptionAction::HardPowerOff => "power_off",
+            ExceptionAction::LogEvent => "sel_logging",
+        }
+    }
+
+    /// Parses the exception action as printed by `dcmi power get_limit`, eg "Hard Power Off & Log Event to SEL"
+    fn from_ipmitool(text: &str) -> Option<Self> {
+        let text = text.to_lowercase();
+        if text.contains("power off") {
+            Some(ExceptionAction::HardPowerOff)
+        } else if text.contains("log event") {
+            Some(ExceptionAction::LogEvent)
+        } else if text.contains("no action") {
+            Some(ExceptionAction::NoAction)
+        } else {
+            None
+        }
+    }
+}
+
+/// The DCMI power limit. Beyond the limit itself, the BMC has `correction_time` to bring
+/// power under the limit before taking the exception action, and judges the power over
+/// the `sampling_period`. Backends that don't report these leave them as `None`.
 #[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BMC_CapSetting {
     pub is_active: bool,
     pub power_limit: u64,
+    pub exception_action: Option<ExceptionAction>,
+    pub correction_time: Option<Duration>,
+    pub sampling_period: Option<Duration>,
 }
 
 impl BMC {
     #[must_use]
-    pub fn new(hostname: &str, username: &str, password: &str, ipmi: &str) -> Self {
-        Self {
+    pub fn new(hostname: &str, username: &str, credentials: Arc<dyn CredentialProvider>, ipmi: &str) -> Self {
+        let interface = IpmiInterface::Lan {
             hostname: String::from(hostname),
             username: String::from(username),
-            password: String::from(password),
+            credentials,
+        };
+        Self::with_interface(interface, ipmi)
+    }
+
+    /// The BMC of the host we're running on, through `/dev/ipmi<device>`
+    #[must_use]
+    pub fn in_band(device: u32, ipmi: &str) -> Self {
+        Self::with_interface(IpmiInterface::Open { device }, ipmi)
+    }
+
+    fn with_interface(interface: IpmiInterface, ipmi: &str) -> Self {
+        Self {
+            interface,
             ipmi: String::from(ipmi),
+            shell: None,
+            profile: Vendor::Generic.profile(),
+        }
+    }
+
+    /// Parses the output using `vendor`'s rules
+    #[must_use]
+    pub fn with_vendor(mut self, vendor: Vendor) -> Self {
+        self.profile = vendor.profile();
+        self
+    }
+
+    #[must_use]
+    pub fn vendor(&self) -> Vendor {
+        self.profile.vendor
+    }
+
+    /// Works out the vendor from the BMC's manufacturer ID. Unknown manufacturers get
+    /// the generic profile.
+    ///
+    /// # Errors
+    /// If `mc info` fails or has no manufacturer ID
+    pub fn detect_vendor(&self) -> BMCResult<Vendor> {
+        let bmc_output = self.run_command(BMC_MC_INFO_CMD)?;
+        let id = parse_manufacturer_id(&bmc_output)
+            .ok_or_else(|| BMCError::Parse(format!("no manufacturer ID in '{}'", bmc_output.trim())))?;
+        let vendor = Vendor::from_manufacturer_id(id);
+        info!("BMC manufacturer ID {id}: using the {vendor:?} profile");
+        Ok(vendor)
+    }
+
+    /// Sends the commands through a persistent `ipmitool shell` instead of running
+    /// ipmitool once per command
+    ///
+    /// # Errors
+    /// If the password can't be had
+    pub fn with_shell(mut self) -> BMCResult<Self> {
+        let (args, password) = self.session_args()?;
+        let envs = password.map(|password| password.envs()).unwrap_or_default();
+        let timeout = Duration::from_secs(BMC_COMMAND_TIMEOUT_SECS);
+        self.shell = Some(Arc::new(Mutex::new(IpmiShell::new(&self.ipmi, args, envs, timeout))));
+        Ok(self)
+    }
+
+    /// The ipmitool options that open a session with the BMC, and how the password
+    /// goes with them. The password is never one of the options, and in-band access
+    /// has none.
+    fn session_args(&self) -> BMCResult<(Vec<String>, Option<IpmitoolPassword>)> {
+        let mut args: Vec<String> = self.to_string().split_whitespace().map(String::from).collect();
+        match &self.interface {
+            IpmiInterface::Lan { credentials, .. } => {
+                let password = credentials.ipmitool_password()?;
+                args.extend(password.args());
+                Ok((args, Some(password)))
+            }
+            IpmiInterface::Open { .. } => Ok((args, None)),
         }
     }
 
@@ -71,213 +270,360 @@ impl BMC {
     /// * `bmc_command` - a string slice with command to exectue
     ///
     /// # Return
-    /// * <stdout> as a string
-    ///
-    /// # Panics
-    /// The method will panic if the command fails to run
-    // TODO: This should also return a flag, indicating whether the command succeeded or failed
-    fn run_command(&self, bmc_command: &str) -> String {
+    /// * <stdout> as a string, or a `BMCError` if ipmitool couldn't be launched, timed out or
+    ///   exited with a failure status
+    fn run_command(&self, bmc_command: &str) -> BMCResult<String> {
+        match &self.shell {
+            Some(shell) => {
+                trace!("BMC running shell command: {self:?} {bmc_command}");
+                shell.lock().expect("ipmitool shell poisoned").run(bmc_command)
+            }
+            None => self.run_process(bmc_command),
+        }
+    }
 
-        // Concatenate command with the credentials
-        let ipmi_args = format!("{self} {bmc_command}");
-        trace!("BMC running command: {self:?} {bmc_command}");
+    /// Runs one ipmitool process for `bmc_command`
+    fn run_process(&self, bmc_command: &str) -> BMCResult<String> {
 
-        // process::Command requires arguments as an array
-        let ipmi_args: Vec<&str> = ipmi_args.split_whitespace().collect();
+        trace!("BMC running command: {self:?} {bmc_command}");
+        let (session_args, password) = self.session_args()?;
+        let envs = password.map(|password| password.envs()).unwrap_or_default();
         let ipmi_path = &self.ipmi;
 
         // Launch the command
-        match Command::new(ipmi_path).args(&ipmi_args).output() {
-            Ok(out) => {
-                let stdout = String::from_utf8_lossy(&out.stdout);
-                let stderr = String::from_utf8_lossy(&out.stderr);
+        let mut child = Command::new(ipmi_path)
+            .args(&session_args)
+            .args(bmc_command.split_whitespace())
+            .envs(envs.iter().map(|(name, secret)| (name, secret.expose())))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| {
+                error!("BMC Failed to execute command: {ipmi_path} {bmc_command}: {source:?}");
+                BMCError::Launch { command: format!("{ipmi_path} {bmc_command}"), source }
+            })?;
+
+        // A full SEL is far more than a pipe holds, so the output is drained while we poll,
+        // or ipmitool would block writing it.
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        // ipmitool retries internally and can hang for a long time on an unreachable BMC.
+        let deadline = Instant::now() + Duration::from_secs(BMC_COMMAND_TIMEOUT_SECS);
+        while child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                // Best effort - the child may have exited in the meantime
+                let _ = child.kill();
+                let _ = child.wait();
+                error!("BMC run_command({bmc_command}) timed out after {BMC_COMMAND_TIMEOUT_SECS}s");
+                return Err(BMCError::Timeout(format!("{bmc_command}: no result after {BMC_COMMAND_TIMEOUT_SECS}s")));
+            }
+            thread::sleep(Duration::from_millis(BMC_COMMAND_POLL_MILLIS));
+        }
 
-                if stderr.len() > 0 {
-                    error!("BMC run_command({bmc_command}) stderr: {stderr}");
-                }
+        let status = child.wait()?;
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        let stdout = String::from_utf8_lossy(&stdout);
+        let stderr = String::from_utf8_lossy(&stderr);
 
-                stdout.to_string()
-            }
-            Err(e) => {
-                error!(
-                    "BMC Failed to execute command: {} {}: {:?}",
-                    ipmi_path,
-                    &ipmi_args.join(","),
-                    e
-                );
-                panic!(
-                    "BMC Can't run command: {} {}: {e:?}",
-                    ipmi_path,
-                    &ipmi_args.join(",")
-                );
-            }
+        if !status.success() {
+            error!("BMC run_command({bmc_command}) failed ({status}): {stderr}");
+            return Err(BMCError::from_ipmitool(bmc_command, status.code(), &stderr));
+        }
+
+        if !stderr.is_empty() {
+            warn!("BMC run_command({bmc_command}) stderr: {stderr}");
         }
+
+        Ok(stdout.to_string())
     }
 
     // Capping management
+    /// DCMI support, plus the limit range on platforms with Intel Node Manager
+    pub fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        let mut capabilities = parse_dcmi_discover(&self.run_command(BMC_DCMI_DISCOVER_CMD)?);
+        match self.run_command(BMC_NM_CAPABILITY_CMD) {
+            Ok(bmc_output) => (capabilities.min_limit_watts, capabilities.max_limit_watts) = parse_nm_capability(&bmc_output),
+            Err(e) => info!("BMC power limit range unknown, no Node Manager: {e}"),
+        }
+        Ok(capabilities)
+    }
+
     /// Returns the current cap power limit and activation state in a `CapSetting` struct
-    #[must_use]
-    pub fn current_cap_settings(&self) -> BMC_CapSetting {
-        let bmc_output = self.run_command(BMC_CAP_SETTINGS_CMD);
-        BMC::parse_cap_settings(&bmc_output)
+    pub fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let bmc_output = self.run_command(BMC_CAP_SETTINGS_CMD)?;
+        BMC::parse_cap_settings(&bmc_output, self.profile)
     }
 
-    #[must_use]
-    pub fn capping_is_active(&self) -> bool {
-        self.current_cap_settings().is_active
+    pub fn capping_is_active(&self) -> BMCResult<bool> {
+        Ok(self.current_cap_settings()?.is_active)
     }
 
-    #[must_use]
-    pub fn current_power_limit(&self) -> u64 {
-        self.current_cap_settings().power_limit
+    pub fn current_power_limit(&self) -> BMCResult<u64> {
+        Ok(self.current_cap_settings()?.power_limit)
     }
 
-    pub fn set_cap_power_level(&self, cap: u64) {
+    pub fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
         let cap_cmd = format!("{BMC_SET_CAP_CMD} {cap}");
-        self.run_command(&cap_cmd);
-        // TODO: check the output
+        self.run_command(&cap_cmd)?;
+        Ok(())
+    }
+
+    pub fn activate_power_cap(&self) -> BMCResult<()> {
+        self.run_command(BMC_ACTIVATE_CAP_CMD)?;
+        Ok(())
     }
 
-    pub fn activate_power_cap(&self) {
-        self.run_command(BMC_ACTIVATE_CAP_CMD);
+    pub fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.run_command(BMC_DEACTIVATE_CAP_CMD)?;
+        Ok(())
     }
 
-    pub fn deactivate_power_cap(&self) {
-        self.run_command(BMC_DEACTIVATE_CAP_CMD);
+    /// DCMI takes the correction time in whole milliseconds
+    pub fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        let correction_cmd = format!("{BMC_SET_CORRECTION_CMD} {}", correction_time.as_millis());
+        self.run_command(&correction_cmd)?;
+        Ok(())
+    }
+
+    /// DCMI takes the sampling period in whole seconds
+    pub fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        let sampling_cmd = format!("{BMC_SET_SAMPLING_CMD} {}", sampling_period.as_secs());
+        self.run_command(&sampling_cmd)?;
+        Ok(())
+    }
+
+    pub fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        let action_cmd = format!("{BMC_SET_ACTION_CMD} {}", action.ipmitool_arg());
+        self.run_command(&action_cmd)?;
+        Ok(())
     }
 
 
     // Power management
-    #[must_use]
-    pub fn current_power(&self) -> u64 {
-        let bmc_output = self.run_command(BMC_READ_POWER_CMD);
-        BMC::parse_power_reading(&bmc_output).instant
+    pub fn current_power(&self) -> BMCResult<u64> {
+        Ok(self.current_power_reading()?.instant)
     }
 
+    pub fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        let bmc_output = self.run_command(BMC_READ_POWER_CMD)?;
+        BMC::parse_power_reading(&bmc_output, self.profile)
+    }
 
+    /// ipmitool has no command for the enhanced power statistics, so the DCMI requests
+    /// are sent raw. The response is decoded as lanplus does.
+    fn run_dcmi_raw(&self, cmd: u8, data: &[u8]) -> BMCResult<Vec<u8>> {
+        let bytes: Vec<String> = data.iter().map(|byte| format!("{byte:#04x}")).collect();
+        let raw_cmd = format!("raw {NETFN_GROUP_EXTENSION:#04x} {cmd:#04x} {DCMI_GROUP_ID:#04x} {}", bytes.join(" "));
+        parse_raw_output(&self.run_command(&raw_cmd)?)
+    }
+
+    /// The rolling average periods the BMC keeps enhanced power statistics for
+    pub fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        let response = self.run_dcmi_raw(CMD_DCMI_GET_CAPABILITIES, &[DCMI_ENHANCED_POWER_ATTRIBUTES])?;
+        parse_enhanced_periods(&response)
+            .ok_or_else(|| BMCError::Parse(format!("DCMI enhanced power attributes: {response:02x?}")))
+    }
+
+    /// The power statistics over one of the `enhanced_power_periods`
+    pub fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        let code = period_to_dcmi(period)
+            .ok_or_else(|| BMCError::OutOfRange(format!("No DCMI rolling average period of {period:?}")))?;
+        let response = self.run_dcmi_raw(CMD_DCMI_GET_POWER_READING, &[DCMI_ENHANCED_POWER_STATISTICS, code, 0x00])?;
+        LanplusBMC::parse_power_reading(&response)
+            .ok_or_else(|| BMCError::Parse(format!("DCMI enhanced power reading: {response:02x?}")))
+    }
+
+    // Sensors
+    /// PSU power, inlet/exhaust temperatures and fan speeds from the SDR
+    pub fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        let bmc_output = self.run_command(BMC_SDR_LIST_CMD)?;
+        Ok(parse_sdr_elist(&bmc_output))
+    }
+
+    // Event log
+    /// The whole System Event Log
+    pub fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        let bmc_output = self.run_command(BMC_SEL_LIST_CMD)?;
+        Ok(parse_sel_elist(&bmc_output))
+    }
+
+    // Intel Node Manager
+    /// Sends a Node Manager command to the Management Engine, bridged by the BMC. The
+    /// bridge is set up by ipmitool options, so this always runs its own ipmitool process.
+    fn node_manager(&self, command: u8, data: &[u8]) -> BMCResult<Vec<u8>> {
+        let bytes: Vec<String> = data.iter().map(|byte| format!("{byte:#04x}")).collect();
+        let raw_cmd = format!(
+            "-b {NM_CHANNEL:#04x} -t {NM_TARGET_ADDRESS:#04x} raw {NM_NETFN:#04x} {command:#04x} {}",
+            bytes.join(" ")
+        );
+        parse_raw_output(&self.run_process(&raw_cmd)?)
+    }
+
+    pub fn nm_set_policy(&self, policy: &NmPolicy) -> BMCResult<()> {
+        self.node_manager(NM_CMD_SET_POLICY, &set_policy_request(policy))?;
+        Ok(())
+    }
+
+    pub fn nm_remove_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<()> {
+        self.node_manager(NM_CMD_SET_POLICY, &remove_policy_request(domain, policy_id))?;
+        Ok(())
+    }
+
+    pub fn nm_set_policy_enabled(&self, domain: NmDomain, policy_id: u8, enabled: bool) -> BMCResult<()> {
+        self.node_manager(NM_CMD_POLICY_CONTROL, &policy_control_request(domain, policy_id, enabled))?;
+        Ok(())
+    }
+
+    pub fn nm_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<NmPolicy> {
+        let response = self.node_manager(NM_CMD_GET_POLICY, &get_policy_request(domain, policy_id))?;
+        parse_policy(policy_id, &response)
+    }
+
+    /// The limit range of one domain, from the same command as the platform's
+    pub fn nm_capabilities(&self, domain: NmDomain) -> BMCResult<CapCapabilities> {
+        let bmc_output = self.run_command(&format!("{BMC_NM_CAPABILITY_CMD} domain {}", domain.ipmitool_name()))?;
+        let (min_limit_watts, max_limit_watts) = parse_nm_capability(&bmc_output);
+        Ok(CapCapabilities { power_management: true, min_limit_watts, max_limit_watts, ..CapCapabilities::default() })
+    }
+
+    pub fn nm_statistics(&self, domain: NmDomain, policy_id: Option<u8>) -> BMCResult<NmStatistics> {
+        let response = self.node_manager(NM_CMD_GET_STATISTICS, &statistics_request(domain, policy_id))?;
+        parse_statistics(&response)
+    }
 
 
     /// Parses a u64 from the first word in the `power_reading` string
     /// Used in the application to parse the power values returned from
     /// # Example
     /// ```
-    /// use capping::bmc::BMC;
-    /// assert_eq!(220, BMC::parse_number("220 Watts"));
+    /// use agent::bmc::bmc::BMC;
+    /// assert_eq!(220, BMC::parse_number("220 Watts").unwrap());
+    /// assert!(BMC::parse_number("N/A").is_err());
     /// ```
     ///
-    /// # Panics
+    /// # Errors
     /// If the passed string is empty, or first word is not a number
-    #[must_use]
-    pub fn parse_number(power_reading: &str) -> u64 {
-        let parts: Vec<&str> = power_reading.trim().split_ascii_whitespace().collect();
-        assert!(!parts.is_empty());
-        let n: u64 = parts[0].parse().expect("Failed to parse power reading");
-        n
-    }
-
-    /// Parses a BMC date string into local time without timezone (`NaiveDateTime`)
-    ///
-    /// # Example
-    /// ```
-    /// use chrono::{NaiveDate, NaiveDateTime};
-    /// use capping::bmc::BMC;
-    ///
-    /// let bmc_date_string = "Tue May  9 14:24:36 2023";
-    /// let bmc_date = BMC::date_from_string(bmc_date_string);
-    /// let expected: NaiveDateTime = NaiveDate::from_ymd_opt(2023, 5, 9).unwrap().and_hms_opt(14, 24, 36).unwrap();
-    /// assert_eq!(expected, bmc_date);
-    /// ```
-    ///
-    /// # Panics
-    /// Will panic if the date string cannot be parsed
-    #[must_use]
-    pub fn date_from_string(date_string: &str) -> NaiveDateTime {
-        // Tue May  9 14:24:36 2023
-        let bmc_timestamp_fmt = "%a %b %e %H:%M:%S %Y";
-        let dt = NaiveDateTime::parse_from_str(date_string.trim(), bmc_timestamp_fmt)
-            .expect("Failed to parse BMC timestamp");
-        dt
+    pub fn parse_number(power_reading: &str) -> BMCResult<u64> {
+        power_reading
+            .split_ascii_whitespace()
+            .next()
+            .and_then(|word| word.parse().ok())
+            .ok_or_else(|| BMCError::Parse(format!("expected a number, got '{}'", power_reading.trim())))
     }
 
     /// Parses the ouptut of BMC ipmi dcmi power command, returning a `BMC_PowerReading` struct
-    #[must_use]
-    #[allow(clippy::match_on_vec_items)]
-    fn parse_power_reading(output: &str) -> BMC_PowerReading {
+    fn parse_power_reading(output: &str, profile: &VendorProfile) -> BMCResult<BMC_PowerReading> {
         let mut readings = BMC_PowerReading::new();
+        let mut found_instant = false;
 
         // An example of the output format is shown in the tests below
         // It comprises a series of rows, some empty. The non-empty rows contain
-        // a key and a value separated by a colon followed by whitespace.
+        // a key and a value separated by the first colon - the timestamp has more.
         // The routine matches on the first word of the key and calls the appropriate
         // parser to convert the value into its natural type (from a string).
         for line in &mut output.lines() {
-            // Can't use a simple colon (:) for the split here because of the timestamp date string
-            let parts: Vec<&str> = line.trim().split(": ").collect();
-
-            // skip empty lines by checking # parts
-            if parts.len() == 2 {
-                let (lhs, rhs) = (parts[0], parts[1]);
-                let lhs_parts: Vec<&str> = lhs.split_ascii_whitespace().collect();
-                assert!(!lhs_parts.is_empty());
-
-                // despite what clippy says, this won't panic
-                match lhs_parts[0] {
-                    "Instantaneous" => readings.instant = BMC::parse_number(rhs.trim()),
-                    "Minimum" => readings.minimum = BMC::parse_number(rhs.trim()),
-                    "Maximum" => readings.maximum = BMC::parse_number(rhs.trim()),
-                    "Average" => readings.average = BMC::parse_number(rhs.trim()),
-                    "IPMI" => readings.timestamp = BMC::date_from_string(rhs.trim()),
-                    _ => continue,
-                };
-            }
+            let Some((lhs, rhs)) = line.trim().split_once(':') else { continue };
+            let Some(key) = lhs.split_ascii_whitespace().next() else { continue };
+
+            match key {
+                "Instantaneous" => {
+                    readings.instant = BMC::parse_number(rhs)?;
+                    found_instant = true;
+                }
+                "Minimum" => readings.minimum = BMC::parse_number(rhs)?,
+                "Maximum" => readings.maximum = BMC::parse_number(rhs)?,
+                "Average" => readings.average = BMC::parse_number(rhs)?,
+                // ipmitool prints the BMC's time converted to the local timezone
+                "IPMI" => {
+                    readings.timestamp = Local
+                        .from_local_datetime(&profile.parse_timestamp(rhs)?)
+                        .earliest()
+                        .map(|t| t.with_timezone(&Utc));
+                }
+                "Sampling" => readings.sampling_period = Some(Duration::from_secs(BMC::parse_number(rhs)?)),
+                "Power" => {
+                    readings.reading_is_active = Some(rhs.trim().eq_ignore_ascii_case("activated"));
+                }
+                _ => continue,
+            };
         }
-        readings
+
+        if !found_instant {
+            return Err(BMCError::Parse(format!("no instantaneous power in '{}'", output.trim())));
+        }
+        Ok(readings)
     }
 
-    /// Parses the output of the IPMI dcmi power capping commands
-    /// Like `power_readings` above, the output comprises key/value pairs separated by a colon
-    // Example output is shown in the tests below.
-    #[must_use]
-    fn parse_cap_settings(output: &str) -> BMC_CapSetting {
-        // have to initialize here to keep the compiler happy
-        let mut is_active: bool = false;
-        let mut power_limit: u64 = 0;
+    fn parse_cap_settings(output: &str, profile: &VendorProfile) -> BMCResult<BMC_CapSetting> {
+        let mut is_active: Option<bool> = None;
+        let mut power_limit: Option<u64> = None;
+        let mut settings = BMC_CapSetting::default();
 
         for line in &mut output.lines() {
-            let parts: Vec<&str> = line.trim().split(':').collect();
-            if parts.len() == 2 {
-                let (lhs, rhs) = (parts[0], parts[1]);
-                match lhs.trim() {
-                    "Current Limit State" => is_active = rhs.trim() == "Power Limit Active",
-                    "Power Limit" => power_limit = BMC::parse_number(rhs),
-                    _ => continue,
+            let Some((lhs, rhs)) = line.trim().split_once(':') else { continue };
+            match lhs.trim().to_lowercase().as_str() {
+                "current limit state" => is_active = Some(profile.is_limit_active(rhs)),
+                "power limit" => power_limit = Some(BMC::parse_number(rhs)?),
+                "exception actions" | "exception action" => {
+                    settings.exception_action = ExceptionAction::from_ipmitool(rhs);
+                    if settings.exception_action.is_none() {
+                        warn!("BMC unknown exception action: {}", rhs.trim());
+                    }
                 }
+                "correction time" => settings.correction_time = Some(Duration::from_millis(BMC::parse_number(rhs)?)),
+                "sampling period" => settings.sampling_period = Some(Duration::from_secs(BMC::parse_number(rhs)?)),
+                _ => continue,
             }
         }
-        BMC_CapSetting {
-            is_active,
-            power_limit,
+
+        match (is_active, power_limit) {
+            (Some(is_active), Some(power_limit)) => Ok(BMC_CapSetting { is_active, power_limit, ..settings }),
+            _ => Err(BMCError::Parse(format!("no limit state or power limit in '{}'", output.trim()))),
         }
     }
 }
 
+/// The ipmitool options naming the BMC and user, the password is passed separately
 impl Display for BMC {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "-H {} -U {} -P {}",
-            self.hostname, self.username, self.password)
+        match &self.interface {
+            IpmiInterface::Lan { hostname, username, .. } => write!(f, "-H {hostname} -U {username}"),
+            IpmiInterface::Open { device } => write!(f, "-I open -d {device}"),
+        }
     }
 }
 
 impl Debug for BMC {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "-H {} -U {} -P ****",
-            self.hostname, self.username)
+        match &self.interface {
+            IpmiInterface::Lan { .. } => write!(f, "{self} -P ****"),
+            IpmiInterface::Open { .. } => write!(f, "{self}"),
+        }
     }
 }
 
+/// Reads a pipe of a child to the end on a thread of its own, so the child never blocks
+/// on a full pipe
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            if let Err(e) = pipe.read_to_end(&mut bytes) {
+                warn!("BMC failed to read ipmitool output: {e}");
+            }
+        }
+        bytes
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bmc::credentials::{EnvPassword, StaticPassword};
+    use crate::test_util::{fake_ipmitool, TempDir};
+    use chrono::NaiveDateTime;
 
     #[test]
     fn test_parse_power_reading() {
@@ -291,14 +637,16 @@ mod tests {
         Power reading state is:                   activated
         ";
 
-        let readings = BMC::parse_power_reading(bmc_output);
+        let readings = BMC::parse_power_reading(bmc_output, Vendor::Generic.profile()).unwrap();
         let expected_timestamp =
             NaiveDateTime::parse_from_str("2023 May 09 14:24:36", "%Y %b %d %H:%M:%S").unwrap();
         assert_eq!(readings.instant, 220);
         assert_eq!(readings.minimum, 70);
         assert_eq!(readings.maximum, 600);
         assert_eq!(readings.average, 220);
-        assert_eq!(readings.timestamp, expected_timestamp);
+        assert_eq!(readings.timestamp.unwrap().with_timezone(&Local).naive_local(), expected_timestamp);
+        assert_eq!(readings.sampling_period, Some(Duration::from_secs(5)));
+        assert_eq!(readings.reading_is_active, Some(true));
     }
 
     #[test]
@@ -311,9 +659,12 @@ mod tests {
         Sampling period:     5 seconds
         ";
 
-        let reading = BMC::parse_cap_settings(bmc_output);
+        let reading = BMC::parse_cap_settings(bmc_output, Vendor::Generic.profile()).unwrap();
         assert!(!reading.is_active);
         assert_eq!(reading.power_limit, 1600);
+        assert_eq!(reading.exception_action, Some(ExceptionAction::HardPowerOff));
+        assert_eq!(reading.correction_time, Some(Duration::from_millis(1000)));
+        assert_eq!(reading.sampling_period, Some(Duration::from_secs(5)));
     }
 
     #[test]
@@ -326,8 +677,137 @@ mod tests {
         Sampling period:     5 seconds
         ";
 
-        let reading = BMC::parse_cap_settings(bmc_output);
+        let reading = BMC::parse_cap_settings(bmc_output, Vendor::Generic.profile()).unwrap();
         assert!(reading.is_active);
         assert_eq!(reading.power_limit, 2000);
     }
+
+    #[test]
+    fn test_parse_cap_settings_log_event() {
+        let bmc_output = "
+        Current Limit State: No Active Power Limit
+        Exception actions:   Log Event to SEL
+        Power Limit:         450   Watts
+        Correction time:     6000 milliseconds
+        Sampling period:     1 seconds
+        ";
+
+        let reading = BMC::parse_cap_settings(bmc_output, Vendor::Generic.profile()).unwrap();
+        assert_eq!(reading.power_limit, 450);
+        assert_eq!(reading.exception_action, Some(ExceptionAction::LogEvent));
+        assert_eq!(reading.correction_time, Some(Duration::from_millis(6000)));
+        assert_eq!(reading.sampling_period, Some(Duration::from_secs(1)));
+        assert_eq!(ExceptionAction::from_dcmi(ExceptionAction::LogEvent.dcmi_code()), Some(ExceptionAction::LogEvent));
+    }
+
+    /// `dcmi power reading` and `dcmi power get_limit` outputs laid out to ipmitool's own
+    /// formats, not captured from a BMC. The two readings differ in how the ipmitool build
+    /// prints the BMC time.
+    const IPMITOOL_POWER_READINGS: [&str; 2] = [
+        include_str!("fixtures/dcmi/ipmitool_power_reading.txt"),
+        include_str!("fixtures/dcmi/ipmitool_power_reading_numeric_time.txt"),
+    ];
+    const IPMITOOL_GET_LIMIT: &str = include_str!("fixtures/dcmi/ipmitool_get_limit.txt");
+
+    #[test]
+    fn test_ipmitool_fixtures() {
+        for vendor in [Vendor::Generic, Vendor::Dell, Vendor::Hpe, Vendor::Lenovo, Vendor::Supermicro] {
+            for output in IPMITOOL_POWER_READINGS {
+                let reading = BMC::parse_power_reading(output, vendor.profile()).unwrap();
+                assert_eq!((reading.instant, reading.minimum, reading.maximum, reading.average), (262, 98, 431, 255));
+                assert!(reading.timestamp.is_some());
+                assert_eq!(reading.sampling_period, Some(Duration::from_secs(1)));
+                assert_eq!(reading.reading_is_active, Some(true));
+            }
+            let limit = BMC::parse_cap_settings(IPMITOOL_GET_LIMIT, vendor.profile()).unwrap();
+            assert!(limit.is_active);
+            assert_eq!(limit.power_limit, 400);
+            assert_eq!(limit.exception_action, Some(ExceptionAction::HardPowerOff));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(BMC::parse_power_reading("Error: Unable to establish IPMI v2 / RMCP+ session", Vendor::Generic.profile()).is_err());
+        assert!(BMC::parse_power_reading("Instantaneous power reading: N/A Watts", Vendor::Generic.profile()).is_err());
+        assert!(BMC::parse_cap_settings("Current Limit State: No Active Power Limit", Vendor::Generic.profile()).is_err());
+    }
+
+    fn password() -> Arc<dyn CredentialProvider> {
+        Arc::new(StaticPassword::new("s3cret"))
+    }
+
+    #[test]
+    fn test_run_command_errors() {
+        let bmc = BMC::new("localhost", "user", password(), "/nonexistent/ipmitool");
+        assert!(matches!(bmc.current_power(), Err(BMCError::Launch { .. })));
+
+        let bmc = BMC::new("localhost", "user", password(), "false");
+        assert!(matches!(bmc.current_power(), Err(BMCError::ExitStatus { code: Some(1), .. })));
+
+        let bmc = BMC::new("localhost", "user", Arc::new(EnvPassword::new("AGENT_TEST_UNSET_PASSWORD")), "true");
+        assert!(matches!(bmc.current_power(), Err(BMCError::Credentials(_))));
+    }
+
+    #[test]
+    fn test_password_not_in_arguments() {
+        // An ipmitool that prints its arguments and the password it was given
+        let dir = TempDir::new("bmc_args");
+        let script = fake_ipmitool(&dir, "echo \"args: $*\"\necho \"password: $IPMI_PASSWORD\"\n");
+
+        let bmc = BMC::new("bmc", "admin", password(), script.to_str().unwrap());
+        let output = bmc.run_command(BMC_READ_POWER_CMD).unwrap();
+        assert!(output.contains("args: -H bmc -U admin -E dcmi power reading\n"), "{output}");
+        assert!(output.contains("password: s3cret"));
+        assert!(!format!("{bmc:?} {bmc}").contains("s3cret"));
+    }
+
+    #[test]
+    fn test_in_band_arguments() {
+        let dir = TempDir::new("bmc_in_band");
+        let script = fake_ipmitool(&dir, "echo \"args: $*\"\n");
+
+        // No session, so no credentials either
+        let bmc = BMC::in_band(0, script.to_str().unwrap());
+        assert_eq!(format!("{bmc:?}"), "-I open -d 0");
+        let output = bmc.run_command(BMC_READ_POWER_CMD).unwrap();
+        assert!(output.contains("args: -I open -d 0 dcmi power reading\n"), "{output}");
+    }
+
+    #[test]
+    fn test_capability_arguments() {
+        // Logs each argument in brackets, a line per run
+        let script = r#"printf '[%s]' "$@" >> "$0.log"
+echo >> "$0.log"
+case "$*" in
+    *"dcmi discover") echo "    Power management available" ;;
+    *"nm capability") printf '    max_power_thermal: 900 Watts\n    min_power_thermal: 300 Watts\n' ;;
+    *) echo "Invalid command" >&2; exit 1 ;;
+esac
+"#;
+        let dir = TempDir::new("bmc_capability");
+        let ipmitool = fake_ipmitool(&dir, script);
+
+        let capabilities = BMC::in_band(0, ipmitool.to_str().unwrap()).cap_capabilities().unwrap();
+        assert!(capabilities.power_management);
+        assert_eq!((capabilities.min_limit_watts, capabilities.max_limit_watts), (Some(300), Some(900)));
+        let log = std::fs::read_to_string(ipmitool.with_extension("log")).unwrap();
+        assert_eq!(log, "[-I][open][-d][0][dcmi][discover]\n[-I][open][-d][0][nm][capability]\n");
+    }
+
+    #[test]
+    fn test_large_output() {
+        // Some 200 KB of SEL, well past what a pipe holds
+        let script = r#"i=0
+while [ $i -lt 2000 ]; do
+    echo "  $i | 05/09/2023 | 14:24:36 | Power Unit #0x01 | Power off/down | Asserted | padding the line out to a hundred"
+    i=$((i + 1))
+done
+echo "done" >&2
+"#;
+        let dir = TempDir::new("bmc_large_output");
+        let ipmitool = fake_ipmitool(&dir, script);
+        let entries = BMC::in_band(0, ipmitool.to_str().unwrap()).sel_entries().unwrap();
+        assert_eq!(entries.len(), 2000);
+    }
 }
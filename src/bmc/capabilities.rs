@@ -0,0 +1,157 @@
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::test::Test;
+use serde::{Deserialize, Serialize};
+
+/// What the platform can do about power capping, as discovered from the BMC
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapCapabilities {
+    /// eg "1.5"
+    pub dcmi_version: Option<String>,
+    /// Whether the optional DCMI power management (reading and limiting) is supported
+    pub power_management: bool,
+    /// The lowest and highest limits the platform accepts, when it says
+    pub min_limit_watts: Option<u64>,
+    pub max_limit_watts: Option<u64>,
+}
+
+impl CapCapabilities {
+    /// Whether the platform accepts a limit of `cap` Watts. Without a known range every
+    /// limit is taken to be fine.
+    #[must_use]
+    pub fn accepts(&self, cap: u64) -> bool {
+        self.min_limit_watts.map_or(true, |min| cap >= min) && self.max_limit_watts.map_or(true, |max| cap <= max)
+    }
+
+    /// Checks a whole test plan before any test runs, so a campaign doesn't fail halfway
+    /// through on a limit the BMC was never going to take
+    ///
+    /// # Errors
+    /// `Unsupported` if the platform can't cap power, `OutOfRange` listing the
+    /// `cap_from`/`cap_to` values outside the platform's range
+    pub fn check_plan(&self, tests: impl IntoIterator<Item = Test>) -> BMCResult<()> {
+        if !self.power_management {
+            return Err(BMCError::Unsupported(String::from("the platform doesn't support DCMI power management")));
+        }
+
+        let mut rejected: Vec<u64> = tests
+            .into_iter()
+            .flat_map(|test| [test.cap_from, test.cap_to])
+            .filter(|cap| !self.accepts(*cap))
+            .collect();
+        if rejected.is_empty() {
+            return Ok(());
+        }
+
+        rejected.sort_unstable();
+        rejected.dedup();
+        let range = |limit: Option<u64>| limit.map_or_else(|| String::from("?"), |watts| watts.to_string());
+        Err(BMCError::OutOfRange(format!(
+            "the test plan caps at {rejected:?}W, the platform accepts {}-{}W",
+            range(self.min_limit_watts),
+            range(self.max_limit_watts)
+        )))
+    }
+}
+
+/// Parses the output of `ipmitool dcmi discover`, leaving the limits out:
+///
+/// ```text
+///     DCMI Specification 1.5
+///     Supported DCMI capabilities:
+///         Optional platform capabilties
+///             Power management available
+/// ```
+#[must_use]
+pub fn parse_dcmi_discover(output: &str) -> CapCapabilities {
+    let dcmi_version = output.lines().find_map(|line| {
+        line.trim().strip_prefix("DCMI Specification").map(|version| String::from(version.trim()))
+    });
+    let power_management = output
+        .lines()
+        .any(|line| line.trim().eq_ignore_ascii_case("power management available"));
+
+    CapCapabilities { dcmi_version, power_management, ..CapCapabilities::default() }
+}
+
+/// The power limit range from the output of `ipmitool nm capability` (Intel Node
+/// Manager), eg `max_power_thermal: 900 Watts`. `None` for a limit that isn't there.
+#[must_use]
+pub fn parse_nm_capability(output: &str) -> (Option<u64>, Option<u64>) {
+    let mut limits = (None, None);
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let key = key.to_lowercase();
+        if !key.contains("power") {
+            continue;
+        }
+        let watts = value.split_ascii_whitespace().next().and_then(|word| word.parse().ok());
+        if key.contains("min") {
+            limits.0 = watts;
+        } else if key.contains("max") {
+            limits.1 = watts;
+        }
+    }
+    limits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::load_iterator::LoadTestSuite;
+    use crate::test::{CapMechanism, POWER_HIGH, POWER_LOW};
+
+    const DCMI_DISCOVER: &str = "
+    DCMI Specification 1.5
+
+    Supported DCMI capabilities:
+        Mandatory platform capabilties
+            Identification support available
+            SEL logging available
+            Chassis power available
+            Temperature monitor available
+
+        Optional platform capabilties
+            Power management available
+
+        Managebility access capabilties
+            In-band KCS channel available
+            Out-of-band primary LAN channel available
+";
+
+    const NM_CAPABILITY: &str = "
+    power policies:                 16
+    max_power_thermal:              900 Watts
+    min_power_thermal:              300 Watts
+    min_correction_time:            6 secs
+    max_correction_time:            600 secs
+";
+
+    #[test]
+    fn test_parse_capabilities() {
+        let capabilities = parse_dcmi_discover(DCMI_DISCOVER);
+        assert_eq!(capabilities.dcmi_version.as_deref(), Some("1.5"));
+        assert!(capabilities.power_management);
+
+        let without = DCMI_DISCOVER.replace("Power management available", "");
+        assert!(!parse_dcmi_discover(&without).power_management);
+
+        assert_eq!(parse_nm_capability(NM_CAPABILITY), (Some(300), Some(900)));
+        assert_eq!(parse_nm_capability("Error: No Node Manager"), (None, None));
+    }
+
+    #[test]
+    fn test_check_plan() {
+        let dcmi_tests = || LoadTestSuite::new().filter(|test| test.cap_mechanism == CapMechanism::Dcmi);
+        let mut capabilities = CapCapabilities { power_management: true, ..CapCapabilities::default() };
+        assert!(capabilities.check_plan(LoadTestSuite::new()).is_ok());
+
+        capabilities.min_limit_watts = Some(300);
+        capabilities.max_limit_watts = Some(900);
+        let error = capabilities.check_plan(dcmi_tests()).unwrap_err();
+        assert!(matches!(error, BMCError::OutOfRange(ref message) if message.contains(&format!("[{POWER_LOW}]"))));
+        assert!(capabilities.accepts(POWER_HIGH));
+
+        capabilities.power_management = false;
+        assert!(matches!(capabilities.check_plan(LoadTestSuite::new()), Err(BMCError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,400 @@
+use crate::bmc::bmc::{BMC, BMC_CapSetting, BMC_PowerReading, ExceptionAction};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::bmc::lanplus::LanplusBMC;
+use crate::bmc::node_manager::{NmDomain, NmPolicy, NmStatistics};
+use crate::bmc::redfish::Redfish;
+use crate::bmc::sel::SelEntry;
+use crate::bmc::sensors::SensorReading;
+use crate::bmc::simulated::SimulatedBMC;
+use crate::bmc::verify::CapAcceptance;
+use crate::model::FirestarterParams;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::time::Duration;
+use tokio::task;
+
+/// The capping mechanisms the client knows how to drive
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum ControllerType {
+    /// DCMI commands through ipmitool
+    Ipmi,
+    /// DCMI commands through one long-lived `ipmitool shell`
+    IpmiShell,
+    /// DCMI commands over a native, persistent RMCP+ session
+    Lanplus,
+    /// Redfish `Power`/`Controls` resources
+    Redfish,
+    /// DCMI commands run in-band by the agent, for BMCs the client can't reach. Needs
+    /// the agent started with `--serve-power-control`
+    Agent,
+    /// A power model standing in for a real node, see `SimulatedBMC`
+    Simulated,
+}
+
+/// Operations needed to run a capping campaign against a node. The test orchestration
+/// and the BMC monitor only talk to this trait, so any capping mechanism (or a fake one)
+/// can be dropped in.
+#[async_trait]
+pub trait PowerCapController: Send + Sync + Debug {
+    /// The instantaneous node power in Watts
+    async fn current_power(&self) -> BMCResult<u64>;
+
+    /// The full power reading. Mechanisms that only report the instantaneous power
+    /// leave the statistics, BMC timestamp and sampling period out.
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        Ok(BMC_PowerReading::from_instant(self.current_power().await?))
+    }
+
+    /// The rolling average periods the BMC keeps enhanced power statistics for
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        Err(BMCError::Unsupported(format!("{self:?} has no enhanced power statistics")))
+    }
+
+    /// The power statistics over one of the `enhanced_power_periods`
+    async fn enhanced_power_reading(&self, _period: Duration) -> BMCResult<BMC_PowerReading> {
+        Err(BMCError::Unsupported(format!("{self:?} has no enhanced power statistics")))
+    }
+
+    /// The current cap power limit and activation state
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting>;
+
+    /// Whether the platform can cap power, and within which range
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        Err(BMCError::Unsupported(format!("{self:?} can't discover its capabilities")))
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()>;
+
+    async fn activate_power_cap(&self) -> BMCResult<()>;
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()>;
+
+    /// Time allowed to bring power under the limit before the exception action is taken
+    async fn set_correction_time(&self, _correction_time: Duration) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} can't set the correction time")))
+    }
+
+    /// Period over which the BMC averages power when enforcing the limit
+    async fn set_sampling_period(&self, _sampling_period: Duration) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} can't set the sampling period")))
+    }
+
+    async fn set_exception_action(&self, _action: ExceptionAction) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} can't set the exception action")))
+    }
+
+    /// PSU power, temperatures and fan speeds, under vendor neutral names
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        Err(BMCError::Unsupported(format!("{self:?} can't read sensors")))
+    }
+
+    /// The System Event Log
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        Err(BMCError::Unsupported(format!("{self:?} can't read the SEL")))
+    }
+
+    /// Creates or replaces an Intel Node Manager policy
+    async fn nm_set_policy(&self, _policy: &NmPolicy) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    async fn nm_remove_policy(&self, _domain: NmDomain, _policy_id: u8) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    /// Enables or disables an existing Node Manager policy, leaving its settings alone
+    async fn nm_set_policy_enabled(&self, _domain: NmDomain, _policy_id: u8, _enabled: bool) -> BMCResult<()> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    async fn nm_policy(&self, _domain: NmDomain, _policy_id: u8) -> BMCResult<NmPolicy> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    /// Power statistics of one policy, or of the whole domain without one
+    async fn nm_statistics(&self, _domain: NmDomain, _policy_id: Option<u8>) -> BMCResult<NmStatistics> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    /// The limit range of a Node Manager domain
+    async fn nm_capabilities(&self, _domain: NmDomain) -> BMCResult<CapCapabilities> {
+        Err(BMCError::Unsupported(format!("{self:?} has no Node Manager")))
+    }
+
+    /// Called before the agent is asked to run a load. Hardware doesn't need
+    /// telling, but a simulated node has to know what it is running.
+    async fn set_workload(&self, _params: &FirestarterParams) {}
+
+    /// Hands over the acceptance measurements made since the last call. Only a
+    /// `VerifiedController` reads back its cap changes, other controllers have none.
+    fn take_cap_acceptances(&self) -> Vec<CapAcceptance> {
+        Vec::new()
+    }
+}
+
+/// Runs a blocking BMC call on tokio's blocking thread pool
+async fn blocking<T, F>(f: F) -> BMCResult<T>
+where
+    F: FnOnce() -> BMCResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .expect("Blocking BMC task panicked")
+}
+
+/// The ipmitool BMC blocks while ipmitool runs, so each call is pushed onto
+/// tokio's blocking thread pool.
+#[async_trait]
+impl PowerCapController for BMC {
+    async fn current_power(&self) -> BMCResult<u64> {
+        let bmc = self.clone();
+        blocking(move || BMC::current_power(&bmc)).await
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        let bmc = self.clone();
+        blocking(move || BMC::current_power_reading(&bmc)).await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        let bmc = self.clone();
+        blocking(move || BMC::enhanced_power_periods(&bmc)).await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        let bmc = self.clone();
+        blocking(move || BMC::enhanced_power_reading(&bmc, period)).await
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let bmc = self.clone();
+        blocking(move || BMC::current_cap_settings(&bmc)).await
+    }
+
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        let bmc = self.clone();
+        blocking(move || BMC::cap_capabilities(&bmc)).await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::set_cap_power_level(&bmc, cap)).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::activate_power_cap(&bmc)).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::deactivate_power_cap(&bmc)).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::set_correction_time(&bmc, correction_time)).await
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::set_sampling_period(&bmc, sampling_period)).await
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::set_exception_action(&bmc, action)).await
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        let bmc = self.clone();
+        blocking(move || BMC::sensor_readings(&bmc)).await
+    }
+
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        let bmc = self.clone();
+        blocking(move || BMC::sel_entries(&bmc)).await
+    }
+
+    async fn nm_set_policy(&self, policy: &NmPolicy) -> BMCResult<()> {
+        let (bmc, policy) = (self.clone(), *policy);
+        blocking(move || BMC::nm_set_policy(&bmc, &policy)).await
+    }
+
+    async fn nm_remove_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::nm_remove_policy(&bmc, domain, policy_id)).await
+    }
+
+    async fn nm_set_policy_enabled(&self, domain: NmDomain, policy_id: u8, enabled: bool) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || BMC::nm_set_policy_enabled(&bmc, domain, policy_id, enabled)).await
+    }
+
+    async fn nm_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<NmPolicy> {
+        let bmc = self.clone();
+        blocking(move || BMC::nm_policy(&bmc, domain, policy_id)).await
+    }
+
+    async fn nm_statistics(&self, domain: NmDomain, policy_id: Option<u8>) -> BMCResult<NmStatistics> {
+        let bmc = self.clone();
+        blocking(move || BMC::nm_statistics(&bmc, domain, policy_id)).await
+    }
+
+    async fn nm_capabilities(&self, domain: NmDomain) -> BMCResult<CapCapabilities> {
+        let bmc = self.clone();
+        blocking(move || BMC::nm_capabilities(&bmc, domain)).await
+    }
+}
+
+/// Like the ipmitool BMC, the lanplus session does blocking socket I/O
+#[async_trait]
+impl PowerCapController for LanplusBMC {
+    async fn current_power(&self) -> BMCResult<u64> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::current_power(&bmc)).await
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::current_power_reading(&bmc)).await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::enhanced_power_periods(&bmc)).await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::enhanced_power_reading(&bmc, period)).await
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::current_cap_settings(&bmc)).await
+    }
+
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::cap_capabilities(&bmc)).await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::set_cap_power_level(&bmc, cap)).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::activate_power_cap(&bmc)).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::deactivate_power_cap(&bmc)).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::set_correction_time(&bmc, correction_time)).await
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::set_sampling_period(&bmc, sampling_period)).await
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        let bmc = self.clone();
+        blocking(move || LanplusBMC::set_exception_action(&bmc, action)).await
+    }
+}
+
+#[async_trait]
+impl PowerCapController for Redfish {
+    async fn current_power(&self) -> BMCResult<u64> {
+        Redfish::current_power(self).await
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        Redfish::current_cap_settings(self).await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        Redfish::set_cap_power_level(self, cap).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        Redfish::activate_power_cap(self).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        Redfish::deactivate_power_cap(self).await
+    }
+}
+
+/// The simulation can't fail
+#[async_trait]
+impl PowerCapController for SimulatedBMC {
+    async fn current_power(&self) -> BMCResult<u64> {
+        Ok(SimulatedBMC::current_power(self))
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        Ok(SimulatedBMC::current_power_reading(self))
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        Ok(SimulatedBMC::current_cap_settings(self))
+    }
+
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        Ok(SimulatedBMC::cap_capabilities(self))
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        SimulatedBMC::set_cap_power_level(self, cap);
+        Ok(())
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        SimulatedBMC::activate_power_cap(self);
+        Ok(())
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        SimulatedBMC::deactivate_power_cap(self);
+        Ok(())
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        SimulatedBMC::set_correction_time(self, correction_time);
+        Ok(())
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        SimulatedBMC::set_sampling_period(self, sampling_period);
+        Ok(())
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        SimulatedBMC::set_exception_action(self, action);
+        Ok(())
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        Ok(SimulatedBMC::sensor_readings(self))
+    }
+
+    /// Nothing ever happens to a simulated platform
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        Ok(Vec::new())
+    }
+
+    async fn set_workload(&self, params: &FirestarterParams) {
+        SimulatedBMC::set_workload(self, params);
+    }
+}
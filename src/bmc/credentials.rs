@@ -0,0 +1,234 @@
+//! Where the BMC password comes from. A password never goes on a command line, where
+//! every user of the host could read it with `ps`: ipmitool is given it through its
+//! own `-E` (environment) or `-f` (file) options.
+
+use crate::bmc::error::{BMCError, BMCResult};
+use std::env;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// The environment variable read by `ipmitool -E`
+pub const IPMI_PASSWORD_ENV: &str = "IPMI_PASSWORD";
+
+/// A password that doesn't show up in `Debug` output, so it can't end up in a log
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    #[must_use]
+    pub fn new(secret: &str) -> Self {
+        Self(String::from(secret))
+    }
+
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "****")
+    }
+}
+
+/// How ipmitool is handed the password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpmitoolPassword {
+    /// `-E`, with the password set in the child's `IPMI_PASSWORD`
+    Env(Secret),
+    /// `-f <file>`, ipmitool reads the file itself
+    File(PathBuf),
+}
+
+impl IpmitoolPassword {
+    /// The ipmitool options, which never include the password itself
+    #[must_use]
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            IpmitoolPassword::Env(_) => vec![String::from("-E")],
+            IpmitoolPassword::File(path) => vec![String::from("-f"), path.display().to_string()],
+        }
+    }
+
+    /// The environment the ipmitool process needs
+    #[must_use]
+    pub fn envs(&self) -> Vec<(&'static str, Secret)> {
+        match self {
+            IpmitoolPassword::Env(password) => vec![(IPMI_PASSWORD_ENV, password.clone())],
+            IpmitoolPassword::File(_) => Vec::new(),
+        }
+    }
+}
+
+/// A source of the BMC password. It's read when a connection needs it rather than
+/// kept around, so a rotated password file is picked up.
+pub trait CredentialProvider: Send + Sync + Debug {
+    /// # Errors
+    /// `Credentials` if the password can't be had
+    fn password(&self) -> BMCResult<Secret>;
+
+    /// How to pass the password to ipmitool. By default through its environment.
+    ///
+    /// # Errors
+    /// `Credentials` if the password can't be had
+    fn ipmitool_password(&self) -> BMCResult<IpmitoolPassword> {
+        Ok(IpmitoolPassword::Env(self.password()?))
+    }
+}
+
+/// The password from an environment variable of the client
+#[derive(Debug, Clone)]
+pub struct EnvPassword {
+    var: String,
+}
+
+impl EnvPassword {
+    #[must_use]
+    pub fn new(var: &str) -> Self {
+        Self { var: String::from(var) }
+    }
+}
+
+impl CredentialProvider for EnvPassword {
+    fn password(&self) -> BMCResult<Secret> {
+        env::var(&self.var)
+            .map(Secret)
+            .map_err(|e| BMCError::Credentials(format!("${}: {e}", self.var)))
+    }
+}
+
+/// The password from the first line of a file, as `ipmitool -f` reads it
+#[derive(Debug, Clone)]
+pub struct PasswordFile {
+    path: PathBuf,
+}
+
+impl PasswordFile {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self { path: PathBuf::from(path) }
+    }
+}
+
+impl CredentialProvider for PasswordFile {
+    fn password(&self) -> BMCResult<Secret> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| BMCError::Credentials(format!("{}: {e}", self.path.display())))?;
+        let password = contents.lines().next().unwrap_or_default();
+        if password.is_empty() {
+            return Err(BMCError::Credentials(format!("{}: no password", self.path.display())));
+        }
+        Ok(Secret::new(password))
+    }
+
+    /// ipmitool reads the file itself
+    fn ipmitool_password(&self) -> BMCResult<IpmitoolPassword> {
+        Ok(IpmitoolPassword::File(self.path.clone()))
+    }
+}
+
+/// The password piped or typed into the client, read once when it starts since stdin
+/// can't be read again
+#[derive(Debug, Clone)]
+pub struct StdinPassword(Secret);
+
+impl StdinPassword {
+    /// Reads the password from the first line of `input`
+    ///
+    /// # Errors
+    /// `Credentials` if the line can't be read or is empty
+    pub fn read(mut input: impl BufRead) -> BMCResult<Self> {
+        let mut line = String::new();
+        input
+            .read_line(&mut line)
+            .map_err(|e| BMCError::Credentials(format!("stdin: {e}")))?;
+        let password = line.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            return Err(BMCError::Credentials(String::from("stdin: no password")));
+        }
+        Ok(Self(Secret::new(password)))
+    }
+}
+
+impl CredentialProvider for StdinPassword {
+    fn password(&self) -> BMCResult<Secret> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A password known up front
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct StaticPassword(Secret);
+
+#[cfg(test)]
+impl StaticPassword {
+    #[must_use]
+    pub fn new(password: &str) -> Self {
+        Self(Secret::new(password))
+    }
+}
+
+#[cfg(test)]
+impl CredentialProvider for StaticPassword {
+    fn password(&self) -> BMCResult<Secret> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_env_password() {
+        let var = format!("AGENT_TEST_PASSWORD_{}", std::process::id());
+        let provider = EnvPassword::new(&var);
+        assert!(matches!(provider.password(), Err(BMCError::Credentials(_))));
+
+        env::set_var(&var, "s3cret");
+        assert_eq!(provider.password().unwrap().expose(), "s3cret");
+        let ipmitool = provider.ipmitool_password().unwrap();
+        assert_eq!(ipmitool.args(), ["-E"]);
+        assert_eq!(ipmitool.envs(), [(IPMI_PASSWORD_ENV, Secret::new("s3cret"))]);
+        env::remove_var(&var);
+    }
+
+    #[test]
+    fn test_password_file() {
+        let dir = TempDir::new("password");
+        let path = dir.join("password");
+        let provider = PasswordFile::new(&path);
+        assert!(matches!(provider.password(), Err(BMCError::Credentials(_))));
+
+        fs::write(&path, "s3cret\n").unwrap();
+        assert_eq!(provider.password().unwrap().expose(), "s3cret");
+        let ipmitool = provider.ipmitool_password().unwrap();
+        assert_eq!(ipmitool.args(), ["-f", path.to_str().unwrap()]);
+        assert!(ipmitool.envs().is_empty());
+
+        fs::write(&path, "\n").unwrap();
+        assert!(matches!(provider.password(), Err(BMCError::Credentials(_))));
+    }
+
+    #[test]
+    fn test_stdin_password() {
+        let provider = StdinPassword::read("s3cret\r\nnext line\n".as_bytes()).unwrap();
+        assert_eq!(provider.password().unwrap().expose(), "s3cret");
+        assert_eq!(provider.ipmitool_password().unwrap().args(), ["-E"]);
+
+        assert!(matches!(StdinPassword::read("\n".as_bytes()), Err(BMCError::Credentials(_))));
+        assert!(matches!(StdinPassword::read("".as_bytes()), Err(BMCError::Credentials(_))));
+    }
+
+    #[test]
+    fn test_secret_is_not_logged() {
+        let provider = StaticPassword::new("s3cret");
+        assert!(!format!("{provider:?}").contains("s3cret"));
+        assert!(!format!("{:?}", provider.ipmitool_password().unwrap()).contains("s3cret"));
+    }
+}
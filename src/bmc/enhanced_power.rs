@@ -0,0 +1,100 @@
+use crate::bmc::bmc::BMC_PowerReading;
+use crate::bmc::lanplus::DCMI_GROUP_ID;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Get DCMI Capabilities Info parameter: enhanced system power statistics attributes
+pub const DCMI_ENHANCED_POWER_ATTRIBUTES: u8 = 0x05;
+/// Get Power Reading mode: statistics over one of the rolling average periods
+pub const DCMI_ENHANCED_POWER_STATISTICS: u8 = 0x02;
+
+/// A rolling average period byte is a count in bits 5:0 and a unit in bits 7:6
+const PERIOD_COUNT_MASK: u8 = 0x3f;
+const PERIOD_UNIT_SHIFT: u8 = 6;
+const PERIOD_UNIT_SECS: [u64; 4] = [1, 60, 60 * 60, 24 * 60 * 60];
+
+/// The BMC's own power statistics over one rolling average period
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollingAverage {
+    pub period_secs: u64,
+    pub average: u64,
+    pub minimum: u64,
+    pub maximum: u64,
+}
+
+impl RollingAverage {
+    #[must_use]
+    pub fn new(period: Duration, reading: &BMC_PowerReading) -> Self {
+        Self {
+            period_secs: period.as_secs(),
+            average: reading.average,
+            minimum: reading.minimum,
+            maximum: reading.maximum,
+        }
+    }
+}
+
+/// Decodes a rolling average period byte
+#[must_use]
+pub fn period_from_dcmi(code: u8) -> Duration {
+    let unit = PERIOD_UNIT_SECS[usize::from(code >> PERIOD_UNIT_SHIFT)];
+    Duration::from_secs(u64::from(code & PERIOD_COUNT_MASK) * unit)
+}
+
+/// Encodes a period in the largest unit that holds it exactly. `None` for a period
+/// that can't be encoded, eg 90 minutes.
+#[must_use]
+pub fn period_to_dcmi(period: Duration) -> Option<u8> {
+    let secs = period.as_secs();
+    PERIOD_UNIT_SECS.iter().enumerate().rev().find_map(|(unit, unit_secs)| {
+        let count = u8::try_from(secs / unit_secs).ok()?;
+        let exact = secs % unit_secs == 0 && count > 0 && count <= PERIOD_COUNT_MASK;
+        // The unit index is at most 3, so it fits in the top two bits
+        exact.then_some(((unit as u8) << PERIOD_UNIT_SHIFT) | count)
+    })
+}
+
+/// The rolling average periods from the enhanced system power statistics attributes
+/// of Get DCMI Capabilities Info, completion code already removed. `None` if the
+/// response is malformed.
+#[must_use]
+pub fn parse_enhanced_periods(data: &[u8]) -> Option<Vec<Duration>> {
+    if data.len() < 5 || data[0] != DCMI_GROUP_ID {
+        return None;
+    }
+    let count = usize::from(data[4]);
+    let periods = data.get(5..5 + count)?;
+    Some(periods.iter().map(|code| period_from_dcmi(*code)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_codes() {
+        assert_eq!(period_from_dcmi(0x1e), Duration::from_secs(30));
+        assert_eq!(period_from_dcmi(0x41), Duration::from_secs(60));
+        assert_eq!(period_from_dcmi(0x45), Duration::from_secs(300));
+        assert_eq!(period_from_dcmi(0x81), Duration::from_secs(3600));
+
+        for code in [0x1e, 0x41, 0x45, 0x81, 0xc1] {
+            assert_eq!(period_to_dcmi(period_from_dcmi(code)), Some(code));
+        }
+        assert_eq!(period_to_dcmi(Duration::from_secs(90)), None);
+        assert_eq!(period_to_dcmi(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_parse_enhanced_periods() {
+        // DCMI 1.5, revision 2, 30s, 1min and 5min
+        let data = [0xdc, 0x01, 0x05, 0x02, 0x03, 0x1e, 0x41, 0x45];
+        assert_eq!(
+            parse_enhanced_periods(&data),
+            Some(vec![Duration::from_secs(30), Duration::from_secs(60), Duration::from_secs(300)])
+        );
+        assert_eq!(parse_enhanced_periods(&[0xdc, 0x01, 0x05, 0x02, 0x00]), Some(Vec::new()));
+        assert_eq!(parse_enhanced_periods(&data[..6]), None);
+        assert_eq!(parse_enhanced_periods(&[0x00; 8]), None);
+    }
+}
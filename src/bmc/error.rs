@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+
+/// Everything that can go wrong talking to a BMC
+#[derive(Debug)]
+pub enum BMCError {
+    /// The ipmitool executable couldn't be launched
+    Launch { command: String, source: io::Error },
+    /// ipmitool ran but exited with a failure status
+    ExitStatus { command: String, code: Option<i32>, stderr: String },
+    /// The BMC refused the credentials or the privilege level
+    Authentication(String),
+    /// The password couldn't be had from its source, eg an unset environment variable
+    Credentials(String),
+    /// No response from the BMC within the allowed time
+    Timeout(String),
+    /// The BMC doesn't implement the (DCMI) command
+    Unsupported(String),
+    /// The power limit is outside the range the platform accepts
+    OutOfRange(String),
+    /// The BMC settings could harm the node, eg power it off
+    Unsafe(String),
+    /// The BMC answered, but not in a form we understand
+    Parse(String),
+    /// Network, HTTP or IPMI protocol level failure
+    Transport(String),
+}
+
+pub type BMCResult<T> = Result<T, BMCError>;
+
+impl BMCError {
+    /// Classifies a failed ipmitool run from its stderr. ipmitool exits with status 1
+    /// for nearly everything, so the message is all there is to go on.
+    #[must_use]
+    pub fn from_ipmitool(command: &str, code: Option<i32>, stderr: &str) -> Self {
+        let message = stderr.to_lowercase();
+        let context = || format!("{command}: {}", stderr.trim());
+
+        if ["unauthorized name", "hmac is invalid", "invalid user name", "password", "insufficient privilege", "authentication"]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+        {
+            BMCError::Authentication(context())
+        } else if ["timeout", "timed out", "no response", "unable to establish"]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+        {
+            BMCError::Timeout(context())
+        } else if message.contains("out of range") {
+            BMCError::OutOfRange(context())
+        } else if ["invalid command", "not supported", "invalid dcmi", "dcmi is not", "command not supported"]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+        {
+            BMCError::Unsupported(context())
+        } else {
+            BMCError::ExitStatus {
+                command: String::from(command),
+                code,
+                stderr: String::from(stderr.trim()),
+            }
+        }
+    }
+
+    /// Only a failed launch, rejected or missing credentials, a missing command, a limit
+    /// the platform won't take or unsafe settings will keep on failing
+    #[must_use]
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            BMCError::Launch { .. } | BMCError::Authentication(_) | BMCError::Credentials(_) | BMCError::Unsupported(_) | BMCError::OutOfRange(_) | BMCError::Unsafe(_)
+        )
+    }
+}
+
+impl Display for BMCError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BMCError::Launch { command, source } => write!(f, "failed to launch {command}: {source}"),
+            BMCError::ExitStatus { command, code, stderr } => match code {
+                Some(code) => write!(f, "{command} exited with status {code}: {stderr}"),
+                None => write!(f, "{command} was killed by a signal: {stderr}"),
+            },
+            BMCError::Authentication(message) => write!(f, "BMC authentication failed: {message}"),
+            BMCError::Credentials(message) => write!(f, "no BMC password: {message}"),
+            BMCError::Timeout(message) => write!(f, "BMC timed out: {message}"),
+            BMCError::Unsupported(message) => write!(f, "BMC doesn't support the command: {message}"),
+            BMCError::OutOfRange(message) => write!(f, "power limit out of range: {message}"),
+            BMCError::Unsafe(message) => write!(f, "unsafe BMC settings: {message}"),
+            BMCError::Parse(message) => write!(f, "failed to parse BMC output: {message}"),
+            BMCError::Transport(message) => write!(f, "BMC transport error: {message}"),
+        }
+    }
+}
+
+impl Error for BMCError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BMCError::Launch { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BMCError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => BMCError::Timeout(e.to_string()),
+            _ => BMCError::Transport(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_ipmitool_errors() {
+        let stderr = "RAKP 2 message indicates an error : unauthorized name\nError: Unable to establish IPMI v2 / RMCP+ session";
+        assert!(matches!(BMCError::from_ipmitool("dcmi power reading", Some(1), stderr), BMCError::Authentication(_)));
+
+        let stderr = "Error: Unable to establish IPMI v2 / RMCP+ session";
+        assert!(matches!(BMCError::from_ipmitool("dcmi power reading", Some(1), stderr), BMCError::Timeout(_)));
+
+        let stderr = "DCMI request failed because: Invalid command (c1)";
+        assert!(matches!(BMCError::from_ipmitool("dcmi power get_limit", Some(1), stderr), BMCError::Unsupported(_)));
+
+        let stderr = "DCMI request failed because: Power Limit out of range (84)";
+        assert!(matches!(BMCError::from_ipmitool("dcmi power set_limit limit 90", Some(1), stderr), BMCError::OutOfRange(_)));
+
+        let error = BMCError::from_ipmitool("dcmi power activate", Some(1), "Node busy");
+        assert!(matches!(error, BMCError::ExitStatus { code: Some(1), .. }));
+        assert!(!error.is_fatal());
+    }
+
+    #[test]
+    fn test_local_permission_error_is_not_authentication() {
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "/usr/bin/ipmitool: Permission denied");
+        assert!(matches!(BMCError::from(e), BMCError::Transport(_)));
+    }
+}
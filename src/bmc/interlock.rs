@@ -0,0 +1,69 @@
+use crate::bmc::bmc::ExceptionAction;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use log::{info, warn};
+
+/// Makes sure the BMC won't power the node off when a cap undershoots, which a stepped
+/// or low cap can easily do. When given, `campaign_action` is applied first; the
+/// snapshot taken before the campaign puts the old action back afterwards.
+///
+/// # Errors
+/// `Unsafe` if the exception action is, or might be, a hard power off and
+/// `allow_power_off` isn't set. Any error reading the cap settings.
+pub async fn ensure_safe_exception_action(
+    controller: &dyn PowerCapController,
+    campaign_action: Option<ExceptionAction>,
+    allow_power_off: bool,
+) -> BMCResult<()> {
+    if let Some(action) = campaign_action {
+        match controller.set_exception_action(action).await {
+            Ok(()) => info!("Exception action set to {action:?} for the campaign"),
+            Err(e) if e.is_fatal() && !matches!(e, BMCError::Unsupported(_)) => return Err(e),
+            // Whatever the BMC is left with gets checked below
+            Err(e) => warn!("Failed to set the exception action: {e}"),
+        }
+    }
+
+    let action = controller.current_cap_settings().await?.exception_action;
+    match action {
+        Some(ExceptionAction::NoAction | ExceptionAction::LogEvent) => Ok(()),
+        _ if allow_power_off => {
+            warn!("Exception action is {action:?}: the node may be powered off if a cap isn't met in time");
+            Ok(())
+        }
+        Some(ExceptionAction::HardPowerOff) => Err(BMCError::Unsafe(String::from(
+            "the BMC will power the node off if a cap isn't met in time. \
+             Pick a safe action with --campaign-exception-action, or accept the risk with --allow-hard-power-off",
+        ))),
+        None => Err(BMCError::Unsafe(String::from(
+            "the BMC doesn't report its exception action, it could power the node off. \
+             Accept the risk with --allow-hard-power-off",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::simulated::SimulatedBMC;
+
+    #[tokio::test]
+    async fn test_hard_power_off_is_refused() {
+        let sim = SimulatedBMC::default();
+        assert!(ensure_safe_exception_action(&sim, None, false).await.is_ok());
+
+        sim.set_exception_action(ExceptionAction::HardPowerOff);
+        assert!(matches!(ensure_safe_exception_action(&sim, None, false).await, Err(BMCError::Unsafe(_))));
+        assert!(ensure_safe_exception_action(&sim, None, true).await.is_ok());
+        assert_eq!(sim.current_cap_settings().exception_action, Some(ExceptionAction::HardPowerOff));
+    }
+
+    #[tokio::test]
+    async fn test_campaign_action_is_applied() {
+        let sim = SimulatedBMC::default();
+        sim.set_exception_action(ExceptionAction::HardPowerOff);
+
+        assert!(ensure_safe_exception_action(&sim, Some(ExceptionAction::LogEvent), false).await.is_ok());
+        assert_eq!(sim.current_cap_settings().exception_action, Some(ExceptionAction::LogEvent));
+    }
+}
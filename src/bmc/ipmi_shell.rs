@@ -0,0 +1,253 @@
+use crate::bmc::credentials::Secret;
+use crate::bmc::error::{BMCError, BMCResult};
+use log::{info, trace, warn};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What `ipmitool shell` prints when it's ready for the next command
+const IPMI_SHELL_PROMPT: &[u8] = b"ipmitool> ";
+/// ipmitool writes a command's errors before printing the next prompt, but the two
+/// pipes are read by different threads, so stderr is given a moment to catch up.
+const IPMI_SHELL_STDERR_GRACE_MILLIS: u64 = 10;
+const IPMI_SHELL_READ_BUFFER_BYTES: usize = 4096;
+
+/// A long-lived `ipmitool ... shell` process. Commands are written to its stdin, and
+/// a command's output is everything printed on stdout up to the next prompt. This
+/// saves the fork and the IPMI session set-up ipmitool pays for on every one-shot run.
+///
+/// A session that died or stopped answering is killed, and a new one is started for
+/// the next command.
+pub struct IpmiShell {
+    ipmi: String,
+    args: Vec<String>,
+    envs: Vec<(&'static str, Secret)>,
+    timeout: Duration,
+    session: Option<Session>,
+}
+
+impl IpmiShell {
+    /// `args` are the ipmitool arguments placed before `shell`, eg the BMC and user,
+    /// and `envs` the environment ipmitool is started with, eg the password.
+    /// `timeout` applies to every command, and to starting the shell.
+    #[must_use]
+    pub fn new(ipmi: &str, args: Vec<String>, envs: Vec<(&'static str, Secret)>, timeout: Duration) -> Self {
+        Self { ipmi: String::from(ipmi), args, envs, timeout, session: None }
+    }
+
+    /// Runs `command` in the shell, starting or restarting the shell as needed
+    ///
+    /// # Errors
+    /// If the shell can't be started, the command times out, ipmitool exits while running
+    /// it, or it prints an error instead of a result
+    pub fn run(&mut self, command: &str) -> BMCResult<String> {
+        // ipmitool may have exited while idle, eg when the BMC dropped the session
+        if self.session.as_mut().is_some_and(|session| !session.is_alive()) {
+            warn!("ipmitool shell exited, restarting it");
+            self.session = None;
+        }
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => self.session.insert(Session::start(&self.ipmi, &self.args, &self.envs, self.timeout)?),
+        };
+
+        match session.run(command, self.timeout) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("ipmitool shell failed running {command}, it will be restarted: {e}");
+                self.session = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// One running `ipmitool shell`. Both output pipes are read on their own threads so
+/// that reads can time out.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Receiver<Vec<u8>>,
+    stderr: Receiver<String>,
+}
+
+impl Session {
+    fn start(ipmi: &str, args: &[String], envs: &[(&'static str, Secret)], timeout: Duration) -> BMCResult<Self> {
+        let mut child = Command::new(ipmi)
+            .args(args)
+            .arg("shell")
+            .envs(envs.iter().map(|(name, secret)| (name, secret.expose())))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| BMCError::Launch { command: format!("{ipmi} shell"), source })?;
+
+        let stdin = child.stdin.take().expect("ipmitool shell stdin is piped");
+        let mut stdout = child.stdout.take().expect("ipmitool shell stdout is piped");
+        let stderr = child.stderr.take().expect("ipmitool shell stderr is piped");
+
+        let (stdout_tx, stdout_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; IPMI_SHELL_READ_BUFFER_BYTES];
+            while let Ok(n) = stdout.read(&mut buffer) {
+                if n == 0 || stdout_tx.send(buffer[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        let (stderr_tx, stderr_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                if stderr_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut session = Self { child, stdin, stdout: stdout_rx, stderr: stderr_rx };
+        // ipmitool opens the IPMI session before the first prompt, so bad credentials show up here
+        session.read_until_prompt("shell", timeout)?;
+        info!("ipmitool shell started");
+        Ok(session)
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Sends one command. The outer error means the session is unusable, the inner
+    /// one that ipmitool reported a failure of the command.
+    fn run(&mut self, command: &str, timeout: Duration) -> BMCResult<BMCResult<String>> {
+        trace!("ipmitool shell running: {command}");
+        // Left over from an earlier command that timed out on our side only
+        while self.stdout.try_recv().is_ok() {}
+        while self.stderr.try_recv().is_ok() {}
+
+        writeln!(self.stdin, "{command}")
+            .and_then(|()| self.stdin.flush())
+            .map_err(|e| BMCError::Transport(format!("{command}: writing to ipmitool shell: {e}")))?;
+
+        let stdout = self.read_until_prompt(command, timeout)?;
+        // Some readline builds echo the command back when stdin isn't a terminal
+        let stdout = stdout.strip_prefix(command).and_then(|rest| rest.strip_prefix('\n')).unwrap_or(&stdout);
+
+        let stderr = self.take_stderr(Duration::from_millis(IPMI_SHELL_STDERR_GRACE_MILLIS));
+        if !stderr.is_empty() {
+            // There is no exit status in the shell, so a command failed if all it did was complain
+            if stdout.trim().is_empty() {
+                return Ok(Err(BMCError::from_ipmitool(command, None, &stderr)));
+            }
+            warn!("ipmitool shell {command} stderr: {stderr}");
+        }
+
+        Ok(Ok(String::from(stdout)))
+    }
+
+    fn read_until_prompt(&mut self, command: &str, timeout: Duration) -> BMCResult<String> {
+        let deadline = Instant::now() + timeout;
+        let mut output = Vec::new();
+
+        while !output.ends_with(IPMI_SHELL_PROMPT) {
+            match self.stdout.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(chunk) => output.extend_from_slice(&chunk),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(BMCError::Timeout(format!("{command}: no prompt from ipmitool shell after {}ms", timeout.as_millis())));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // ipmitool exited: its stderr says why
+                    let stderr = self.take_stderr(timeout);
+                    let code = self.child.wait().ok().and_then(|status| status.code());
+                    return Err(BMCError::from_ipmitool(command, code, &stderr));
+                }
+            }
+        }
+
+        output.truncate(output.len() - IPMI_SHELL_PROMPT.len());
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Whatever arrives on stderr until it has been quiet for `grace`
+    fn take_stderr(&self, grace: Duration) -> String {
+        let mut lines = Vec::new();
+        while let Ok(line) = self.stderr.recv_timeout(grace) {
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Best effort - the process may be gone already
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fake_ipmitool, TempDir};
+    use std::fs;
+    use std::path::Path;
+
+    /// Answers like `ipmitool shell`, and counts its launches in `<script>.launches`
+    const SHELL_SCRIPT: &str = r#"echo launched >> "$0.launches"
+printf 'ipmitool> '
+while read -r line; do
+    case "$line" in
+        "dcmi power reading") echo "    Instantaneous power reading:                   231 Watts" ;;
+        "dcmi power get_limit") echo "    Power Limit:                          400 Watts" ;;
+        "bad command") echo "Invalid command: bad command" >&2 ;;
+        "crash") exit 1 ;;
+        "hang") sleep 5 ;;
+    esac
+    printf 'ipmitool> '
+done
+"#;
+
+    fn launches(script: &Path) -> usize {
+        fs::read_to_string(script.with_extension("launches")).map(|s| s.lines().count()).unwrap_or(0)
+    }
+
+    fn shell(script: &Path, timeout: Duration) -> IpmiShell {
+        IpmiShell::new(script.to_str().unwrap(), vec![String::from("-H"), String::from("bmc")], Vec::new(), timeout)
+    }
+
+    #[test]
+    fn test_commands_share_one_process() {
+        let dir = TempDir::new("ipmi_shell");
+        let script = fake_ipmitool(&dir, SHELL_SCRIPT);
+        let mut shell = shell(&script, Duration::from_secs(5));
+
+        assert!(shell.run("dcmi power reading").unwrap().contains("231 Watts"));
+        assert!(shell.run("dcmi power get_limit").unwrap().contains("400 Watts"));
+        assert!(matches!(shell.run("bad command"), Err(BMCError::Unsupported(_))));
+        assert!(shell.run("dcmi power reading").unwrap().contains("231 Watts"));
+        assert_eq!(launches(&script), 1);
+    }
+
+    #[test]
+    fn test_restarts_after_failure() {
+        let dir = TempDir::new("ipmi_shell");
+        let script = fake_ipmitool(&dir, SHELL_SCRIPT);
+        let mut shell = shell(&script, Duration::from_millis(500));
+
+        assert!(matches!(shell.run("crash"), Err(BMCError::ExitStatus { code: Some(1), .. })));
+        assert!(shell.run("dcmi power reading").unwrap().contains("231 Watts"));
+        assert_eq!(launches(&script), 2);
+
+        assert!(matches!(shell.run("hang"), Err(BMCError::Timeout(_))));
+        assert!(shell.run("dcmi power reading").unwrap().contains("231 Watts"));
+        assert_eq!(launches(&script), 3);
+    }
+
+    #[test]
+    fn test_launch_failure() {
+        let mut shell = IpmiShell::new("/nonexistent/ipmitool", Vec::new(), Vec::new(), Duration::from_secs(1));
+        assert!(matches!(shell.run("dcmi power reading"), Err(BMCError::Launch { .. })));
+    }
+}
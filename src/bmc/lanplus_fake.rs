@@ -0,0 +1,295 @@
+//! An in-process fake BMC answering RMCP+ on a local UDP port. Implements the BMC
+//! side of session establishment and the DCMI power commands, so the native lanplus
+//! client can be tested without hardware.
+
+use crate::bmc::lanplus::{
+    decode_packet, encode_packet, hmac_sha1, random_bytes, user_key, IpmiMessage, SessionKeys,
+    BMC_SLAVE_ADDRESS, CMD_CLOSE_SESSION, CMD_DCMI_ACTIVATE_POWER_LIMIT, CMD_DCMI_GET_CAPABILITIES, CMD_DCMI_GET_POWER_LIMIT,
+    CMD_DCMI_GET_POWER_READING, CMD_DCMI_SET_POWER_LIMIT, CMD_SET_SESSION_PRIVILEGE, COMPLETION_OK,
+    COMPLETION_INVALID_COMMAND, COMPLETION_NO_ACTIVE_POWER_LIMIT, DCMI_GROUP_ID, DCMI_SUPPORTED_CAPABILITIES, NETFN_APP, NETFN_GROUP_EXTENSION,
+    PAYLOAD_IPMI, PAYLOAD_OPEN_SESSION_REQUEST, PAYLOAD_OPEN_SESSION_RESPONSE, PAYLOAD_RAKP1,
+    PAYLOAD_RAKP2, PAYLOAD_RAKP3, PAYLOAD_RAKP4, REMOTE_CONSOLE_SOFTWARE_ID,
+};
+use crate::bmc::enhanced_power::DCMI_ENHANCED_POWER_ATTRIBUTES;
+use chrono::Utc;
+use log::trace;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const COMPLETION_INVALID_DATA: u8 = 0xcc;
+const RMCP_STATUS_UNAUTHORIZED_NAME: u8 = 0x0d;
+const RMCP_STATUS_INVALID_SESSION_ID: u8 = 0x02;
+
+/// The DCMI state of the fake node
+#[derive(Debug, Clone)]
+pub struct FakeDcmiState {
+    /// Power drawn by the node when no cap is active
+    pub uncapped_watts: u16,
+    pub power_limit: u16,
+    pub is_active: bool,
+    pub exception_action: u8,
+    pub correction_time_millis: u32,
+    pub sampling_period_secs: u16,
+}
+
+impl FakeDcmiState {
+    fn power(&self) -> u16 {
+        if self.is_active { self.uncapped_watts.min(self.power_limit) } else { self.uncapped_watts }
+    }
+}
+
+/// Per-session handshake state, keyed on the BMC session ID
+struct FakeSession {
+    console_session_id: u32,
+    console_random: [u8; 16],
+    bmc_random: [u8; 16],
+    role: u8,
+    username: String,
+    keys: Option<SessionKeys>,
+}
+
+struct FakeBMC {
+    username: String,
+    password: String,
+    guid: [u8; 16],
+    sessions: HashMap<u32, FakeSession>,
+    sessions_opened: usize,
+    state: FakeDcmiState,
+}
+
+/// Handle on a fake BMC running on its own thread. The thread stops when the handle is dropped.
+pub struct FakeLanplusBMC {
+    pub address: SocketAddr,
+    bmc: Arc<Mutex<FakeBMC>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FakeLanplusBMC {
+    /// Starts a fake BMC on an ephemeral localhost port, accepting one user
+    ///
+    /// # Panics
+    /// If the UDP socket can't be created
+    #[must_use]
+    pub fn spawn(username: &str, password: &str, uncapped_watts: u16) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind fake BMC socket");
+        socket.set_read_timeout(Some(Duration::from_millis(50))).expect("Failed to set fake BMC timeout");
+        let address = socket.local_addr().expect("Failed to get fake BMC address");
+
+        let bmc = Arc::new(Mutex::new(FakeBMC {
+            username: String::from(username),
+            password: String::from(password),
+            guid: random_bytes().expect("Failed to read /dev/urandom"),
+            sessions: HashMap::new(),
+            sessions_opened: 0,
+            state: FakeDcmiState {
+                uncapped_watts,
+                power_limit: 0,
+                is_active: false,
+                exception_action: 0x01,
+                correction_time_millis: 1000,
+                sampling_period_secs: 5,
+            },
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (thread_bmc, thread_stop) = (bmc.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            while !thread_stop.load(Ordering::Relaxed) {
+                if let Ok((len, peer)) = socket.recv_from(&mut buffer) {
+                    let response = thread_bmc.lock().expect("fake BMC poisoned").handle(&buffer[..len]);
+                    if let Some(response) = response {
+                        let _ = socket.send_to(&response, peer);
+                    }
+                }
+            }
+        });
+
+        Self { address, bmc, stop }
+    }
+
+    #[must_use]
+    pub fn sessions_opened(&self) -> usize {
+        self.bmc.lock().expect("fake BMC poisoned").sessions_opened
+    }
+
+    #[must_use]
+    pub fn state(&self) -> FakeDcmiState {
+        self.bmc.lock().expect("fake BMC poisoned").state.clone()
+    }
+}
+
+impl Drop for FakeLanplusBMC {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl FakeBMC {
+    fn handle(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        // The session ID is needed to pick the keys before the packet can be checked
+        let session_id = u32::from_le_bytes(packet.get(6..10)?.try_into().ok()?);
+        let keys = self.sessions.get(&session_id).and_then(|session| session.keys.clone());
+        let request = decode_packet(packet, keys.as_ref()).ok()?;
+        trace!("fake BMC: payload {:#04x} session {session_id:#010x}", request.payload_type);
+
+        match request.payload_type {
+            PAYLOAD_OPEN_SESSION_REQUEST => self.open_session(&request.payload).ok(),
+            PAYLOAD_RAKP1 => self.rakp1(&request.payload).ok(),
+            PAYLOAD_RAKP3 => self.rakp3(&request.payload).ok(),
+            PAYLOAD_IPMI => {
+                let keys = keys?;
+                let message = IpmiMessage::decode(&request.payload).ok()?;
+                let console_session_id = self.sessions.get(&session_id)?.console_session_id;
+                let data = self.command(session_id, &message);
+                let response = IpmiMessage { netfn: message.netfn | 1, cmd: message.cmd, sequence: message.sequence, data };
+                let payload = response.encode(REMOTE_CONSOLE_SOFTWARE_ID, BMC_SLAVE_ADDRESS);
+                encode_packet(PAYLOAD_IPMI, console_session_id, request.sequence, &payload, Some(&keys)).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn open_session(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        let tag = request.first().copied().unwrap_or(0);
+        let console_session_id = u32::from_le_bytes(request.get(4..8).and_then(|id| id.try_into().ok()).unwrap_or_default());
+        let bmc_session_id = u32::from_le_bytes(random_bytes()?) | 1;
+        self.sessions.insert(bmc_session_id, FakeSession {
+            console_session_id,
+            console_random: [0; 16],
+            bmc_random: random_bytes()?,
+            role: 0,
+            username: String::new(),
+            keys: None,
+        });
+
+        let mut response = vec![tag, 0x00, 0x04, 0x00];
+        response.extend_from_slice(&console_session_id.to_le_bytes());
+        response.extend_from_slice(&bmc_session_id.to_le_bytes());
+        response.extend_from_slice(&request[8..request.len().min(32)]);
+        encode_packet(PAYLOAD_OPEN_SESSION_RESPONSE, 0, 0, &response, None)
+    }
+
+    fn rakp1(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        let tag = request.first().copied().unwrap_or(0);
+        let bmc_session_id = u32::from_le_bytes(request[4..8].try_into().expect("4 byte slice"));
+        let role = request[24];
+        let username_len = usize::from(request[27]);
+        let username = String::from_utf8_lossy(&request[28..28 + username_len]).to_string();
+
+        let Some(session) = self.sessions.get_mut(&bmc_session_id) else {
+            return encode_packet(PAYLOAD_RAKP2, 0, 0, &[tag, RMCP_STATUS_INVALID_SESSION_ID, 0, 0, 0, 0, 0, 0], None);
+        };
+        let mut response = vec![tag, 0x00, 0x00, 0x00];
+        response.extend_from_slice(&session.console_session_id.to_le_bytes());
+        if username != self.username {
+            response[1] = RMCP_STATUS_UNAUTHORIZED_NAME;
+            return encode_packet(PAYLOAD_RAKP2, 0, 0, &response, None);
+        }
+
+        session.console_random.copy_from_slice(&request[8..24]);
+        session.role = role;
+        session.username = username;
+        let auth_code = hmac_sha1(&user_key(&self.password), &[
+            &session.console_session_id.to_le_bytes(),
+            &bmc_session_id.to_le_bytes(),
+            &session.console_random,
+            &session.bmc_random,
+            &self.guid,
+            &[role, username_len as u8],
+            session.username.as_bytes(),
+        ]);
+        response.extend_from_slice(&session.bmc_random);
+        response.extend_from_slice(&self.guid);
+        response.extend_from_slice(&auth_code);
+        encode_packet(PAYLOAD_RAKP2, 0, 0, &response, None)
+    }
+
+    fn rakp3(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        let tag = request.first().copied().unwrap_or(0);
+        let bmc_session_id = u32::from_le_bytes(request[4..8].try_into().expect("4 byte slice"));
+        let Some(session) = self.sessions.get_mut(&bmc_session_id) else {
+            return encode_packet(PAYLOAD_RAKP4, 0, 0, &[tag, RMCP_STATUS_INVALID_SESSION_ID, 0, 0, 0, 0, 0, 0], None);
+        };
+
+        let expected = hmac_sha1(&user_key(&self.password), &[
+            &session.bmc_random,
+            &session.console_session_id.to_le_bytes(),
+            &[session.role, session.username.len() as u8],
+            session.username.as_bytes(),
+        ]);
+        let mut response = vec![tag, 0x00, 0x00, 0x00];
+        response.extend_from_slice(&session.console_session_id.to_le_bytes());
+        if request.get(8..28) != Some(&expected[..]) {
+            response[1] = RMCP_STATUS_UNAUTHORIZED_NAME;
+            return encode_packet(PAYLOAD_RAKP4, 0, 0, &response, None);
+        }
+
+        let keys = SessionKeys::derive(&self.password, &session.console_random, &session.bmc_random, &session.username);
+        let integrity = hmac_sha1(&keys.sik, &[&session.console_random, &bmc_session_id.to_le_bytes(), &self.guid]);
+        response.extend_from_slice(&integrity[..12]);
+        session.keys = Some(keys);
+        self.sessions_opened += 1;
+        encode_packet(PAYLOAD_RAKP4, 0, 0, &response, None)
+    }
+
+    /// Runs an IPMI command, returning the completion code followed by the response data
+    fn command(&mut self, session_id: u32, message: &IpmiMessage) -> Vec<u8> {
+        let state = &mut self.state;
+        let data = &message.data;
+        match (message.netfn, message.cmd) {
+            (NETFN_APP, CMD_SET_SESSION_PRIVILEGE) => vec![COMPLETION_OK, data.first().copied().unwrap_or(0x04)],
+            (NETFN_APP, CMD_CLOSE_SESSION) => {
+                self.sessions.remove(&session_id);
+                vec![COMPLETION_OK]
+            }
+            (NETFN_GROUP_EXTENSION, _) if data.first() != Some(&DCMI_GROUP_ID) => vec![COMPLETION_INVALID_DATA],
+            // DCMI 1.5, parameter revision 2, power management supported
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_GET_CAPABILITIES) if data.get(1) == Some(&DCMI_SUPPORTED_CAPABILITIES) => {
+                vec![COMPLETION_OK, DCMI_GROUP_ID, 0x01, 0x05, 0x02, 0x00, 0x01, 0x00]
+            }
+            // Rolling averages over 30s and 1min
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_GET_CAPABILITIES) if data.get(1) == Some(&DCMI_ENHANCED_POWER_ATTRIBUTES) => {
+                vec![COMPLETION_OK, DCMI_GROUP_ID, 0x01, 0x05, 0x02, 0x02, 0x1e, 0x41]
+            }
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_GET_POWER_READING) => {
+                let power = state.power().to_le_bytes();
+                let mut response = vec![COMPLETION_OK, DCMI_GROUP_ID];
+                for _ in 0..4 {
+                    response.extend_from_slice(&power);
+                }
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                response.extend_from_slice(&(Utc::now().timestamp() as u32).to_le_bytes());
+                response.extend_from_slice(&(u32::from(state.sampling_period_secs) * 1000).to_le_bytes());
+                response.push(0x40);
+                response
+            }
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_GET_POWER_LIMIT) => {
+                let completion = if state.is_active { COMPLETION_OK } else { COMPLETION_NO_ACTIVE_POWER_LIMIT };
+                let mut response = vec![completion, DCMI_GROUP_ID, 0x00, 0x00, state.exception_action];
+                response.extend_from_slice(&state.power_limit.to_le_bytes());
+                response.extend_from_slice(&state.correction_time_millis.to_le_bytes());
+                response.extend_from_slice(&[0x00, 0x00]);
+                response.extend_from_slice(&state.sampling_period_secs.to_le_bytes());
+                response
+            }
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_SET_POWER_LIMIT) if data.len() >= 15 => {
+                state.exception_action = data[4];
+                state.power_limit = u16::from_le_bytes([data[5], data[6]]);
+                state.correction_time_millis = u32::from_le_bytes(data[7..11].try_into().expect("4 byte slice"));
+                state.sampling_period_secs = u16::from_le_bytes([data[13], data[14]]);
+                vec![COMPLETION_OK, DCMI_GROUP_ID]
+            }
+            (NETFN_GROUP_EXTENSION, CMD_DCMI_ACTIVATE_POWER_LIMIT) if data.len() >= 2 => {
+                state.is_active = data[1] == 0x01;
+                vec![COMPLETION_OK, DCMI_GROUP_ID]
+            }
+            _ => vec![COMPLETION_INVALID_COMMAND],
+        }
+    }
+}
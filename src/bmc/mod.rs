@@ -1,7 +1,29 @@
 #[allow(clippy::module_inception)]
 pub mod bmc;
+pub mod agent_proxy;
+pub mod capabilities;
+pub mod controller;
+pub mod credentials;
+pub mod enhanced_power;
+pub mod error;
+pub mod interlock;
+pub mod ipmi_shell;
+pub mod lanplus;
+#[cfg(test)]
+pub mod lanplus_fake;
 pub mod monitor_bmc;
-use crate::bmc::bmc::BMC_CapSetting;
+pub mod node_manager;
+pub mod rapl_cap;
+pub mod redfish;
+pub mod redfish_mock;
+pub mod sel;
+pub mod sensors;
+pub mod simulated;
+pub mod snapshot;
+pub mod vendor;
+pub mod verify;
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading};
+use crate::bmc::enhanced_power::RollingAverage;
 
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
@@ -9,19 +31,86 @@ use chrono::{DateTime, Utc};
 #[allow(non_camel_case_types)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BMCStats {
+    /// Host time of the power reading
     pub timestamp: DateTime<Utc>,
     pub power: u64,
+    pub minimum: u64,
+    pub maximum: u64,
+    pub average: u64,
+    /// BMC time of the power reading
+    pub bmc_timestamp: Option<DateTime<Utc>>,
+    /// BMC clock minus host clock, estimated from this reading
+    pub clock_offset_millis: Option<i64>,
+    pub sampling_period_millis: Option<u64>,
+    pub reading_is_active: Option<bool>,
     pub cap_level: u64,
     pub cap_is_active: bool,
+    /// The BMC's statistics over its enhanced rolling average periods, when it has any
+    #[serde(default)]
+    pub rolling_averages: Vec<RollingAverage>,
 }
 
 impl BMCStats {
-    pub fn new(power: u64, cap_settings: &BMC_CapSetting) -> Self {
+    /// `timestamp` is the host time the reading was taken at, ideally half way through the request
+    pub fn new(timestamp: DateTime<Utc>, reading: &BMC_PowerReading, cap_settings: &BMC_CapSetting) -> Self {
         Self {
-            timestamp: Utc::now(),
-            power,
+            timestamp,
+            power: reading.instant,
+            minimum: reading.minimum,
+            maximum: reading.maximum,
+            average: reading.average,
+            bmc_timestamp: reading.timestamp,
+            clock_offset_millis: reading.timestamp.map(|bmc_timestamp| clock_offset(bmc_timestamp, timestamp).num_milliseconds()),
+            sampling_period_millis: reading.sampling_period.map(|period| u64::try_from(period.as_millis()).unwrap_or(u64::MAX)),
+            reading_is_active: reading.reading_is_active,
             cap_level: cap_settings.power_limit,
             cap_is_active: cap_settings.is_active,
+            rolling_averages: Vec::new(),
         }
     }
 }
+
+/// The BMC timestamp is truncated to the second, so on average it's half a second
+/// behind the moment the reading was taken.
+fn clock_offset(bmc_timestamp: DateTime<Utc>, host_timestamp: DateTime<Utc>) -> chrono::Duration {
+    bmc_timestamp + chrono::Duration::milliseconds(500) - host_timestamp
+}
+
+/// Estimates how far the BMC clock is ahead of the host clock over a series of readings,
+/// so that BMC and RAPL series can be put on the same time base. Each reading is only good
+/// to a second, the median of the series is much better.
+#[must_use]
+pub fn estimate_clock_offset(stats: &[BMCStats]) -> Option<chrono::Duration> {
+    let mut offsets: Vec<i64> = stats.iter().filter_map(|s| s.clock_offset_millis).collect();
+    if offsets.is_empty() {
+        return None;
+    }
+    offsets.sort_unstable();
+    Some(chrono::Duration::milliseconds(offsets[offsets.len() / 2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_clock_offset() {
+        let host = Utc.with_ymd_and_hms(2023, 5, 9, 14, 24, 36).unwrap();
+        let cap_settings = BMC_CapSetting::default();
+        let stats: Vec<BMCStats> = [1800, 2200, 2000, 9000]
+            .iter()
+            .map(|millis| {
+                // a BMC 2s ahead, read at various points within the second
+                let timestamp = host + chrono::Duration::milliseconds(*millis);
+                let bmc_timestamp = Utc.timestamp_opt((timestamp + chrono::Duration::seconds(2)).timestamp(), 0).unwrap();
+                let reading = BMC_PowerReading { timestamp: Some(bmc_timestamp), ..BMC_PowerReading::from_instant(300) };
+                BMCStats::new(timestamp, &reading, &cap_settings)
+            })
+            .collect();
+
+        let offset = estimate_clock_offset(&stats).unwrap();
+        assert!((offset.num_milliseconds() - 2000).abs() <= 500, "{offset}");
+        assert_eq!(estimate_clock_offset(&[]), None);
+    }
+}
@@ -1,49 +1,280 @@
-use crate::bmc::bmc::BMC;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::enhanced_power::RollingAverage;
 use crate::bmc::BMCStats;
-use log::{info, trace};
-use std::sync::mpsc::Receiver;
-use std::thread;
-use std::time::Duration;
-use crate::CONFIGURATION;
-
-const BMC_INTER_COMMAND_SLEEP_MILLIS: u64 = 500;
-const BMC_POLL_INTERVAL_MILLIS: u64 = 500;
-
-
-/// Periodically polls the BMC for power reading and saves the result. Runs on its own thread.
-/// Each time through the loop, checks for a message from the main monitor thread that signals
-/// that this thread can exit.
-pub fn monitor_bmc(rx: Receiver<()>) -> Vec::<BMCStats> {
-    info!("\tBMC: launched");
-
-    let mut stats = Vec::<BMCStats>::new();
-    let bmc = BMC::new(
-        &CONFIGURATION.bmc_hostname,
-        &CONFIGURATION.bmc_username,
-        &CONFIGURATION.bmc_password,
-        &CONFIGURATION.ipmi
-    );
+use crate::bmc::error::BMCError;
+use crate::bmc::sensors::SensorRecord;
+use chrono::Utc;
+use futures_util::future::join_all;
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How regularly a monitor managed to sample
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitorTicks {
+    /// The requested sample period
+    pub period_millis: u64,
+    /// Ticks skipped because a read was still running when they were due. The samples
+    /// stay on the period grid, so a missed tick shows up as a gap rather than a drift.
+    pub missed: u64,
+}
+
+/// What a monitor collected before it was cancelled
+#[derive(Debug)]
+pub struct Monitored<T> {
+    pub samples: Vec<T>,
+    pub ticks: MonitorTicks,
+}
+
+/// The outcome of one read of a monitor
+enum Sample<T> {
+    Taken(T),
+    /// The read failed, losing this sample but not the run
+    Skipped,
+    /// The capping mechanism can't provide this reading at all
+    Unavailable,
+}
+
+/// Reads once per `period` until `cancel` fires. A read that overruns its period
+/// makes the following ticks be skipped and counted, instead of bunching the next reads up.
+async fn poll<T, F, Fut>(name: &str, period: Duration, cancel: CancellationToken, mut read: F) -> Monitored<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Sample<T>>,
+{
+    info!("\t{name}: launched, sampling every {}ms", period.as_millis());
+
+    let mut ticks = MonitorTicks { period_millis: u64::try_from(period.as_millis()).unwrap_or(u64::MAX), missed: 0 };
+    let mut samples = Vec::new();
+    let mut next_tick = Instant::now();
 
     loop {
-        // Check if monitor master asked us to exit with a message on the channel
-        if rx.try_recv().is_ok() {
-            trace!("\tBMC: got message - exiting");
-            break;
+        tokio::select! {
+            biased;
+            () = cancel.cancelled() => {
+                trace!("\t{name}: cancelled - exiting");
+                break;
+            }
+            () = time::sleep_until(next_tick) => {}
         }
 
-        // No message, read current power and capping status
-        let current_power = bmc.current_power();
-        thread::sleep(Duration::from_millis(BMC_INTER_COMMAND_SLEEP_MILLIS));
-        let current_cap_settings = bmc.current_cap_settings();
-        let reading = BMCStats::new(current_power, &current_cap_settings);
+        match read().await {
+            Sample::Taken(sample) => samples.push(sample),
+            Sample::Skipped => {}
+            Sample::Unavailable => break,
+        }
 
-        trace!("BMC power reading: {reading:#?}");
-        stats.push(reading);
+        // Stay on the period grid: ticks that went by during the read are skipped and counted
+        next_tick += period;
+        let now = Instant::now();
+        if now > next_tick {
+            let missed = u32::try_from((now - next_tick).as_nanos() / period.as_nanos()).unwrap_or(u32::MAX) + 1;
+            warn!("\t{name}: read overran the {}ms period, missed {missed} ticks", period.as_millis());
+            ticks.missed += u64::from(missed);
+            next_tick += period * missed;
+        }
+    }
 
-        thread::sleep(Duration::from_millis(BMC_POLL_INTERVAL_MILLIS));
+    info!("\t{name}: Exiting");
+    Monitored { samples, ticks }
+}
+
+/// Polls the BMC for power reading and cap settings every `period`, until cancelled.
+/// Power, cap settings and the enhanced rolling averages are read in parallel, so one
+/// sample takes as long as the slowest of the reads; `period` should be longer than that.
+pub async fn monitor_bmc(controller: Arc<dyn PowerCapController>, period: Duration, cancel: CancellationToken) -> Monitored<BMCStats> {
+    let rolling_periods: Arc<[Duration]> = enhanced_power_periods(controller.as_ref()).await.into();
+    poll("BMC", period, cancel, || {
+        let controller = controller.clone();
+        let rolling_periods = rolling_periods.clone();
+        async move {
+            let before = Utc::now();
+            let (current_power, current_cap_settings, rolling_averages) = tokio::join!(
+                async {
+                    let reading = controller.current_power_reading().await;
+                    (reading, before + (Utc::now() - before) / 2)
+                },
+                controller.current_cap_settings(),
+                read_rolling_averages(controller.as_ref(), &rolling_periods),
+            );
+            match (current_power, current_cap_settings) {
+                ((Ok(current_power), timestamp), Ok(current_cap_settings)) => {
+                    let reading = BMCStats { rolling_averages, ..BMCStats::new(timestamp, &current_power, &current_cap_settings) };
+                    trace!("BMC power reading: {reading:#?}");
+                    Sample::Taken(reading)
+                }
+                ((Err(e), _), _) | (_, Err(e)) => {
+                    warn!("\tBMC: skipping sample: {e}");
+                    Sample::Skipped
+                }
+            }
+        }
+    })
+    .await
+}
+
+/// The rolling average periods to read along with each power reading, none if the
+/// BMC has no enhanced power statistics
+async fn enhanced_power_periods(controller: &dyn PowerCapController) -> Vec<Duration> {
+    match controller.enhanced_power_periods().await {
+        Ok(periods) => {
+            info!("\tBMC: enhanced power statistics over {periods:?}");
+            periods
+        }
+        Err(BMCError::Unsupported(e)) => {
+            info!("\tBMC: {e}");
+            Vec::new()
+        }
+        Err(e) => {
+            warn!("\tBMC: enhanced power statistics left out: {e}");
+            Vec::new()
+        }
     }
+}
 
+/// The periods are read in parallel, so a BMC with several doesn't take several times
+/// as long. One period failing leaves it out of this sample only.
+async fn read_rolling_averages(controller: &dyn PowerCapController, periods: &[Duration]) -> Vec<RollingAverage> {
+    let readings = join_all(periods.iter().map(|period| controller.enhanced_power_reading(*period))).await;
+    periods
+        .iter()
+        .zip(readings)
+        .filter_map(|(period, reading)| match reading {
+            Ok(reading) => Some(RollingAverage::new(*period, &reading)),
+            Err(e) => {
+                warn!("\tBMC: skipping the {}s rolling average: {e}", period.as_secs());
+                None
+            }
+        })
+        .collect()
+}
 
-    info!("\tBMC: Exiting");
-    stats
+/// Polls the BMC sensors (PSU power, temperatures, fans) on its own task, alongside
+/// `monitor_bmc`, so a slow SDR read doesn't hold back the power readings.
+/// Gives up straight away if the capping mechanism has no sensors.
+pub async fn monitor_sensors(controller: Arc<dyn PowerCapController>, period: Duration, cancel: CancellationToken) -> Monitored<SensorRecord> {
+    poll("Sensors", period, cancel, || {
+        let controller = controller.clone();
+        async move {
+            let before = Utc::now();
+            match controller.sensor_readings().await {
+                Ok(readings) => {
+                    let timestamp = before + (Utc::now() - before) / 2;
+                    trace!("Sensor readings: {readings:#?}");
+                    Sample::Taken(SensorRecord { timestamp, readings })
+                }
+                Err(BMCError::Unsupported(e)) => {
+                    info!("\tSensors: {e}");
+                    Sample::Unavailable
+                }
+                Err(e) => {
+                    warn!("\tSensors: skipping sample: {e}");
+                    Sample::Skipped
+                }
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::bmc::BMC_CapSetting;
+    use crate::bmc::simulated::SimulatedBMC;
+    use crate::test_util::StubController;
+    use tokio::time::sleep;
+
+    /// Reports 321W under an active 400W cap
+    fn fixed() -> StubController {
+        StubController::new(321).with_cap_settings(BMC_CapSetting { is_active: true, power_limit: 400, ..BMC_CapSetting::default() })
+    }
+
+    const PERIOD: Duration = Duration::from_millis(100);
+
+    async fn run_for<T>(monitor: impl Future<Output = Monitored<T>> + Send + 'static, cancel: &CancellationToken, millis: u64) -> Monitored<T>
+    where
+        T: Send + 'static,
+    {
+        let monitor = tokio::spawn(monitor);
+        sleep(Duration::from_millis(millis)).await;
+        cancel.cancel();
+        monitor.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_monitor_any_controller() {
+        let cancel = CancellationToken::new();
+        let stats = run_for(monitor_bmc(Arc::new(fixed()), PERIOD, cancel.clone()), &cancel, 550).await;
+        assert!(stats.samples.len() >= 5);
+        assert!(stats.samples.iter().all(|s| s.power == 321 && s.cap_level == 400 && s.cap_is_active));
+        assert!(stats.samples.iter().all(|s| s.rolling_averages.is_empty()));
+        assert_eq!(stats.ticks, MonitorTicks { period_millis: 100, missed: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_monitor_rolling_averages() {
+        let cancel = CancellationToken::new();
+        // the 1min average can't be read
+        let periods = [Duration::from_secs(30), Duration::from_secs(60)];
+        let controller = StubController::new(321).with_rolling_averages(&periods, Some(periods[1]));
+        let stats = run_for(monitor_bmc(Arc::new(controller), PERIOD, cancel.clone()), &cancel, 250).await;
+        assert!(stats.samples.len() >= 2);
+        let expected = RollingAverage { period_secs: 30, average: 300, minimum: 250, maximum: 350 };
+        assert!(stats.samples.iter().all(|s| s.rolling_averages == [expected]));
+    }
+
+    #[tokio::test]
+    async fn test_rolling_averages_read_in_parallel() {
+        let periods: Vec<Duration> = [15, 30, 60, 300].into_iter().map(Duration::from_secs).collect();
+        let start = Instant::now();
+        let controller = StubController::new(321).with_read_delay(Duration::from_millis(100));
+        let rolling_averages = read_rolling_averages(&controller, &periods).await;
+        assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+        let read_periods: Vec<u64> = rolling_averages.iter().map(|average| average.period_secs).collect();
+        assert_eq!(read_periods, [15, 30, 60, 300]);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_sensors() {
+        let cancel = CancellationToken::new();
+        let records = run_for(monitor_sensors(Arc::new(SimulatedBMC::default()), PERIOD, cancel.clone()), &cancel, 250).await;
+        assert!(records.samples.len() >= 2);
+        assert!(records.samples.iter().all(|r| r.readings.iter().any(|s| s.name == "psu1_input_watts")));
+
+        // no sensors, no waiting
+        let records = monitor_sensors(Arc::new(fixed()), PERIOD, CancellationToken::new()).await;
+        assert!(records.samples.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_monitor_skips_failed_reads() {
+        let controller = Arc::new(StubController::new(321).flaky());
+        let cancel = CancellationToken::new();
+        let stats = run_for(monitor_bmc(controller.clone(), PERIOD, cancel.clone()), &cancel, 550).await;
+
+        let reads = controller.power_reads();
+        assert!(reads >= 2);
+        assert_eq!(stats.samples.len() as u64, reads / 2);
+        assert!(stats.samples.iter().all(|s| s.power == 321));
+    }
+
+    #[tokio::test]
+    async fn test_missed_ticks_are_counted() {
+        let cancel = CancellationToken::new();
+        // each read takes longer than the monitor period allows
+        let controller = StubController::new(321).with_read_delay(Duration::from_millis(250));
+        let stats = run_for(monitor_bmc(Arc::new(controller), PERIOD, cancel.clone()), &cancel, 1000).await;
+
+        // each 250ms read runs over the next two ticks, the last one included
+        assert!(stats.samples.len() >= 3);
+        assert_eq!(stats.ticks.missed, 2 * stats.samples.len() as u64);
+        for pair in stats.samples.windows(2) {
+            let gap = (pair[1].timestamp - pair[0].timestamp).num_milliseconds();
+            assert!((250..350).contains(&gap), "{gap}ms between samples");
+        }
+    }
 }
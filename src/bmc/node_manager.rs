@@ -0,0 +1,650 @@
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading, ExceptionAction};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::bmc::sel::SelEntry;
+use crate::bmc::sensors::SensorReading;
+use crate::model::FirestarterParams;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Node Manager runs on the Management Engine, reached from the BMC through IPMB
+pub const NM_CHANNEL: u8 = 0x06;
+pub const NM_TARGET_ADDRESS: u8 = 0x2c;
+/// OEM/Group net function, followed by Intel's manufacturer ID in every request and response
+pub const NM_NETFN: u8 = 0x2e;
+const INTEL_MANUFACTURER_ID: [u8; 3] = [0x57, 0x01, 0x00];
+
+pub const NM_CMD_POLICY_CONTROL: u8 = 0xc0;
+pub const NM_CMD_SET_POLICY: u8 = 0xc1;
+pub const NM_CMD_GET_POLICY: u8 = 0xc2;
+pub const NM_CMD_GET_STATISTICS: u8 = 0xc8;
+
+const NM_CONTROL_DISABLE_POLICY: u8 = 0x04;
+const NM_CONTROL_ENABLE_POLICY: u8 = 0x05;
+const NM_STATISTICS_GLOBAL_POWER: u8 = 0x01;
+const NM_STATISTICS_POLICY_POWER: u8 = 0x11;
+
+const NM_POLICY_ENABLED: u8 = 0x10;
+const NM_POLICY_ADD: u8 = 0x10;
+/// Volatile policies are gone after a reset, so an interrupted campaign leaves nothing behind
+const NM_POLICY_VOLATILE: u8 = 0x80;
+const NM_EXCEPTION_ALERT: u8 = 0x01;
+const NM_EXCEPTION_SHUTDOWN: u8 = 0x02;
+const NM_STATISTICS_POLICY_ACTIVE: u8 = 0x80;
+const NM_STATISTICS_MEASURING: u8 = 0x40;
+
+/// The policy the campaign creates and removes in each domain
+pub const NM_CAMPAIGN_POLICY_ID: u8 = 0x10;
+/// Completion code of a request naming a policy that doesn't exist
+const NM_COMPLETION_INVALID_POLICY_ID: u8 = 0x80;
+const NM_DEFAULT_STATISTICS_PERIOD_SECS: u16 = 1;
+
+/// The part of the node a Node Manager policy limits
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NmDomain {
+    /// The whole platform, as DCMI
+    Platform,
+    Cpu,
+    Memory,
+}
+
+impl NmDomain {
+    #[must_use]
+    pub fn id(self) -> u8 {
+        match self {
+            NmDomain::Platform => 0x00,
+            NmDomain::Cpu => 0x01,
+            NmDomain::Memory => 0x02,
+        }
+    }
+
+    #[must_use]
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x00 => Some(NmDomain::Platform),
+            0x01 => Some(NmDomain::Cpu),
+            0x02 => Some(NmDomain::Memory),
+            _ => None,
+        }
+    }
+
+    /// The domain as `ipmitool nm` names it
+    #[must_use]
+    pub fn ipmitool_name(self) -> &'static str {
+        match self {
+            NmDomain::Platform => "platform",
+            NmDomain::Cpu => "CPU",
+            NmDomain::Memory => "Memory",
+        }
+    }
+}
+
+/// What makes a policy start limiting. The trigger limit is in the trigger's own unit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NmTrigger {
+    /// Limits whenever the policy is enabled
+    Always,
+    /// Inlet temperature above the trigger limit, in Celsius
+    InletTemperature,
+    /// No power reading for the trigger limit, in tenths of a second
+    MissingPowerReading,
+    /// For the trigger limit seconds after a platform reset
+    TimeAfterReset,
+    /// While booting
+    BootTime,
+}
+
+impl NmTrigger {
+    #[must_use]
+    pub fn code(self) -> u8 {
+        match self {
+            NmTrigger::Always => 0x00,
+            NmTrigger::InletTemperature => 0x01,
+            NmTrigger::MissingPowerReading => 0x02,
+            NmTrigger::TimeAfterReset => 0x03,
+            NmTrigger::BootTime => 0x04,
+        }
+    }
+
+    #[must_use]
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(NmTrigger::Always),
+            0x01 => Some(NmTrigger::InletTemperature),
+            0x02 => Some(NmTrigger::MissingPowerReading),
+            0x03 => Some(NmTrigger::TimeAfterReset),
+            0x04 => Some(NmTrigger::BootTime),
+            _ => None,
+        }
+    }
+}
+
+/// A Node Manager power policy. Like a DCMI limit it has a correction time and an
+/// exception action, but it applies to one domain and can wait for a trigger.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NmPolicy {
+    pub domain: NmDomain,
+    pub policy_id: u8,
+    pub enabled: bool,
+    pub trigger: NmTrigger,
+    pub trigger_limit: u16,
+    pub power_limit_watts: u16,
+    pub correction_time: Duration,
+    /// Period the power is averaged over for the statistics
+    pub statistics_period_secs: u16,
+    pub exception_action: ExceptionAction,
+}
+
+impl NmPolicy {
+    /// A disabled, always triggered policy without a limit yet
+    #[must_use]
+    pub fn new(domain: NmDomain, policy_id: u8) -> Self {
+        Self {
+            domain,
+            policy_id,
+            enabled: false,
+            trigger: NmTrigger::Always,
+            trigger_limit: 0,
+            power_limit_watts: 0,
+            correction_time: Duration::from_secs(6),
+            statistics_period_secs: NM_DEFAULT_STATISTICS_PERIOD_SECS,
+            exception_action: ExceptionAction::NoAction,
+        }
+    }
+}
+
+impl From<&NmPolicy> for BMC_CapSetting {
+    fn from(policy: &NmPolicy) -> Self {
+        Self {
+            is_active: policy.enabled,
+            power_limit: u64::from(policy.power_limit_watts),
+            exception_action: Some(policy.exception_action),
+            correction_time: Some(policy.correction_time),
+            sampling_period: Some(Duration::from_secs(u64::from(policy.statistics_period_secs))),
+        }
+    }
+}
+
+/// Power statistics of a domain, or of one policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NmStatistics {
+    pub domain: NmDomain,
+    pub current_watts: u16,
+    pub minimum_watts: u16,
+    pub maximum_watts: u16,
+    pub average_watts: u16,
+    /// Management Engine time of the statistics, `None` when not known
+    pub timestamp: Option<DateTime<Utc>>,
+    pub reporting_period: Duration,
+    /// Whether the policy is limiting power right now
+    pub policy_active: bool,
+    pub measuring: bool,
+}
+
+/// Request for Set NM Policy (0xC1), which creates the policy or replaces it
+#[must_use]
+pub fn set_policy_request(policy: &NmPolicy) -> Vec<u8> {
+    let mut data = INTEL_MANUFACTURER_ID.to_vec();
+    data.push(policy.domain.id() | if policy.enabled { NM_POLICY_ENABLED } else { 0 });
+    data.push(policy.policy_id);
+    data.push(policy.trigger.code() | NM_POLICY_ADD | NM_POLICY_VOLATILE);
+    data.push(exception_code(policy.exception_action));
+    data.extend_from_slice(&policy.power_limit_watts.to_le_bytes());
+    let correction_millis = u32::try_from(policy.correction_time.as_millis()).unwrap_or(u32::MAX);
+    data.extend_from_slice(&correction_millis.to_le_bytes());
+    data.extend_from_slice(&policy.trigger_limit.to_le_bytes());
+    data.extend_from_slice(&policy.statistics_period_secs.to_le_bytes());
+    data
+}
+
+/// Request for Set NM Policy (0xC1) without the add flag, which removes the policy
+#[must_use]
+pub fn remove_policy_request(domain: NmDomain, policy_id: u8) -> Vec<u8> {
+    let mut data = INTEL_MANUFACTURER_ID.to_vec();
+    data.extend_from_slice(&[domain.id(), policy_id, NM_POLICY_VOLATILE]);
+    data.extend_from_slice(&[0; 11]);
+    data
+}
+
+/// Request for Enable/Disable NM Policy Control (0xC0) of one policy
+#[must_use]
+pub fn policy_control_request(domain: NmDomain, policy_id: u8, enable: bool) -> Vec<u8> {
+    let control = if enable { NM_CONTROL_ENABLE_POLICY } else { NM_CONTROL_DISABLE_POLICY };
+    let mut data = INTEL_MANUFACTURER_ID.to_vec();
+    data.extend_from_slice(&[control, domain.id(), policy_id]);
+    data
+}
+
+/// Request for Get NM Policy (0xC2)
+#[must_use]
+pub fn get_policy_request(domain: NmDomain, policy_id: u8) -> Vec<u8> {
+    let mut data = INTEL_MANUFACTURER_ID.to_vec();
+    data.extend_from_slice(&[domain.id(), policy_id]);
+    data
+}
+
+/// Request for Get NM Statistics (0xC8): the power of a policy, or of the whole domain
+/// without one
+#[must_use]
+pub fn statistics_request(domain: NmDomain, policy_id: Option<u8>) -> Vec<u8> {
+    let mode = if policy_id.is_some() { NM_STATISTICS_POLICY_POWER } else { NM_STATISTICS_GLOBAL_POWER };
+    let mut data = INTEL_MANUFACTURER_ID.to_vec();
+    data.extend_from_slice(&[mode, domain.id(), policy_id.unwrap_or(0)]);
+    data
+}
+
+/// Decodes a Get NM Policy response, completion code already removed
+///
+/// # Errors
+/// If the response is short, isn't from Intel's Node Manager or has an unknown domain or trigger
+pub fn parse_policy(policy_id: u8, data: &[u8]) -> BMCResult<NmPolicy> {
+    let data = strip_manufacturer_id(data, 13)?;
+    let domain = NmDomain::from_id(data[0] & 0x0f)
+        .ok_or_else(|| BMCError::Parse(format!("Node Manager domain {:#04x}", data[0] & 0x0f)))?;
+    let trigger = NmTrigger::from_code(data[1] & 0x0f)
+        .ok_or_else(|| BMCError::Parse(format!("Node Manager trigger {:#04x}", data[1] & 0x0f)))?;
+
+    Ok(NmPolicy {
+        domain,
+        policy_id,
+        enabled: data[0] & NM_POLICY_ENABLED != 0,
+        trigger,
+        exception_action: exception_action(data[2]),
+        power_limit_watts: u16_at(data, 3),
+        correction_time: Duration::from_millis(u64::from(u32_at(data, 5))),
+        trigger_limit: u16_at(data, 9),
+        statistics_period_secs: u16_at(data, 11),
+    })
+}
+
+/// Decodes a Get NM Statistics response, completion code already removed
+///
+/// # Errors
+/// If the response is short, isn't from Intel's Node Manager or has an unknown domain
+pub fn parse_statistics(data: &[u8]) -> BMCResult<NmStatistics> {
+    let data = strip_manufacturer_id(data, 17)?;
+    let state = data[16];
+    let domain = NmDomain::from_id(state & 0x0f)
+        .ok_or_else(|| BMCError::Parse(format!("Node Manager domain {:#04x}", state & 0x0f)))?;
+    // 0xFFFFFFFF when the Management Engine has no time
+    let timestamp = match u32_at(data, 8) {
+        u32::MAX => None,
+        seconds => Utc.timestamp_opt(i64::from(seconds), 0).single(),
+    };
+
+    Ok(NmStatistics {
+        domain,
+        current_watts: u16_at(data, 0),
+        minimum_watts: u16_at(data, 2),
+        maximum_watts: u16_at(data, 4),
+        average_watts: u16_at(data, 6),
+        timestamp,
+        reporting_period: Duration::from_secs(u64::from(u32_at(data, 12))),
+        policy_active: state & NM_STATISTICS_POLICY_ACTIVE != 0,
+        measuring: state & NM_STATISTICS_MEASURING != 0,
+    })
+}
+
+/// Parses the response bytes printed by `ipmitool raw`, eg ` 57 01 00 10 00`
+///
+/// # Errors
+/// If a word isn't a hex byte
+pub fn parse_raw_output(output: &str) -> BMCResult<Vec<u8>> {
+    output
+        .split_ascii_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| BMCError::Parse(format!("raw response byte '{byte}'"))))
+        .collect()
+}
+
+/// Whether Node Manager refused a request for a policy that doesn't exist. `ipmitool raw`
+/// only tells in its message, eg `Unable to send RAW command (... cmd=0xc1 rsp=0x80)`.
+#[must_use]
+pub fn is_policy_not_found(error: &BMCError) -> bool {
+    let completion = format!("rsp={NM_COMPLETION_INVALID_POLICY_ID:#04x}");
+    matches!(error, BMCError::ExitStatus { stderr, .. } if stderr.contains(&completion))
+}
+
+fn strip_manufacturer_id(data: &[u8], len: usize) -> BMCResult<&[u8]> {
+    let body = data
+        .strip_prefix(&INTEL_MANUFACTURER_ID)
+        .ok_or_else(|| BMCError::Parse(format!("not an Intel Node Manager response: {data:02x?}")))?;
+    if body.len() < len {
+        return Err(BMCError::Parse(format!("Node Manager response of {} bytes, expected {len}", body.len())));
+    }
+    Ok(body)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Node Manager can alert and shut down, there is no separate event logging
+fn exception_code(action: ExceptionAction) -> u8 {
+    match action {
+        ExceptionAction::NoAction => 0,
+        ExceptionAction::LogEvent => NM_EXCEPTION_ALERT,
+        ExceptionAction::HardPowerOff => NM_EXCEPTION_SHUTDOWN,
+    }
+}
+
+fn exception_action(code: u8) -> ExceptionAction {
+    if code & NM_EXCEPTION_SHUTDOWN != 0 {
+        ExceptionAction::HardPowerOff
+    } else if code & NM_EXCEPTION_ALERT != 0 {
+        ExceptionAction::LogEvent
+    } else {
+        ExceptionAction::NoAction
+    }
+}
+
+/// Caps one Node Manager domain through a policy of its own, so a campaign can drive
+/// NM caps with the same operations as DCMI caps. Power readings and everything that
+/// isn't the cap come from `inner`.
+///
+/// The policy is created by the first `set_cap_power_level`. Changes made before that
+/// are kept and sent with it.
+#[derive(Debug)]
+pub struct NmCapController {
+    inner: Arc<dyn PowerCapController>,
+    policy: Mutex<NmPolicy>,
+    created: tokio::sync::Mutex<bool>,
+}
+
+impl NmCapController {
+    #[must_use]
+    pub fn new(inner: Arc<dyn PowerCapController>, domain: NmDomain, policy_id: u8) -> Self {
+        Self { inner, policy: Mutex::new(NmPolicy::new(domain, policy_id)), created: tokio::sync::Mutex::new(false) }
+    }
+
+    fn policy(&self) -> NmPolicy {
+        *self.policy.lock().expect("NM policy poisoned")
+    }
+
+    /// Updates the policy, and sends it if it exists on the BMC. `created` is held throughout,
+    /// so concurrent first calls create the policy once and no change is left unsent.
+    async fn update(&self, change: impl FnOnce(&mut NmPolicy), create: bool) -> BMCResult<()> {
+        let mut created = self.created.lock().await;
+        let policy = {
+            let mut policy = self.policy.lock().expect("NM policy poisoned");
+            change(&mut policy);
+            *policy
+        };
+        if create || *created {
+            self.inner.nm_set_policy(&policy).await?;
+            *created = true;
+        }
+        Ok(())
+    }
+
+    /// The statistics of the policy
+    ///
+    /// # Errors
+    /// If the BMC can't report them
+    pub async fn statistics(&self) -> BMCResult<NmStatistics> {
+        let policy = self.policy();
+        self.inner.nm_statistics(policy.domain, Some(policy.policy_id)).await
+    }
+
+    /// Removes the policy from the BMC, if it was created
+    ///
+    /// # Errors
+    /// If the BMC fails to remove it
+    pub async fn remove_policy(&self) -> BMCResult<()> {
+        let mut created = self.created.lock().await;
+        if !std::mem::take(&mut *created) {
+            return Ok(());
+        }
+        let policy = self.policy();
+        self.inner.nm_remove_policy(policy.domain, policy.policy_id).await
+    }
+
+    async fn set_enabled(&self, enabled: bool) -> BMCResult<()> {
+        let policy = {
+            let mut policy = self.policy.lock().expect("NM policy poisoned");
+            policy.enabled = enabled;
+            *policy
+        };
+        self.inner.nm_set_policy_enabled(policy.domain, policy.policy_id, enabled).await
+    }
+}
+
+#[async_trait]
+impl PowerCapController for NmCapController {
+    async fn current_power(&self) -> BMCResult<u64> {
+        self.inner.current_power().await
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        self.inner.current_power_reading().await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        self.inner.enhanced_power_periods().await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        self.inner.enhanced_power_reading(period).await
+    }
+
+    /// The policy as the BMC reports it. Before it's created, the one that will be sent.
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let policy = self.policy();
+        if !*self.created.lock().await {
+            return Ok(BMC_CapSetting::from(&policy));
+        }
+        let reported = self.inner.nm_policy(policy.domain, policy.policy_id).await?;
+        Ok(BMC_CapSetting::from(&reported))
+    }
+
+    /// The limit range of the policy's domain
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        self.inner.nm_capabilities(self.policy().domain).await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        let watts = u16::try_from(cap).map_err(|_| BMCError::OutOfRange(format!("{cap}W Node Manager limit")))?;
+        self.update(|policy| policy.power_limit_watts = watts, true).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        self.set_enabled(true).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.set_enabled(false).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        self.update(|policy| policy.correction_time = correction_time, false).await
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        let secs = u16::try_from(sampling_period.as_secs()).unwrap_or(u16::MAX);
+        self.update(|policy| policy.statistics_period_secs = secs, false).await
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        self.update(|policy| policy.exception_action = action, false).await
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        self.inner.sensor_readings().await
+    }
+
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        self.inner.sel_entries().await
+    }
+
+    async fn set_workload(&self, params: &FirestarterParams) {
+        self.inner.set_workload(params).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::load_iterator::LoadTestSuite;
+    use crate::test::CapMechanism;
+
+    /// A Get NM Policy response, hand-built from the Node Manager spec rather than captured:
+    /// CPU domain, enabled, no trigger, alert on failure, 150W, 6s correction, 1s statistics
+    const GET_POLICY_OUTPUT: &str = " 57 01 00 71 10 01 96 00 70 17 00 00 00 00 01 00";
+    /// Get NM Statistics: 212W now, 180-260W, 230W average, policy limiting
+    const GET_STATISTICS_OUTPUT: &str = " 57 01 00 d4 00 b4 00 04 01 e6 00 80 4a 5a 64
+ 3c 00 00 00 f1";
+
+    /// Stands in for a BMC with Node Manager: one policy, no enforcement
+    #[derive(Debug, Default)]
+    struct FakeNodeManager {
+        policy: Mutex<Option<NmPolicy>>,
+    }
+
+    #[async_trait]
+    impl PowerCapController for FakeNodeManager {
+        async fn current_power(&self) -> BMCResult<u64> {
+            Ok(300)
+        }
+        async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+            Ok(BMC_CapSetting::default())
+        }
+        async fn set_cap_power_level(&self, _cap: u64) -> BMCResult<()> {
+            Ok(())
+        }
+        async fn activate_power_cap(&self) -> BMCResult<()> {
+            Ok(())
+        }
+        async fn deactivate_power_cap(&self) -> BMCResult<()> {
+            Ok(())
+        }
+        async fn nm_set_policy(&self, policy: &NmPolicy) -> BMCResult<()> {
+            *self.policy.lock().unwrap() = Some(*policy);
+            Ok(())
+        }
+        async fn nm_remove_policy(&self, _domain: NmDomain, _policy_id: u8) -> BMCResult<()> {
+            *self.policy.lock().unwrap() = None;
+            Ok(())
+        }
+        async fn nm_set_policy_enabled(&self, _domain: NmDomain, _policy_id: u8, enabled: bool) -> BMCResult<()> {
+            let mut policy = self.policy.lock().unwrap();
+            let policy = policy.as_mut().ok_or_else(|| BMCError::Parse(String::from("no policy")))?;
+            policy.enabled = enabled;
+            Ok(())
+        }
+        async fn nm_policy(&self, _domain: NmDomain, _policy_id: u8) -> BMCResult<NmPolicy> {
+            self.policy.lock().unwrap().ok_or_else(|| BMCError::Parse(String::from("no policy")))
+        }
+        async fn nm_capabilities(&self, domain: NmDomain) -> BMCResult<CapCapabilities> {
+            let (min, max) = match domain {
+                NmDomain::Platform => (200, 900),
+                NmDomain::Cpu => (100, 400),
+                NmDomain::Memory => (20, 80),
+            };
+            Ok(CapCapabilities { power_management: true, min_limit_watts: Some(min), max_limit_watts: Some(max), ..CapCapabilities::default() })
+        }
+    }
+
+    #[test]
+    fn test_policy_requests() {
+        let policy = NmPolicy {
+            enabled: true,
+            power_limit_watts: 150,
+            exception_action: ExceptionAction::LogEvent,
+            ..NmPolicy::new(NmDomain::Cpu, 0x10)
+        };
+        assert_eq!(
+            set_policy_request(&policy),
+            [0x57, 0x01, 0x00, 0x11, 0x10, 0x90, 0x01, 0x96, 0x00, 0x70, 0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(remove_policy_request(NmDomain::Cpu, 0x10).len(), set_policy_request(&policy).len());
+        assert_eq!(policy_control_request(NmDomain::Memory, 0x10, false), [0x57, 0x01, 0x00, 0x04, 0x02, 0x10]);
+        assert_eq!(statistics_request(NmDomain::Platform, None), [0x57, 0x01, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(statistics_request(NmDomain::Cpu, Some(0x10))[3], 0x11);
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = parse_policy(0x10, &parse_raw_output(GET_POLICY_OUTPUT).unwrap()).unwrap();
+        assert_eq!(policy.domain, NmDomain::Cpu);
+        assert!(policy.enabled);
+        assert_eq!(policy.trigger, NmTrigger::Always);
+        assert_eq!(policy.exception_action, ExceptionAction::LogEvent);
+        assert_eq!(policy.power_limit_watts, 150);
+        assert_eq!(policy.correction_time, Duration::from_secs(6));
+        assert_eq!(policy.statistics_period_secs, 1);
+
+        let settings = BMC_CapSetting::from(&policy);
+        assert!(settings.is_active);
+        assert_eq!(settings.power_limit, 150);
+    }
+
+    #[test]
+    fn test_parse_statistics() {
+        let statistics = parse_statistics(&parse_raw_output(GET_STATISTICS_OUTPUT).unwrap()).unwrap();
+        assert_eq!(statistics.domain, NmDomain::Cpu);
+        assert_eq!(
+            (statistics.current_watts, statistics.minimum_watts, statistics.maximum_watts, statistics.average_watts),
+            (212, 180, 260, 230)
+        );
+        assert_eq!(statistics.timestamp.unwrap().timestamp(), 0x645a_4a80);
+        assert_eq!(statistics.reporting_period, Duration::from_secs(60));
+        assert!(statistics.policy_active && statistics.measuring);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(parse_raw_output("57 01 zz"), Err(BMCError::Parse(_))));
+        assert!(matches!(parse_policy(0x10, &[0x57, 0x01, 0x00, 0x71]), Err(BMCError::Parse(_))));
+        assert!(matches!(parse_statistics(&[0x00; 23]), Err(BMCError::Parse(_))));
+
+        let stderr = "Unable to send RAW command (channel=0x6 netfn=0x2e lun=0x0 cmd=0xc1 rsp=0x80): Unknown (0x80)";
+        assert!(is_policy_not_found(&BMCError::from_ipmitool("raw", Some(1), stderr)));
+        let stderr = "Unable to send RAW command (channel=0x6 netfn=0x2e lun=0x0 cmd=0xc1 rsp=0xc1): Invalid command";
+        assert!(!is_policy_not_found(&BMCError::from_ipmitool("raw", Some(1), stderr)));
+    }
+
+    #[tokio::test]
+    async fn test_cap_controller_drives_a_policy() {
+        let node_manager = Arc::new(FakeNodeManager::default());
+        let controller = NmCapController::new(node_manager.clone(), NmDomain::Cpu, NM_CAMPAIGN_POLICY_ID);
+
+        // Kept until the policy is created
+        controller.set_correction_time(Duration::from_secs(10)).await.unwrap();
+        assert!(node_manager.policy.lock().unwrap().is_none());
+
+        controller.set_cap_power_level(150).await.unwrap();
+        controller.activate_power_cap().await.unwrap();
+        let settings = controller.current_cap_settings().await.unwrap();
+        assert!(settings.is_active);
+        assert_eq!(settings.power_limit, 150);
+        assert_eq!(settings.correction_time, Some(Duration::from_secs(10)));
+
+        controller.deactivate_power_cap().await.unwrap();
+        assert!(!controller.current_cap_settings().await.unwrap().is_active);
+
+        controller.remove_policy().await.unwrap();
+        assert!(node_manager.policy.lock().unwrap().is_none());
+        assert!(controller.set_cap_power_level(70_000).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_domain_cap_levels() {
+        let node_manager = Arc::new(FakeNodeManager::default());
+        let tests = |mechanism: CapMechanism| LoadTestSuite::new().filter(move |test| test.cap_mechanism == mechanism);
+        for mechanism in [CapMechanism::NmPlatform, CapMechanism::NmCpu, CapMechanism::NmMemory] {
+            let domain = mechanism.nm_domain().unwrap();
+            let capabilities = NmCapController::new(node_manager.clone(), domain, NM_CAMPAIGN_POLICY_ID).cap_capabilities().await.unwrap();
+            capabilities.check_plan(tests(mechanism)).unwrap();
+        }
+        // The platform levels are out of the memory domain's range
+        let memory = node_manager.nm_capabilities(NmDomain::Memory).await.unwrap();
+        assert!(matches!(memory.check_plan(tests(CapMechanism::Dcmi)), Err(BMCError::OutOfRange(_))));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::bmc::agent_proxy::AgentClient;
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::bmc::sel::SelEntry;
+use crate::bmc::sensors::SensorReading;
+use crate::model::{FirestarterParams, RaplLimitOperation};
+use crate::rapl::power_limit::{RaplConstraint, RaplConstraintLimit, RaplPowerLimits};
+use crate::rapl::rapl::RaplDomainType;
+use crate::route::{RAPL_LIMITS_PATH, RAPL_LIMIT_PATH, RAPL_RESTORE_PATH};
+use async_trait::async_trait;
+use reqwest::Method;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MICROWATTS_PER_WATT: u64 = 1_000_000;
+
+/// Caps the agent's host through the long term RAPL limits of its packages rather than
+/// the BMC, which is still read for the power. The cap is that of each package, not of
+/// the platform, and the long term time window stands in for the correction time.
+pub struct RaplCapController {
+    inner: Arc<dyn PowerCapController>,
+    agent: AgentClient,
+}
+
+impl RaplCapController {
+    /// `agent` is the agent's URL, eg: `http://oahu10000:8000`
+    #[must_use]
+    pub fn new(inner: Arc<dyn PowerCapController>, agent: &str) -> Self {
+        Self { inner, agent: AgentClient::new(agent) }
+    }
+
+    /// The limits of every RAPL domain of the host
+    ///
+    /// # Errors
+    /// If the agent can't read them
+    pub async fn power_limits(&self) -> BMCResult<Vec<RaplPowerLimits>> {
+        self.agent.get(RAPL_LIMITS_PATH).await
+    }
+
+    /// Puts back the limits the agent saved before the first change, returning whether
+    /// there were any
+    ///
+    /// # Errors
+    /// If the agent fails to write them
+    pub async fn restore(&self) -> BMCResult<bool> {
+        self.agent.request(Method::POST, RAPL_RESTORE_PATH, None::<()>).await
+    }
+
+    /// The limits of the packages, which the cap is applied to
+    async fn package_limits(&self) -> BMCResult<Vec<RaplPowerLimits>> {
+        let packages: Vec<RaplPowerLimits> = self.power_limits()
+            .await?
+            .into_iter()
+            .filter(|limits| limits.domain.domain_type == RaplDomainType::Package)
+            .collect();
+        if packages.is_empty() {
+            return Err(BMCError::Unsupported(format!("{self:?} has no package limits")));
+        }
+        Ok(packages)
+    }
+
+    /// The long term constraint of a package, which the cap is set on
+    fn long_term(limits: &RaplPowerLimits) -> Option<&RaplConstraintLimit> {
+        limits.constraints.iter().find(|limit| limit.constraint == RaplConstraint::LongTerm)
+    }
+
+    /// Makes the change `operation` returns for each package
+    async fn apply_to_packages(&self, operation: impl Fn(&RaplPowerLimits) -> RaplLimitOperation) -> BMCResult<()> {
+        for package in self.package_limits().await? {
+            self.agent.request::<(), _>(Method::POST, RAPL_LIMIT_PATH, Some(operation(&package))).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PowerCapController for RaplCapController {
+    async fn current_power(&self) -> BMCResult<u64> {
+        self.inner.current_power().await
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        self.inner.current_power_reading().await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        self.inner.enhanced_power_periods().await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        self.inner.enhanced_power_reading(period).await
+    }
+
+    /// The highest of the package limits, active when every package is enabled
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let packages = self.package_limits().await?;
+        let long_term: Vec<_> = packages.iter().filter_map(Self::long_term).collect();
+        let power_limit_uw = long_term.iter().map(|limit| limit.power_limit_uw).max().unwrap_or_default();
+        Ok(BMC_CapSetting {
+            is_active: packages.iter().all(|limits| limits.enabled),
+            power_limit: (power_limit_uw + MICROWATTS_PER_WATT / 2) / MICROWATTS_PER_WATT,
+            exception_action: None,
+            correction_time: long_term.first().and_then(|limit| limit.time_window_us).map(Duration::from_micros),
+            sampling_period: None,
+        })
+    }
+
+    /// The range of a package limit as far as the kernel tells: the lowest of the
+    /// packages' maximums, with no minimum
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        let max_limit_watts = self.package_limits()
+            .await?
+            .iter()
+            .filter_map(|limits| Self::long_term(limits)?.max_power_uw)
+            .min()
+            .map(|max_power_uw| max_power_uw / MICROWATTS_PER_WATT);
+        Ok(CapCapabilities { power_management: true, max_limit_watts, ..CapCapabilities::default() })
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        let power_limit_uw = cap * MICROWATTS_PER_WATT;
+        self.apply_to_packages(|limits| RaplLimitOperation::SetPowerLimit {
+            domain: limits.domain,
+            constraint: RaplConstraint::LongTerm,
+            power_limit_uw,
+        })
+        .await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        self.apply_to_packages(|limits| RaplLimitOperation::SetEnabled { domain: limits.domain, enabled: true }).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.apply_to_packages(|limits| RaplLimitOperation::SetEnabled { domain: limits.domain, enabled: false }).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        let time_window_us = u64::try_from(correction_time.as_micros()).unwrap_or(u64::MAX);
+        self.apply_to_packages(|limits| RaplLimitOperation::SetTimeWindow {
+            domain: limits.domain,
+            constraint: RaplConstraint::LongTerm,
+            time_window_us,
+        })
+        .await
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        self.inner.sensor_readings().await
+    }
+
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        self.inner.sel_entries().await
+    }
+
+    async fn set_workload(&self, params: &FirestarterParams) {
+        self.inner.set_workload(params).await;
+    }
+}
+
+impl Debug for RaplCapController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RAPL limits of {}", self.agent.base_url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::simulated::SimulatedBMC;
+    use crate::rapl::power_limit::RaplLimitsSnapshot;
+    use crate::rapl::powercap_fake::FakePowercap;
+    use crate::rapl::rapl::{RaplDomain, RaplSource};
+    use crate::route::AgentState;
+    use crate::server::Server;
+    use crate::test_util::spawn_router;
+
+    /// Serves the agent's routes, with the RAPL limits of `fake` to change if `serve_rapl_limits`
+    fn spawn_agent(fake: &FakePowercap, serve_rapl_limits: bool) -> String {
+        spawn_router(AgentState {
+            rapl: Arc::new(RaplSource::Powercap(fake.config(&["intel-rapl"]))),
+            serve_rapl_limits,
+            rapl_limits_file: Some(Arc::new(fake.root().join("rapl_limits.json"))),
+            ..AgentState::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_rapl_capping() {
+        let fake = FakePowercap::xeon("rapl_cap", "intel-rapl", 200.0).unwrap();
+        let capper = RaplCapController::new(Arc::new(SimulatedBMC::default()), &spawn_agent(&fake, true));
+
+        // 2 packages limited to 250W long term, of at most 300W
+        let settings = capper.current_cap_settings().await.unwrap();
+        assert!(settings.is_active);
+        assert_eq!(settings.power_limit, 250);
+        assert_eq!(settings.correction_time, Some(Duration::from_micros(999_424)));
+        let capabilities = capper.cap_capabilities().await.unwrap();
+        assert_eq!((capabilities.min_limit_watts, capabilities.max_limit_watts), (None, Some(300)));
+        let original = capper.power_limits().await.unwrap();
+
+        capper.set_cap_power_level(151).await.unwrap();
+        capper.set_correction_time(Duration::from_secs(1)).await.unwrap();
+        capper.deactivate_power_cap().await.unwrap();
+        let limits = capper.power_limits().await.unwrap();
+        assert_eq!(limits[0].constraints[0].power_limit_uw, 151_000_000);
+        assert_eq!(limits[3].constraints[0].power_limit_uw, 151_000_000);
+        assert_eq!(limits[3].constraints[0].time_window_us, Some(1_000_000));
+        // Short term limits and subzones are left alone
+        assert_eq!(limits[0].constraints[1], original[0].constraints[1]);
+        assert_eq!(limits[1], original[1]);
+        let settings = capper.current_cap_settings().await.unwrap();
+        assert!(!settings.is_active);
+        assert_eq!(settings.power_limit, 151);
+
+        // The original limits are kept on disk until restored
+        let limits_file = fake.root().join("rapl_limits.json");
+        assert_eq!(RaplLimitsSnapshot::load(&limits_file).unwrap().limits, original);
+        assert!(capper.restore().await.unwrap());
+        assert_eq!(capper.power_limits().await.unwrap(), original);
+        assert!(!limits_file.exists());
+        assert!(!capper.restore().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_limits_restored_after_restart() {
+        let fake = FakePowercap::xeon("rapl_cap_restart", "intel-rapl", 200.0).unwrap();
+        let capper = RaplCapController::new(Arc::new(SimulatedBMC::default()), &spawn_agent(&fake, true));
+        let original = capper.power_limits().await.unwrap();
+        capper.set_cap_power_level(120).await.unwrap();
+
+        // A new agent picks the original limits up from the file
+        let server = Server::new("127.0.0.1:0")
+            .with_rapl(RaplSource::Powercap(fake.config(&["intel-rapl"])))
+            .with_rapl_limits(&fake.root().join("rapl_limits.json"));
+        let restarted = spawn_router(server.state);
+        let capper = RaplCapController::new(Arc::new(SimulatedBMC::default()), &restarted);
+        assert!(capper.restore().await.unwrap());
+        assert_eq!(capper.power_limits().await.unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn test_limits_not_served() {
+        let fake = FakePowercap::xeon("rapl_cap_read_only", "intel-rapl", 200.0).unwrap();
+        let capper = RaplCapController::new(Arc::new(SimulatedBMC::default()), &spawn_agent(&fake, false));
+        assert_eq!(capper.current_cap_settings().await.unwrap().power_limit, 250);
+        assert!(matches!(capper.set_cap_power_level(120).await, Err(BMCError::Unsupported(_))));
+        assert!(matches!(capper.restore().await, Err(BMCError::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn test_no_packages() {
+        let fake = FakePowercap::new("rapl_cap_none");
+        let capper = RaplCapController::new(Arc::new(SimulatedBMC::default()), &spawn_agent(&fake, true));
+        assert!(matches!(capper.set_cap_power_level(200).await, Err(BMCError::Unsupported(_))));
+        // A limit the kernel doesn't have is an unsupported cap, not a failed test
+        let domain = RaplDomain::new(RaplDomainType::Package, Some(0));
+        let operation = RaplLimitOperation::SetEnabled { domain, enabled: true };
+        let result: BMCResult<()> = capper.agent.request(Method::POST, RAPL_LIMIT_PATH, Some(operation)).await;
+        assert!(matches!(result, Err(BMCError::Unsupported(_))));
+    }
+}
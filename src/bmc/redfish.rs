@@ -0,0 +1,397 @@
+use crate::bmc::bmc::{BMC_CapSetting, ExceptionAction};
+use crate::bmc::error::{BMCError, BMCResult};
+use log::{trace, error};
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{json, Value};
+use std::fmt::{self, Debug};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const REDFISH_CHASSIS_PATH: &str = "/redfish/v1/Chassis";
+const REDFISH_CONTROL_TYPE_POWER: &str = "Power";
+const REDFISH_CONTROL_MODE_ENABLED: &str = "Automatic";
+const REDFISH_CONTROL_MODE_DISABLED: &str = "Disabled";
+
+/// The two flavours of power capping a Redfish service can expose
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedfishSchema {
+    /// The (deprecated but widespread) `Chassis/{id}/Power` resource. The cap lives in
+    /// `PowerControl[0].PowerLimit.LimitInWatts`, and a `null` limit means "no cap".
+    PowerControl,
+    /// The newer `Chassis/{id}/Controls/{id}` resource, with the reading taken from
+    /// `Chassis/{id}/EnvironmentMetrics`. The cap is the control's `SetPoint` and
+    /// activation is carried by its `ControlMode`.
+    Controls,
+}
+
+/// The resources discovered on the Redfish service for power reading and capping
+#[derive(Debug, Clone)]
+struct RedfishEndpoints {
+    schema: RedfishSchema,
+    /// URI of the resource holding the current power reading
+    power: String,
+    /// URI of the resource holding the power limit
+    limit: String,
+}
+
+/// Redfish power-capping client. Offers the same operations as the ipmitool `BMC`:
+/// reading power, reading/setting the limit and activating/deactivating it.
+pub struct Redfish {
+    base_url: String,
+    username: String,
+    password: String,
+    client: Client,
+    endpoints: RedfishEndpoints,
+    /// The `PowerControl` schema has no separate activation flag - a cap is deactivated by
+    /// nulling the limit. To keep the DCMI semantics (a limit can be set while inactive and
+    /// is applied on activation) the requested limit is held here.
+    pending_limit: Mutex<u64>,
+}
+
+impl Redfish {
+    /// Connects to the Redfish service on `hostname` and discovers the power resources of
+    /// the first chassis. `hostname` may be a bare host (https is assumed) or a full URL,
+    /// eg: `http://localhost:8000` for the mock server.
+    ///
+    /// # Errors
+    /// If the service can't be reached or exposes neither a power control nor a `Power` resource
+    pub async fn new(hostname: &str, username: &str, password: &str) -> BMCResult<Self> {
+        let base_url = if hostname.contains("://") {
+            hostname.trim_end_matches('/').to_string()
+        } else {
+            format!("https://{hostname}")
+        };
+
+        // BMCs almost universally present self-signed certificates
+        let client = Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .map_err(|e| BMCError::Transport(format!("Failed to create Redfish client: {e}")))?;
+
+        let mut redfish = Self {
+            base_url,
+            username: String::from(username),
+            password: String::from(password),
+            client,
+            endpoints: RedfishEndpoints {
+                schema: RedfishSchema::PowerControl,
+                power: String::new(),
+                limit: String::new(),
+            },
+            pending_limit: Mutex::new(0),
+        };
+
+        redfish.endpoints = redfish.discover_endpoints().await?;
+        trace!("Redfish endpoints: {:?}", redfish.endpoints);
+
+        let current_limit = redfish.current_cap_settings().await?.power_limit;
+        *redfish.pending_limit.lock().expect("Redfish pending limit poisoned") = current_limit;
+        Ok(redfish)
+    }
+
+    #[must_use]
+    pub fn schema(&self) -> RedfishSchema {
+        self.endpoints.schema
+    }
+
+    /// Sends a request to the Redfish service, returning the JSON body of the response (if any)
+    ///
+    /// # Errors
+    /// If the request can't be sent or the service responds with an error status
+    async fn request(&self, method: Method, uri: &str, body: Option<Value>) -> BMCResult<Value> {
+        let url = format!("{}{uri}", self.base_url);
+        trace!("Redfish {method} {url} {body:?}");
+
+        let mut request = self.client
+            .request(method.clone(), &url)
+            .basic_auth(&self.username, Some(&self.password));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        let response = request.send().await.map_err(|e| {
+            let message = format!("{method} {url}: {e}");
+            if e.is_timeout() { BMCError::Timeout(message) } else { BMCError::Transport(message) }
+        })?;
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            error!("Redfish {method} {url} returned {status}: {text}");
+            let message = format!("{method} {url} returned {status}");
+            return Err(match status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => BMCError::Authentication(message),
+                StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => BMCError::Unsupported(message),
+                StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => BMCError::Timeout(message),
+                _ => BMCError::Transport(message),
+            });
+        }
+
+        // PATCH responses are frequently "204 No Content"
+        if text.trim().is_empty() {
+            Ok(Value::Null)
+        } else {
+            serde_json::from_str(&text).map_err(|e| BMCError::Parse(format!("{method} {url}: {e}")))
+        }
+    }
+
+    async fn get(&self, uri: &str) -> BMCResult<Value> {
+        self.request(Method::GET, uri, None).await
+    }
+
+    async fn patch(&self, uri: &str, body: Value) -> BMCResult<()> {
+        self.request(Method::PATCH, uri, Some(body)).await?;
+        Ok(())
+    }
+
+    /// Walks the chassis collection and picks the capping resources. The `Controls`
+    /// schema is preferred when the chassis has a power control, otherwise falls back
+    /// to `PowerControl`.
+    async fn discover_endpoints(&self) -> BMCResult<RedfishEndpoints> {
+        let chassis_collection = self.get(REDFISH_CHASSIS_PATH).await?;
+        let chassis_uri = Redfish::first_member(&chassis_collection)
+            .ok_or_else(|| BMCError::Unsupported(String::from("Redfish service has no chassis")))?;
+        let chassis = self.get(&chassis_uri).await?;
+
+        if let (Some(controls_uri), Some(metrics_uri)) = (
+            Redfish::odata_id(&chassis["Controls"]),
+            Redfish::odata_id(&chassis["EnvironmentMetrics"]),
+        ) {
+            let controls = self.get(&controls_uri).await?;
+            for control_uri in Redfish::members(&controls) {
+                let control = self.get(&control_uri).await?;
+                if control["ControlType"] == REDFISH_CONTROL_TYPE_POWER {
+                    return Ok(RedfishEndpoints {
+                        schema: RedfishSchema::Controls,
+                        power: metrics_uri,
+                        limit: control_uri,
+                    });
+                }
+            }
+        }
+
+        let power_uri = Redfish::odata_id(&chassis["Power"])
+            .ok_or_else(|| BMCError::Unsupported(String::from("Redfish chassis has neither a power control nor a Power resource")))?;
+        Ok(RedfishEndpoints {
+            schema: RedfishSchema::PowerControl,
+            power: power_uri.clone(),
+            limit: power_uri,
+        })
+    }
+
+    fn odata_id(link: &Value) -> Option<String> {
+        link["@odata.id"].as_str().map(String::from)
+    }
+
+    fn members(collection: &Value) -> Vec<String> {
+        collection["Members"]
+            .as_array()
+            .map(|members| members.iter().filter_map(Redfish::odata_id).collect())
+            .unwrap_or_default()
+    }
+
+    fn first_member(collection: &Value) -> Option<String> {
+        Redfish::members(collection).into_iter().next()
+    }
+
+    // Capping management
+    /// Returns the current cap power limit and activation state in a `CapSetting` struct
+    pub async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        let resource = self.get(&self.endpoints.limit).await?;
+        Ok(match self.endpoints.schema {
+            RedfishSchema::PowerControl => {
+                let pending = *self.pending_limit.lock().expect("Redfish pending limit poisoned");
+                Redfish::parse_power_control_limit(&resource, pending)
+            }
+            RedfishSchema::Controls => Redfish::parse_control_limit(&resource),
+        })
+    }
+
+    pub async fn capping_is_active(&self) -> BMCResult<bool> {
+        Ok(self.current_cap_settings().await?.is_active)
+    }
+
+    pub async fn current_power_limit(&self) -> BMCResult<u64> {
+        Ok(self.current_cap_settings().await?.power_limit)
+    }
+
+    /// Sets the power limit. With the `PowerControl` schema an inactive cap is only
+    /// recorded, and applied on the next `activate_power_cap`.
+    pub async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        match self.endpoints.schema {
+            RedfishSchema::PowerControl => {
+                *self.pending_limit.lock().expect("Redfish pending limit poisoned") = cap;
+                if self.capping_is_active().await? {
+                    self.patch_power_control_limit(json!(cap)).await?;
+                }
+                Ok(())
+            }
+            RedfishSchema::Controls => {
+                self.patch(&self.endpoints.limit, json!({ "SetPoint": cap })).await
+            }
+        }
+    }
+
+    pub async fn activate_power_cap(&self) -> BMCResult<()> {
+        match self.endpoints.schema {
+            RedfishSchema::PowerControl => {
+                let cap = *self.pending_limit.lock().expect("Redfish pending limit poisoned");
+                self.patch_power_control_limit(json!(cap)).await
+            }
+            RedfishSchema::Controls => {
+                self.patch(&self.endpoints.limit, json!({ "ControlMode": REDFISH_CONTROL_MODE_ENABLED })).await
+            }
+        }
+    }
+
+    pub async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        match self.endpoints.schema {
+            RedfishSchema::PowerControl => self.patch_power_control_limit(Value::Null).await,
+            RedfishSchema::Controls => {
+                self.patch(&self.endpoints.limit, json!({ "ControlMode": REDFISH_CONTROL_MODE_DISABLED })).await
+            }
+        }
+    }
+
+    async fn patch_power_control_limit(&self, limit: Value) -> BMCResult<()> {
+        let body = json!({ "PowerControl": [{ "PowerLimit": { "LimitInWatts": limit } }] });
+        self.patch(&self.endpoints.limit, body).await
+    }
+
+    // Power management
+    pub async fn current_power(&self) -> BMCResult<u64> {
+        let resource = self.get(&self.endpoints.power).await?;
+        match self.endpoints.schema {
+            RedfishSchema::PowerControl => Redfish::parse_power_control_reading(&resource),
+            RedfishSchema::Controls => Redfish::parse_environment_metrics_reading(&resource),
+        }
+    }
+
+    /// Redfish reports watts as JSON numbers, which may or may not be integral
+    fn watts(value: &Value) -> Option<u64> {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        value.as_u64().or_else(|| value.as_f64().map(|watts| watts.round() as u64))
+    }
+
+    /// Parses the power reading from a `Chassis/{id}/Power` resource
+    fn parse_power_control_reading(power: &Value) -> BMCResult<u64> {
+        Redfish::watts(&power["PowerControl"][0]["PowerConsumedWatts"])
+            .ok_or_else(|| BMCError::Parse(String::from("Redfish Power resource has no PowerConsumedWatts")))
+    }
+
+    /// Parses the power reading from a `Chassis/{id}/EnvironmentMetrics` resource
+    fn parse_environment_metrics_reading(metrics: &Value) -> BMCResult<u64> {
+        Redfish::watts(&metrics["PowerWatts"]["Reading"])
+            .ok_or_else(|| BMCError::Parse(String::from("Redfish EnvironmentMetrics resource has no PowerWatts reading")))
+    }
+
+    /// A `null` (or absent) `LimitInWatts` means capping is inactive, in which case the
+    /// reported limit is the one that will be applied on activation.
+    fn parse_power_control_limit(power: &Value, pending_limit: u64) -> BMC_CapSetting {
+        let limit = &power["PowerControl"][0]["PowerLimit"];
+        let exception_action = match limit["LimitException"].as_str() {
+            Some("NoAction") => Some(ExceptionAction::NoAction),
+            Some("HardPowerOff") => Some(ExceptionAction::HardPowerOff),
+            Some("LogEventOnly") => Some(ExceptionAction::LogEvent),
+            _ => None,
+        };
+        let correction_time = limit["CorrectionInMs"].as_u64().map(Duration::from_millis);
+
+        let (is_active, power_limit) = match Redfish::watts(&limit["LimitInWatts"]) {
+            Some(power_limit) => (true, power_limit),
+            None => (false, pending_limit),
+        };
+        BMC_CapSetting { is_active, power_limit, exception_action, correction_time, sampling_period: None }
+    }
+
+    fn parse_control_limit(control: &Value) -> BMC_CapSetting {
+        BMC_CapSetting {
+            is_active: control["ControlMode"] != REDFISH_CONTROL_MODE_DISABLED,
+            power_limit: Redfish::watts(&control["SetPoint"]).unwrap_or(0),
+            ..BMC_CapSetting::default()
+        }
+    }
+}
+
+impl Debug for Redfish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -U {} -P **** ({:?})",
+            self.base_url, self.username, self.endpoints.schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::redfish_mock::MockRedfish;
+
+    #[test]
+    fn test_parse_power_control_limit() {
+        let power = json!({
+            "PowerControl": [{
+                "PowerConsumedWatts": 344.5,
+                "PowerLimit": { "LimitInWatts": 500, "LimitException": "LogEventOnly", "CorrectionInMs": 2000 }
+            }]
+        });
+        let settings = Redfish::parse_power_control_limit(&power, 0);
+        assert!(settings.is_active);
+        assert_eq!(settings.power_limit, 500);
+        assert_eq!(settings.exception_action, Some(ExceptionAction::LogEvent));
+        assert_eq!(settings.correction_time, Some(Duration::from_millis(2000)));
+        assert_eq!(Redfish::parse_power_control_reading(&power).unwrap(), 345);
+
+        let power = json!({ "PowerControl": [{ "PowerLimit": { "LimitInWatts": null } }] });
+        let settings = Redfish::parse_power_control_limit(&power, 230);
+        assert!(!settings.is_active);
+        assert_eq!(settings.power_limit, 230);
+    }
+
+    #[test]
+    fn test_parse_control_limit() {
+        let control = json!({ "ControlType": "Power", "SetPoint": 580, "ControlMode": "Disabled" });
+        let settings = Redfish::parse_control_limit(&control);
+        assert!(!settings.is_active);
+        assert_eq!(settings.power_limit, 580);
+
+        let control = json!({ "ControlType": "Power", "SetPoint": 230, "ControlMode": "Automatic" });
+        assert!(Redfish::parse_control_limit(&control).is_active);
+    }
+
+    async fn exercise_capping(schema: RedfishSchema) {
+        let mock = MockRedfish::spawn(schema, 600);
+        let redfish = Redfish::new(&mock.url(), "admin", "secret").await.unwrap();
+        assert_eq!(redfish.schema(), schema);
+        assert!(!redfish.capping_is_active().await.unwrap());
+        assert_eq!(redfish.current_power().await.unwrap(), 600);
+
+        // Level before activate: the limit is recorded but not applied
+        redfish.set_cap_power_level(230).await.unwrap();
+        assert!(!redfish.capping_is_active().await.unwrap());
+        assert_eq!(redfish.current_power_limit().await.unwrap(), 230);
+        assert_eq!(redfish.current_power().await.unwrap(), 600);
+
+        redfish.activate_power_cap().await.unwrap();
+        assert!(redfish.capping_is_active().await.unwrap());
+        assert_eq!(redfish.current_power().await.unwrap(), 230);
+
+        // Level to level while active
+        redfish.set_cap_power_level(400).await.unwrap();
+        assert_eq!(redfish.current_power_limit().await.unwrap(), 400);
+        assert_eq!(redfish.current_power().await.unwrap(), 400);
+
+        redfish.deactivate_power_cap().await.unwrap();
+        assert!(!redfish.capping_is_active().await.unwrap());
+        assert_eq!(redfish.current_power_limit().await.unwrap(), 400);
+        assert_eq!(redfish.current_power().await.unwrap(), 600);
+    }
+
+    #[tokio::test]
+    async fn test_power_control_capping() {
+        exercise_capping(RedfishSchema::PowerControl).await;
+    }
+
+    #[tokio::test]
+    async fn test_controls_capping() {
+        exercise_capping(RedfishSchema::Controls).await;
+    }
+}
@@ -0,0 +1,205 @@
+use crate::bmc::redfish::RedfishSchema;
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::{info, trace};
+use serde_json::{json, Value};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+const MOCK_CHASSIS_URI: &str = "/redfish/v1/Chassis/1";
+
+/// The simulated node behind the mock Redfish service
+#[derive(Debug)]
+pub struct MockNode {
+    pub schema: RedfishSchema,
+    /// Power drawn by the node when no cap is active
+    pub uncapped_watts: u64,
+    pub power_limit: u64,
+    pub is_active: bool,
+}
+
+impl MockNode {
+    /// The node power is the uncapped power, clamped to the limit when capping is active
+    #[must_use]
+    pub fn power(&self) -> u64 {
+        if self.is_active {
+            self.uncapped_watts.min(self.power_limit)
+        } else {
+            self.uncapped_watts
+        }
+    }
+}
+
+type SharedNode = Arc<Mutex<MockNode>>;
+
+/// A local Redfish service implementing just enough of the `Chassis` tree
+/// for power reading and capping, using either schema.
+pub struct MockRedfish {
+    pub address: SocketAddr,
+    pub node: SharedNode,
+}
+
+impl MockRedfish {
+    /// Launches the mock on an ephemeral localhost port. Must be called from within a tokio runtime.
+    ///
+    /// # Panics
+    /// If the listening socket can't be created
+    #[must_use]
+    pub fn spawn(schema: RedfishSchema, uncapped_watts: u64) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock Redfish socket");
+        MockRedfish::spawn_on(listener, schema, uncapped_watts)
+    }
+
+    /// Launches the mock on an already bound listener. Must be called from within a tokio runtime.
+    ///
+    /// # Panics
+    /// If the listener can't be handed over to the server
+    #[must_use]
+    pub fn spawn_on(listener: TcpListener, schema: RedfishSchema, uncapped_watts: u64) -> Self {
+        let address = listener.local_addr().expect("Failed to get mock Redfish address");
+        let node = Arc::new(Mutex::new(MockNode {
+            schema,
+            uncapped_watts,
+            power_limit: 0,
+            is_active: false,
+        }));
+
+        let server = axum::Server::from_tcp(listener)
+            .expect("Failed to create mock Redfish server")
+            .serve(create_router(node.clone()).into_make_service());
+        tokio::spawn(server);
+
+        info!("Mock Redfish ({schema:?}) listening on {address}");
+        Self { address, node }
+    }
+
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+fn create_router(node: SharedNode) -> Router {
+    Router::new()
+        .route("/redfish/v1/Chassis", get(chassis_collection))
+        .route(MOCK_CHASSIS_URI, get(chassis))
+        .route(&format!("{MOCK_CHASSIS_URI}/Power"), get(power).patch(patch_power))
+        .route(&format!("{MOCK_CHASSIS_URI}/EnvironmentMetrics"), get(environment_metrics))
+        .route(&format!("{MOCK_CHASSIS_URI}/Controls"), get(controls))
+        .route(&format!("{MOCK_CHASSIS_URI}/Controls/PowerLimit"), get(power_limit).patch(patch_power_limit))
+        .layer(middleware::from_fn(require_auth))
+        .with_state(node)
+}
+
+/// Real services reject unauthenticated requests - so does the mock
+async fn require_auth<B>(request: Request<B>, next: Next<B>) -> Response {
+    trace!("Mock Redfish: {} {}", request.method(), request.uri());
+    if request.headers().contains_key(AUTHORIZATION) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+async fn chassis_collection() -> Json<Value> {
+    Json(json!({
+        "@odata.id": "/redfish/v1/Chassis",
+        "Members": [{ "@odata.id": MOCK_CHASSIS_URI }],
+        "Members@odata.count": 1
+    }))
+}
+
+async fn chassis(State(node): State<SharedNode>) -> Json<Value> {
+    let node = node.lock().expect("Mock node poisoned");
+    let mut chassis = json!({
+        "@odata.id": MOCK_CHASSIS_URI,
+        "Id": "1",
+        "Power": { "@odata.id": format!("{MOCK_CHASSIS_URI}/Power") },
+    });
+    if node.schema == RedfishSchema::Controls {
+        chassis["Controls"] = json!({ "@odata.id": format!("{MOCK_CHASSIS_URI}/Controls") });
+        chassis["EnvironmentMetrics"] = json!({ "@odata.id": format!("{MOCK_CHASSIS_URI}/EnvironmentMetrics") });
+    }
+    Json(chassis)
+}
+
+async fn power(State(node): State<SharedNode>) -> Json<Value> {
+    let node = node.lock().expect("Mock node poisoned");
+    let limit = if node.is_active { json!(node.power_limit) } else { Value::Null };
+    Json(json!({
+        "@odata.id": format!("{MOCK_CHASSIS_URI}/Power"),
+        "PowerControl": [{
+            "MemberId": "0",
+            "PowerConsumedWatts": node.power(),
+            "PowerLimit": { "LimitInWatts": limit, "LimitException": "LogEventOnly" }
+        }]
+    }))
+}
+
+async fn patch_power(State(node): State<SharedNode>, Json(body): Json<Value>) -> StatusCode {
+    let mut node = node.lock().expect("Mock node poisoned");
+    let limit = &body["PowerControl"][0]["PowerLimit"]["LimitInWatts"];
+    match limit {
+        Value::Null => node.is_active = false,
+        _ => match limit.as_u64() {
+            Some(watts) => {
+                node.power_limit = watts;
+                node.is_active = true;
+            }
+            None => return StatusCode::BAD_REQUEST,
+        },
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn environment_metrics(State(node): State<SharedNode>) -> Json<Value> {
+    let node = node.lock().expect("Mock node poisoned");
+    Json(json!({
+        "@odata.id": format!("{MOCK_CHASSIS_URI}/EnvironmentMetrics"),
+        "PowerWatts": { "Reading": node.power() }
+    }))
+}
+
+async fn controls() -> Json<Value> {
+    Json(json!({
+        "@odata.id": format!("{MOCK_CHASSIS_URI}/Controls"),
+        "Members": [{ "@odata.id": format!("{MOCK_CHASSIS_URI}/Controls/PowerLimit") }]
+    }))
+}
+
+async fn power_limit(State(node): State<SharedNode>) -> Json<Value> {
+    let node = node.lock().expect("Mock node poisoned");
+    let mode = if node.is_active { "Automatic" } else { "Disabled" };
+    Json(json!({
+        "@odata.id": format!("{MOCK_CHASSIS_URI}/Controls/PowerLimit"),
+        "ControlType": "Power",
+        "SetPoint": node.power_limit,
+        "SetPointUnits": "W",
+        "ControlMode": mode,
+        "Sensor": { "Reading": node.power() }
+    }))
+}
+
+async fn patch_power_limit(State(node): State<SharedNode>, Json(body): Json<Value>) -> StatusCode {
+    let mut node = node.lock().expect("Mock node poisoned");
+    if let Some(set_point) = body.get("SetPoint") {
+        match set_point.as_u64() {
+            Some(watts) => node.power_limit = watts,
+            None => return StatusCode::BAD_REQUEST,
+        }
+    }
+    if let Some(mode) = body.get("ControlMode") {
+        match mode.as_str() {
+            Some("Automatic" | "Override" | "Manual") => node.is_active = true,
+            Some("Disabled") => node.is_active = false,
+            _ => return StatusCode::BAD_REQUEST,
+        }
+    }
+    StatusCode::NO_CONTENT
+}
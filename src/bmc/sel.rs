@@ -0,0 +1,137 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// One System Event Log record: power limit exceptions, PSU events, thermal trips...
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelEntry {
+    pub id: u32,
+    /// BMC time of the event. `None` for events logged before the BMC clock was set ("Pre-Init").
+    pub timestamp: Option<DateTime<Utc>>,
+    pub sensor: String,
+    pub description: String,
+    /// `None` when the event is neither asserted nor deasserted
+    pub asserted: Option<bool>,
+    /// Anything after the direction, eg the reading and threshold of a threshold event
+    pub detail: Option<String>,
+}
+
+/// Parses the output of `ipmitool sel elist`. Each line holds `|` separated fields:
+///
+/// `  1a | 05/09/2023 | 14:24:36 | Power Unit #0x01 | Power off/down | Asserted`
+///
+/// Lines that don't look like an entry ("SEL has no entries", ...) are skipped.
+#[must_use]
+pub fn parse_sel_elist(output: &str) -> Vec<SelEntry> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let entry = parse_sel_line(line);
+            if entry.is_none() {
+                warn!("SEL: skipping '{}'", line.trim());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn parse_sel_line(line: &str) -> Option<SelEntry> {
+    let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+    if fields.len() < 5 {
+        return None;
+    }
+
+    let id = u32::from_str_radix(fields[0], 16).ok()?;
+    // ipmitool prints the BMC's time converted to the local timezone
+    let timestamp = NaiveDateTime::parse_from_str(&format!("{} {}", fields[1], fields[2]), "%m/%d/%Y %H:%M:%S")
+        .ok()
+        .and_then(|local| Local.from_local_datetime(&local).earliest())
+        .map(|t| t.with_timezone(&Utc));
+    if timestamp.is_none() && fields[1] != "Pre-Init" {
+        return None;
+    }
+
+    let asserted = match fields.get(5) {
+        Some(&"Asserted") => Some(true),
+        Some(&"Deasserted") => Some(false),
+        _ => None,
+    };
+    // Without a direction, whatever follows the description is detail
+    let detail_from = if asserted.is_some() { 6 } else { 5 };
+    let detail = fields.get(detail_from..).filter(|rest| !rest.is_empty()).map(|rest| rest.join(" | "));
+
+    Some(SelEntry {
+        id,
+        timestamp,
+        sensor: String::from(fields[3]),
+        description: String::from(fields[4]),
+        asserted,
+        detail,
+    })
+}
+
+/// The entries logged between two host times. SEL timestamps are taken from the BMC
+/// clock, so they are compared after removing the BMC's `clock_offset` when known.
+#[must_use]
+pub fn entries_between(entries: &[SelEntry], start: DateTime<Utc>, end: DateTime<Utc>, clock_offset: Option<Duration>) -> Vec<SelEntry> {
+    let offset = clock_offset.unwrap_or_else(Duration::zero);
+    // The SEL only has whole seconds
+    let start = start - Duration::seconds(1);
+    entries
+        .iter()
+        .filter(|entry| entry.timestamp.is_some_and(|t| (start..=end).contains(&(t - offset))))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEL_ELIST: &str = "
+   1 | 05/09/2023 | 14:20:01 | Event Logging Disabled #0x07 | Log area reset/cleared | Asserted
+  1a | 05/09/2023 | 14:24:36 | Power Unit #0x01 | Power off/down | Asserted
+  1b | 05/09/2023 | 14:25:10 | Temperature CPU1 Temp | Upper Critical going high | Asserted | Reading 95 > Threshold 90 degrees C
+  1c | Pre-Init  |0000000042| System Event #0x83 | Timestamp Clock Sync
+  1d | 05/09/2023 | 14:26:00 | Power Supply PS2 Status | Power Supply AC lost | Deasserted
+";
+
+    fn local(hms: &str) -> DateTime<Utc> {
+        let naive = NaiveDateTime::parse_from_str(&format!("05/09/2023 {hms}"), "%m/%d/%Y %H:%M:%S").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_sel_elist() {
+        let entries = parse_sel_elist(SEL_ELIST);
+        assert_eq!(entries.len(), 5);
+
+        assert_eq!(entries[1].id, 0x1a);
+        assert_eq!(entries[1].timestamp, Some(local("14:24:36")));
+        assert_eq!(entries[1].sensor, "Power Unit #0x01");
+        assert_eq!(entries[1].description, "Power off/down");
+        assert_eq!(entries[1].asserted, Some(true));
+        assert_eq!(entries[1].detail, None);
+
+        assert_eq!(entries[2].detail.as_deref(), Some("Reading 95 > Threshold 90 degrees C"));
+
+        assert_eq!(entries[3].timestamp, None);
+        assert_eq!(entries[3].asserted, None);
+        assert_eq!(entries[4].asserted, Some(false));
+
+        assert!(parse_sel_elist("SEL has no entries").is_empty());
+    }
+
+    #[test]
+    fn test_entries_between() {
+        let entries = parse_sel_elist(SEL_ELIST);
+        let ids = |found: Vec<SelEntry>| found.iter().map(|e| e.id).collect::<Vec<u32>>();
+
+        assert_eq!(ids(entries_between(&entries, local("14:24:00"), local("14:25:30"), None)), [0x1a, 0x1b]);
+
+        // a BMC 30s ahead of the host
+        let offset = Some(Duration::seconds(30));
+        assert_eq!(ids(entries_between(&entries, local("14:24:00"), local("14:25:30"), offset)), [0x1a, 0x1b, 0x1d]);
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{DateTime, Utc};
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+/// The sensors worth following during a capping test: where the capped watts go
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorKind {
+    PsuInputPower,
+    PsuOutputPower,
+    InletTemperature,
+    ExhaustTemperature,
+    FanSpeed,
+}
+
+/// A sensor reading under a vendor neutral name, eg `psu1_input_watts` for
+/// Dell's "PS1 Input Power" or Lenovo's "PSU1_PIN"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorReading {
+    pub name: String,
+    pub kind: SensorKind,
+    pub value: f64,
+    /// The name given by the BMC
+    pub raw_name: String,
+}
+
+/// All the sensor readings of one poll
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorRecord {
+    pub timestamp: DateTime<Utc>,
+    pub readings: Vec<SensorReading>,
+}
+
+/// Parses the output of `ipmitool sdr elist full`, keeping the sensors with a
+/// `SensorKind` and a reading:
+///
+/// `PS1 Input Power  | 72h | ok  | 10.1 | 224 Watts`
+#[must_use]
+pub fn parse_sdr_elist(output: &str) -> Vec<SensorReading> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+            if fields.len() < 5 {
+                return None;
+            }
+            let (raw_name, reading) = (fields[0], fields[4]);
+            let mut words = reading.split_ascii_whitespace();
+            let value: f64 = words.next()?.parse().ok()?;
+            let unit = words.collect::<Vec<&str>>().join(" ");
+
+            let reading = normalise(raw_name, &unit).map(|(kind, name)| SensorReading {
+                name,
+                kind,
+                value,
+                raw_name: String::from(raw_name),
+            });
+            if reading.is_none() {
+                trace!("SDR: ignoring {raw_name} ({unit})");
+            }
+            reading
+        })
+        .collect()
+}
+
+/// Works out what a sensor measures from its name and unit, and gives it a vendor neutral name
+fn normalise(raw_name: &str, unit: &str) -> Option<(SensorKind, String)> {
+    let name = raw_name.to_lowercase();
+    let unit = unit.to_lowercase();
+    // "PSU1_PIN" -> ["psu1", "pin"], "PS 2 Output" -> ["ps", "2", "output"]
+    let words: Vec<&str> = name.split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| !w.is_empty()).collect();
+    let has = |candidates: &[&str]| words.iter().any(|word| candidates.contains(word));
+
+    if unit == "watts" {
+        let psu = psu_number(&words)?;
+        if has(&["input", "in", "pin"]) {
+            return Some((SensorKind::PsuInputPower, format!("psu{psu}_input_watts")));
+        }
+        if has(&["output", "out", "pout"]) {
+            return Some((SensorKind::PsuOutputPower, format!("psu{psu}_output_watts")));
+        }
+        return None;
+    }
+
+    if unit == "degrees c" {
+        if has(&["inlet", "ambient", "intake"]) {
+            return Some((SensorKind::InletTemperature, String::from("inlet_celsius")));
+        }
+        if has(&["exhaust", "outlet"]) {
+            return Some((SensorKind::ExhaustTemperature, String::from("exhaust_celsius")));
+        }
+        return None;
+    }
+
+    if unit == "rpm" {
+        // "Fan1A" -> "fan1a", "FAN 2" -> "fan2", "Sys Fan 3" -> "fan3"
+        let fan = name.split_once("fan")?.1;
+        let id: String = fan.chars().filter(char::is_ascii_alphanumeric).collect();
+        let id = id.trim_end_matches("rpm").trim_end_matches("speed");
+        return Some((SensorKind::FanSpeed, format!("fan{id}_rpm")));
+    }
+
+    None
+}
+
+/// The PSU number in names like "PS1", "PSU2", "PS 2" or "Power Supply 1"
+fn psu_number(words: &[&str]) -> Option<u32> {
+    words.iter().enumerate().find_map(|(i, word)| {
+        let prefix = ["psu", "ps", "supply"].iter().find(|prefix| word.starts_with(**prefix))?;
+        let number = &word[prefix.len()..];
+        if number.is_empty() {
+            words.get(i + 1)?.parse().ok()
+        } else {
+            number.parse().ok()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sdr_elist() {
+        let sdr_output = "
+Inlet Temp       | 04h | ok  |  7.1 | 23 degrees C
+Exhaust Temp     | 01h | ok  |  7.1 | 35 degrees C
+Temp             | 0Eh | ok  |  3.1 | 51 degrees C
+Fan1A            | 30h | ok  |  7.1 | 5880 RPM
+Fan2B            | 35h | ns  |  7.1 | No Reading
+PS1 Input Power  | 72h | ok  | 10.1 | 224 Watts
+PS2 Input Power  | 73h | ok  | 10.2 | 218 Watts
+Pwr Consumption  | 77h | ok  |  7.1 | 442 Watts
+Current 1        | 79h | ok  | 10.1 | 1 Amps
+";
+        let readings = parse_sdr_elist(sdr_output);
+        let names: Vec<&str> = readings.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["inlet_celsius", "exhaust_celsius", "fan1a_rpm", "psu1_input_watts", "psu2_input_watts"]);
+        assert_eq!(readings[3].kind, SensorKind::PsuInputPower);
+        assert!((readings[3].value - 224.0).abs() < f64::EPSILON);
+        assert_eq!(readings[3].raw_name, "PS1 Input Power");
+    }
+
+    #[test]
+    fn test_names_are_vendor_neutral() {
+        let cases = [
+            ("PSU1_PIN", "Watts", "psu1_input_watts"),
+            ("PSU2_POUT", "Watts", "psu2_output_watts"),
+            ("Power Supply 1 Output", "Watts", "psu1_output_watts"),
+            ("PS 2 Input", "Watts", "psu2_input_watts"),
+            ("Ambient Temp", "degrees C", "inlet_celsius"),
+            ("SYS_Outlet_Temp", "degrees C", "exhaust_celsius"),
+            ("FAN 2", "RPM", "fan2_rpm"),
+            ("Sys Fan 3 Speed", "RPM", "fan3_rpm"),
+        ];
+        for (raw_name, unit, expected) in cases {
+            assert_eq!(normalise(raw_name, unit).map(|(_, name)| name).as_deref(), Some(expected), "{raw_name}");
+        }
+        assert_eq!(normalise("CPU1 Temp", "degrees C"), None);
+    }
+}
@@ -0,0 +1,390 @@
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading, ExceptionAction};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::sensors::{SensorKind, SensorReading};
+use chrono::{TimeZone, Utc};
+use crate::model::FirestarterParams;
+use log::trace;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SIM_IDLE_WATTS: f64 = 250.0;
+const SIM_MAX_WATTS: f64 = 620.0;
+const SIM_ONLINE_CPUS: u64 = 64;
+const SIM_CORRECTION_TIME_MILLIS: u64 = 1000;
+const SIM_SAMPLING_PERIOD_MILLIS: u64 = 1000;
+const SIM_NOISE_WATTS: f64 = 5.0;
+/// The limit range the simulated BMC accepts
+const SIM_MIN_LIMIT_WATTS: u64 = 100;
+const SIM_MAX_LIMIT_WATTS: u64 = 1000;
+const SIM_PSU_EFFICIENCY: f64 = 0.94;
+const SIM_INLET_CELSIUS: f64 = 22.0;
+/// Exhaust temperature rise per Watt drawn
+const SIM_EXHAUST_CELSIUS_PER_WATT: f64 = 0.03;
+const SIM_FAN_IDLE_RPM: f64 = 4000.0;
+const SIM_FAN_RPM_PER_WATT: f64 = 10.0;
+
+/// The physical characteristics of the simulated node and its BMC
+#[derive(Debug, Clone)]
+pub struct SimulationParams {
+    /// Node power with no load
+    pub idle_watts: f64,
+    /// Node power with every CPU at 100% load
+    pub max_watts: f64,
+    /// Used to scale the load when a test runs on a subset of the CPUs
+    pub online_cpus: u64,
+    /// Time taken by the BMC to bring power down to a newly applied cap
+    pub correction_time: Duration,
+    /// The BMC only refreshes its reading once per sampling period
+    pub sampling_period: Duration,
+    /// Amplitude of the (uniform) noise added to each sample
+    pub noise_watts: f64,
+    /// Seeds the noise generator, so a simulation is reproducible
+    pub seed: u64,
+}
+
+impl Default for SimulationParams {
+    fn default() -> Self {
+        Self {
+            idle_watts: SIM_IDLE_WATTS,
+            max_watts: SIM_MAX_WATTS,
+            online_cpus: SIM_ONLINE_CPUS,
+            correction_time: Duration::from_millis(SIM_CORRECTION_TIME_MILLIS),
+            sampling_period: Duration::from_millis(SIM_SAMPLING_PERIOD_MILLIS),
+            noise_watts: SIM_NOISE_WATTS,
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// Node power model. Power follows the requested load instantly, except when
+/// capping pulls it down: the BMC then ramps the power linearly from where it was
+/// to the cap over the correction time.
+#[derive(Debug)]
+struct PowerModel {
+    params: SimulationParams,
+    epoch: Instant,
+    load_fraction: f64,
+    load_end: Option<Instant>,
+    power_limit: u64,
+    is_active: bool,
+    /// Recorded only - the simulated BMC never powers the node off
+    exception_action: ExceptionAction,
+    /// Power at the last cap change, and when the change happened
+    ramp_from: f64,
+    ramp_start: Instant,
+}
+
+impl PowerModel {
+    fn new(params: SimulationParams, now: Instant) -> Self {
+        Self {
+            ramp_from: params.idle_watts,
+            params,
+            epoch: now,
+            load_fraction: 0.0,
+            load_end: None,
+            power_limit: 0,
+            is_active: false,
+            exception_action: ExceptionAction::NoAction,
+            ramp_start: now,
+        }
+    }
+
+    /// Power the node would draw, uncapped, for the load running at `now`
+    fn demand(&self, now: Instant) -> f64 {
+        let load = match self.load_end {
+            Some(end) if now >= end => 0.0,
+            _ => self.load_fraction,
+        };
+        self.params.idle_watts + (self.params.max_watts - self.params.idle_watts) * load
+    }
+
+    /// Power actually drawn at `now`, before sampling and noise
+    #[allow(clippy::cast_precision_loss)]
+    fn power_at(&self, now: Instant) -> f64 {
+        let demand = self.demand(now);
+        if !self.is_active || demand <= self.power_limit as f64 {
+            return demand;
+        }
+
+        let cap = self.power_limit as f64;
+        let correction_secs = self.params.correction_time.as_secs_f64();
+        let elapsed_secs = now.saturating_duration_since(self.ramp_start).as_secs_f64();
+        if correction_secs <= 0.0 || elapsed_secs >= correction_secs || self.ramp_from <= cap {
+            return cap;
+        }
+
+        let from = self.ramp_from.min(demand);
+        from + (cap - from) * elapsed_secs / correction_secs
+    }
+
+    /// The value reported by the BMC: the power at the start of the current sampling
+    /// period plus noise that is fixed for the period.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    fn reading_at(&self, now: Instant) -> u64 {
+        let since_epoch = now.saturating_duration_since(self.epoch);
+        let period_nanos = self.params.sampling_period.as_nanos().max(1);
+        let sample = (since_epoch.as_nanos() / period_nanos) as u64;
+        let sample_time = self.epoch + Duration::from_nanos((u128::from(sample) * period_nanos) as u64);
+
+        let noise = self.params.noise_watts * PowerModel::noise(self.params.seed, sample);
+        (self.power_at(sample_time) + noise).max(0.0).round() as u64
+    }
+
+    /// Deterministic noise in [-1, 1) for a sample (splitmix64)
+    #[allow(clippy::cast_precision_loss)]
+    fn noise(seed: u64, sample: u64) -> f64 {
+        let mut z = seed.wrapping_add(sample.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    /// Any change to the cap restarts the correction ramp from the current power
+    fn cap_changed(&mut self, now: Instant) {
+        self.ramp_from = self.power_at(now);
+        self.ramp_start = now;
+    }
+
+    fn set_power_limit(&mut self, cap: u64, now: Instant) {
+        self.cap_changed(now);
+        self.power_limit = cap;
+    }
+
+    fn set_active(&mut self, is_active: bool, now: Instant) {
+        self.cap_changed(now);
+        self.is_active = is_active;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn set_workload(&mut self, params: &FirestarterParams, now: Instant) {
+        let cpus = self.params.online_cpus.max(1);
+        let threads = if params.n_threads == 0 { cpus } else { params.n_threads.min(cpus) };
+        self.load_fraction = (params.load_pct.min(100) as f64 / 100.0) * (threads as f64 / cpus as f64);
+        self.load_end = Some(now + Duration::from_secs(params.runtime_secs));
+    }
+}
+
+/// A BMC backed by a power model instead of hardware. Answers the same queries
+/// as the ipmitool `BMC`, so capping campaigns can be developed off-hardware.
+#[derive(Debug)]
+pub struct SimulatedBMC {
+    model: Mutex<PowerModel>,
+}
+
+impl Default for SimulatedBMC {
+    fn default() -> Self {
+        Self::new(SimulationParams::default())
+    }
+}
+
+impl SimulatedBMC {
+    #[must_use]
+    pub fn new(params: SimulationParams) -> Self {
+        Self { model: Mutex::new(PowerModel::new(params, Instant::now())) }
+    }
+
+    fn model(&self) -> std::sync::MutexGuard<'_, PowerModel> {
+        self.model.lock().expect("Simulated BMC model poisoned")
+    }
+
+    // Capping management
+    /// Returns the current cap power limit and activation state in a `CapSetting` struct
+    #[must_use]
+    pub fn current_cap_settings(&self) -> BMC_CapSetting {
+        let model = self.model();
+        BMC_CapSetting {
+            is_active: model.is_active,
+            power_limit: model.power_limit,
+            exception_action: Some(model.exception_action),
+            correction_time: Some(model.params.correction_time),
+            sampling_period: Some(model.params.sampling_period),
+        }
+    }
+
+    #[must_use]
+    pub fn cap_capabilities(&self) -> CapCapabilities {
+        CapCapabilities {
+            dcmi_version: Some(String::from("1.5")),
+            power_management: true,
+            min_limit_watts: Some(SIM_MIN_LIMIT_WATTS),
+            max_limit_watts: Some(SIM_MAX_LIMIT_WATTS),
+        }
+    }
+
+    pub fn set_cap_power_level(&self, cap: u64) {
+        trace!("Simulated BMC: set limit {cap}");
+        self.model().set_power_limit(cap, Instant::now());
+    }
+
+    pub fn activate_power_cap(&self) {
+        trace!("Simulated BMC: activate");
+        self.model().set_active(true, Instant::now());
+    }
+
+    pub fn deactivate_power_cap(&self) {
+        trace!("Simulated BMC: deactivate");
+        self.model().set_active(false, Instant::now());
+    }
+
+    /// Also changes the pace of a ramp already under way
+    pub fn set_correction_time(&self, correction_time: Duration) {
+        trace!("Simulated BMC: set correction time {correction_time:?}");
+        self.model().params.correction_time = correction_time;
+    }
+
+    pub fn set_sampling_period(&self, sampling_period: Duration) {
+        trace!("Simulated BMC: set sampling period {sampling_period:?}");
+        self.model().params.sampling_period = sampling_period;
+    }
+
+    pub fn set_exception_action(&self, action: ExceptionAction) {
+        trace!("Simulated BMC: set exception action {action:?}");
+        self.model().exception_action = action;
+    }
+
+    // Power management
+    #[must_use]
+    pub fn current_power(&self) -> u64 {
+        self.model().reading_at(Instant::now())
+    }
+
+    /// The simulated BMC shares the host clock, truncated to the second like a real one
+    #[must_use]
+    pub fn current_power_reading(&self) -> BMC_PowerReading {
+        let model = self.model();
+        BMC_PowerReading {
+            timestamp: Utc.timestamp_opt(Utc::now().timestamp(), 0).single(),
+            sampling_period: Some(model.params.sampling_period),
+            reading_is_active: Some(true),
+            ..BMC_PowerReading::from_instant(model.reading_at(Instant::now()))
+        }
+    }
+
+    /// Two PSUs sharing the load, with the air heating up and the fans speeding up with power
+    #[must_use]
+    pub fn sensor_readings(&self) -> Vec<SensorReading> {
+        let model = self.model();
+        let watts = model.power_at(Instant::now());
+        let above_idle = (watts - model.params.idle_watts).max(0.0);
+        let reading = |kind, name: &str, value: f64| SensorReading {
+            name: String::from(name),
+            kind,
+            value: value.round(),
+            raw_name: String::from(name),
+        };
+        vec![
+            reading(SensorKind::PsuInputPower, "psu1_input_watts", watts / 2.0),
+            reading(SensorKind::PsuInputPower, "psu2_input_watts", watts / 2.0),
+            reading(SensorKind::PsuOutputPower, "psu1_output_watts", watts / 2.0 * SIM_PSU_EFFICIENCY),
+            reading(SensorKind::PsuOutputPower, "psu2_output_watts", watts / 2.0 * SIM_PSU_EFFICIENCY),
+            reading(SensorKind::InletTemperature, "inlet_celsius", SIM_INLET_CELSIUS),
+            reading(SensorKind::ExhaustTemperature, "exhaust_celsius", SIM_INLET_CELSIUS + watts * SIM_EXHAUST_CELSIUS_PER_WATT),
+            reading(SensorKind::FanSpeed, "fan1_rpm", SIM_FAN_IDLE_RPM + above_idle * SIM_FAN_RPM_PER_WATT),
+        ]
+    }
+
+    /// Tells the model what load the agent has been asked to run
+    pub fn set_workload(&self, params: &FirestarterParams) {
+        trace!("Simulated BMC: workload {params:?}");
+        self.model().set_workload(params, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::controller::PowerCapController;
+    use crate::bmc::monitor_bmc::monitor_bmc;
+    use crate::test::{load_iterator::LoadTestSuite, POWER_LOW};
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+
+    fn quiet_params() -> SimulationParams {
+        SimulationParams {
+            idle_watts: 200.0,
+            max_watts: 600.0,
+            online_cpus: 10,
+            correction_time: Duration::from_millis(1000),
+            sampling_period: Duration::from_millis(100),
+            noise_watts: 0.0,
+            seed: 0,
+        }
+    }
+
+    fn workload(load_pct: u64, n_threads: u64) -> FirestarterParams {
+        FirestarterParams { runtime_secs: 10, load_pct, load_period_us: 10_000, n_threads }
+    }
+
+    #[test]
+    fn test_power_follows_load() {
+        let t0 = Instant::now();
+        let mut model = PowerModel::new(quiet_params(), t0);
+        assert!((model.power_at(t0) - 200.0).abs() < f64::EPSILON);
+
+        model.set_workload(&workload(100, 0), t0);
+        assert!((model.power_at(t0) - 600.0).abs() < f64::EPSILON);
+
+        model.set_workload(&workload(50, 5), t0);
+        assert!((model.power_at(t0) - 300.0).abs() < f64::EPSILON);
+
+        // back to idle once the runtime has elapsed
+        assert!((model.power_at(t0 + Duration::from_secs(11)) - 200.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_cap_ramps_over_correction_time() {
+        let t0 = Instant::now();
+        let mut model = PowerModel::new(quiet_params(), t0);
+        model.set_workload(&workload(100, 0), t0);
+        model.set_power_limit(400, t0);
+        model.set_active(true, t0);
+
+        assert!((model.power_at(t0) - 600.0).abs() < f64::EPSILON);
+        assert!((model.power_at(t0 + Duration::from_millis(500)) - 500.0).abs() < 1e-6);
+        assert!((model.power_at(t0 + Duration::from_millis(1000)) - 400.0).abs() < f64::EPSILON);
+
+        // removing the cap releases the power immediately
+        let t1 = t0 + Duration::from_secs(2);
+        model.set_active(false, t1);
+        assert!((model.power_at(t1) - 600.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_reading_is_sampled_with_bounded_noise() {
+        let t0 = Instant::now();
+        let params = SimulationParams { noise_watts: 5.0, ..quiet_params() };
+        let mut model = PowerModel::new(params, t0);
+        model.set_workload(&workload(100, 0), t0);
+
+        let first = model.reading_at(t0 + Duration::from_millis(10));
+        assert_eq!(first, model.reading_at(t0 + Duration::from_millis(90)));
+        for ms in (0..5000).step_by(100) {
+            let reading = model.reading_at(t0 + Duration::from_millis(ms));
+            assert!((595..=605).contains(&reading), "{reading}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulated_campaign_produces_bmc_stats() {
+        let sim = Arc::new(SimulatedBMC::new(quiet_params()));
+        let controller: Arc<dyn PowerCapController> = sim.clone();
+
+        let test = LoadTestSuite::new().find(|test| test.cap_to == POWER_LOW).unwrap();
+        controller.set_correction_time(Duration::from_millis(300)).await.unwrap();
+        assert_eq!(controller.current_cap_settings().await.unwrap().correction_time, Some(Duration::from_millis(300)));
+        sim.set_workload(&workload(test.load_pct, test.n_threads));
+        controller.set_cap_power_level(test.cap_to).await.unwrap();
+        controller.activate_power_cap().await.unwrap();
+
+        let cancel = CancellationToken::new();
+        let monitor = tokio::spawn(monitor_bmc(controller.clone(), Duration::from_millis(1000), cancel.clone()));
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        cancel.cancel();
+
+        let stats = monitor.await.unwrap().samples;
+        assert!(stats.len() >= 2);
+        assert!(stats.iter().all(|s| s.cap_is_active && s.cap_level == test.cap_to));
+        assert_eq!(stats.last().unwrap().power, test.cap_to);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::bmc::bmc::BMC_CapSetting;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use chrono::{DateTime, Utc};
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// The cap settings of a node before a campaign touched them, so they can be put back
+/// afterwards - whether the campaign ends normally or not.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapSnapshot {
+    pub bmc_hostname: String,
+    pub timestamp: DateTime<Utc>,
+    pub settings: BMC_CapSetting,
+}
+
+impl CapSnapshot {
+    /// # Errors
+    /// If the cap settings can't be read
+    pub async fn take(controller: &dyn PowerCapController, bmc_hostname: &str) -> BMCResult<Self> {
+        let settings = controller.current_cap_settings().await?;
+        info!("Cap settings snapshot of {bmc_hostname}: {settings:?}");
+        Ok(Self {
+            bmc_hostname: String::from(bmc_hostname),
+            timestamp: Utc::now(),
+            settings,
+        })
+    }
+
+    /// # Errors
+    /// If the file can't be written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// # Errors
+    /// If the file can't be read or isn't a snapshot
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Puts the saved settings back. Every setting is attempted even if an earlier one
+    /// fails, as a node left half restored is better than one not restored at all.
+    ///
+    /// # Errors
+    /// The first setting that couldn't be restored
+    pub async fn restore(&self, controller: &dyn PowerCapController) -> BMCResult<()> {
+        let settings = &self.settings;
+        let mut results = Vec::new();
+
+        // Some BMCs reject a zero limit, which is what they report when none was ever set
+        if settings.power_limit > 0 {
+            results.push(controller.set_cap_power_level(settings.power_limit).await);
+        }
+        if let Some(correction_time) = settings.correction_time {
+            results.push(optional(controller.set_correction_time(correction_time).await));
+        }
+        if let Some(sampling_period) = settings.sampling_period {
+            results.push(optional(controller.set_sampling_period(sampling_period).await));
+        }
+        if let Some(action) = settings.exception_action {
+            results.push(optional(controller.set_exception_action(action).await));
+        }
+        results.push(if settings.is_active {
+            controller.activate_power_cap().await
+        } else {
+            controller.deactivate_power_cap().await
+        });
+
+        for e in results.iter().filter_map(|result| result.as_ref().err()) {
+            error!("Failed to restore cap settings of {}: {e}", self.bmc_hostname);
+        }
+        results.into_iter().collect()
+    }
+}
+
+/// Settings the mechanism can't change can't have been changed by the campaign either
+fn optional(result: BMCResult<()>) -> BMCResult<()> {
+    match result {
+        Err(BMCError::Unsupported(e)) => {
+            warn!("Not restored: {e}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::bmc::ExceptionAction;
+    use crate::bmc::simulated::SimulatedBMC;
+    use crate::test_util::TempDir;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_restore_after_campaign() {
+        let sim = SimulatedBMC::default();
+        let controller: &dyn PowerCapController = &sim;
+        controller.set_cap_power_level(1600).await.unwrap();
+        let snapshot = CapSnapshot::take(controller, "node").await.unwrap();
+
+        controller.set_cap_power_level(230).await.unwrap();
+        controller.set_correction_time(Duration::from_millis(6000)).await.unwrap();
+        controller.set_exception_action(ExceptionAction::HardPowerOff).await.unwrap();
+        controller.activate_power_cap().await.unwrap();
+        assert_ne!(controller.current_cap_settings().await.unwrap(), snapshot.settings);
+
+        snapshot.restore(controller).await.unwrap();
+        assert_eq!(controller.current_cap_settings().await.unwrap(), snapshot.settings);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let snapshot = CapSnapshot::take(&SimulatedBMC::default(), "node").await.unwrap();
+        let dir = TempDir::new("snapshot");
+        let path = dir.join("cap_snapshot.json");
+        snapshot.save(&path).unwrap();
+        assert_eq!(CapSnapshot::load(&path).unwrap(), snapshot);
+    }
+}
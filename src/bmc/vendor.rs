@@ -0,0 +1,152 @@
+use crate::bmc::error::{BMCError, BMCResult};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+/// IANA enterprise numbers, as reported in the Manufacturer ID of `mc info`
+const IANA_HP: u32 = 11;
+const IANA_DELL: u32 = 674;
+const IANA_SUPERMICRO: u32 = 10876;
+const IANA_LENOVO: u32 = 19046;
+const IANA_HPE: u32 = 47196;
+
+/// The BMC makers a parsing profile can be picked for. Until output captured from their
+/// BMCs shows a difference, they all read ipmitool's output the same way.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Vendor {
+    /// Output as documented by ipmitool
+    Generic,
+    Dell,
+    Hpe,
+    Lenovo,
+    Supermicro,
+}
+
+/// How to read one vendor's ipmitool DCMI output
+#[derive(Debug, PartialEq, Eq)]
+pub struct VendorProfile {
+    pub vendor: Vendor,
+    /// Lowercase "Current Limit State" values meaning the limit is enforced
+    pub active_states: &'static [&'static str],
+    /// Formats of the "IPMI timestamp" value, tried in order
+    pub timestamp_formats: &'static [&'static str],
+}
+
+/// ipmitool builds differ in how they print the BMC time, whoever made the BMC
+const TIMESTAMP_FORMATS: &[&str] = &["%a %b %e %H:%M:%S %Y", "%m/%d/%Y %H:%M:%S"];
+
+static GENERIC: VendorProfile = VendorProfile {
+    vendor: Vendor::Generic,
+    active_states: &["power limit active"],
+    timestamp_formats: TIMESTAMP_FORMATS,
+};
+
+static DELL: VendorProfile = VendorProfile {
+    vendor: Vendor::Dell,
+    active_states: &["power limit active"],
+    timestamp_formats: TIMESTAMP_FORMATS,
+};
+
+static HPE: VendorProfile = VendorProfile {
+    vendor: Vendor::Hpe,
+    active_states: &["power limit active"],
+    timestamp_formats: TIMESTAMP_FORMATS,
+};
+
+static LENOVO: VendorProfile = VendorProfile {
+    vendor: Vendor::Lenovo,
+    active_states: &["power limit active"],
+    timestamp_formats: TIMESTAMP_FORMATS,
+};
+
+static SUPERMICRO: VendorProfile = VendorProfile {
+    vendor: Vendor::Supermicro,
+    active_states: &["power limit active"],
+    timestamp_formats: TIMESTAMP_FORMATS,
+};
+
+impl Vendor {
+    #[must_use]
+    pub fn from_manufacturer_id(id: u32) -> Self {
+        match id {
+            IANA_DELL => Vendor::Dell,
+            IANA_HP | IANA_HPE => Vendor::Hpe,
+            IANA_LENOVO => Vendor::Lenovo,
+            IANA_SUPERMICRO => Vendor::Supermicro,
+            _ => Vendor::Generic,
+        }
+    }
+
+    #[must_use]
+    pub fn profile(self) -> &'static VendorProfile {
+        match self {
+            Vendor::Generic => &GENERIC,
+            Vendor::Dell => &DELL,
+            Vendor::Hpe => &HPE,
+            Vendor::Lenovo => &LENOVO,
+            Vendor::Supermicro => &SUPERMICRO,
+        }
+    }
+}
+
+impl VendorProfile {
+    /// Whether a "Current Limit State" value means the limit is enforced
+    #[must_use]
+    pub fn is_limit_active(&self, state: &str) -> bool {
+        self.active_states.contains(&state.trim().to_lowercase().as_str())
+    }
+
+    /// Parses an "IPMI timestamp" value, local time without timezone
+    ///
+    /// # Errors
+    /// If the timestamp is in none of the profile's formats
+    pub fn parse_timestamp(&self, text: &str) -> BMCResult<NaiveDateTime> {
+        let text = text.trim();
+        self.timestamp_formats
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+            .ok_or_else(|| BMCError::Parse(format!("BMC timestamp '{text}'")))
+    }
+}
+
+/// The Manufacturer ID from the output of `ipmitool mc info`
+#[must_use]
+pub fn parse_manufacturer_id(mc_info: &str) -> Option<u32> {
+    mc_info.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim() != "Manufacturer ID" {
+            return None;
+        }
+        value.split_ascii_whitespace().next()?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendor_from_mc_info() {
+        let mc_info = "
+Device ID                 : 32
+Device Revision           : 1
+Firmware Revision         : 7.00
+IPMI Version              : 2.0
+Manufacturer ID           : 674
+Manufacturer Name         : DELL Inc
+Product ID                : 256 (0x0100)
+";
+        assert_eq!(parse_manufacturer_id(mc_info), Some(IANA_DELL));
+        assert_eq!(Vendor::from_manufacturer_id(IANA_DELL), Vendor::Dell);
+        assert_eq!(Vendor::from_manufacturer_id(IANA_HP), Vendor::Hpe);
+        assert_eq!(Vendor::from_manufacturer_id(7154), Vendor::Generic);
+        assert_eq!(parse_manufacturer_id("Error: Unable to establish IPMI v2 / RMCP+ session"), None);
+    }
+
+    #[test]
+    fn test_timestamp_formats() {
+        let profile = Vendor::Generic.profile();
+        let expected = profile.parse_timestamp("Tue May  9 14:24:36 2023").unwrap();
+        assert_eq!(profile.parse_timestamp("05/09/2023 14:24:36").unwrap(), expected);
+        assert!(profile.parse_timestamp("yesterday").is_err());
+    }
+}
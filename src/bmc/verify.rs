@@ -0,0 +1,255 @@
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading, ExceptionAction};
+use crate::bmc::capabilities::CapCapabilities;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::BMCResult;
+use crate::bmc::node_manager::{NmDomain, NmPolicy, NmStatistics};
+use crate::bmc::sel::SelEntry;
+use crate::bmc::sensors::SensorReading;
+use crate::model::FirestarterParams;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::time::{sleep, Duration};
+
+const VERIFY_POLL_INTERVAL_MILLIS: u64 = 200;
+
+/// A change to the cap, as requested from the BMC
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CapCommand {
+    SetLevel(u64),
+    Activate,
+    Deactivate,
+}
+
+impl CapCommand {
+    async fn send(self, controller: &dyn PowerCapController) -> BMCResult<()> {
+        match self {
+            CapCommand::SetLevel(cap) => controller.set_cap_power_level(cap).await,
+            CapCommand::Activate => controller.activate_power_cap().await,
+            CapCommand::Deactivate => controller.deactivate_power_cap().await,
+        }
+    }
+
+    /// Whether the BMC reports the state this command asked for
+    #[must_use]
+    pub fn is_reflected_by(&self, settings: &BMC_CapSetting) -> bool {
+        match self {
+            CapCommand::SetLevel(cap) => settings.power_limit == *cap,
+            CapCommand::Activate => settings.is_active,
+            CapCommand::Deactivate => !settings.is_active,
+        }
+    }
+}
+
+/// How long the BMC took to report a cap change as done. This is acceptance by the
+/// BMC only: whether the power actually followed is in the `BMCStats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapAcceptance {
+    pub command: CapCommand,
+    /// When the command was sent
+    pub requested: DateTime<Utc>,
+    /// Time taken by the command itself
+    pub command_millis: u64,
+    /// From sending the command to the first read-back showing the new state,
+    /// `None` if that didn't happen before the timeout
+    pub accepted_millis: Option<u64>,
+    /// Number of `current_cap_settings` reads made
+    pub attempts: u32,
+}
+
+/// Sends `command` then polls the cap settings until they reflect it or `timeout` expires.
+/// Not seeing the change in time isn't an error - it's what's being measured.
+///
+/// # Errors
+/// If the command fails, or a read-back fails with an error that won't go away
+pub async fn apply_verified(controller: &dyn PowerCapController, command: CapCommand, timeout: Duration) -> BMCResult<CapAcceptance> {
+    let requested = Utc::now();
+    let start = Instant::now();
+    command.send(controller).await?;
+    let command_millis = millis(start);
+
+    let mut attempts = 0;
+    let accepted_millis = loop {
+        attempts += 1;
+        match controller.current_cap_settings().await {
+            Ok(settings) if command.is_reflected_by(&settings) => break Some(millis(start)),
+            Ok(settings) => trace!("{command:?} not reflected yet: {settings:?}"),
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => warn!("Failed to read back cap settings: {e}"),
+        }
+        if start.elapsed() >= timeout {
+            warn!("BMC didn't reflect {command:?} within {}ms", timeout.as_millis());
+            break None;
+        }
+        sleep(Duration::from_millis(VERIFY_POLL_INTERVAL_MILLIS)).await;
+    };
+
+    Ok(CapAcceptance { command, requested, command_millis, accepted_millis, attempts })
+}
+
+fn millis(since: Instant) -> u64 {
+    u64::try_from(since.elapsed().as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Wraps a controller so that every cap change is followed by a read-back,
+/// keeping the acceptance of each change until it's collected.
+#[derive(Debug)]
+pub struct VerifiedController {
+    inner: Arc<dyn PowerCapController>,
+    timeout: Duration,
+    acceptances: Mutex<Vec<CapAcceptance>>,
+}
+
+impl VerifiedController {
+    #[must_use]
+    pub fn new(inner: Arc<dyn PowerCapController>, timeout: Duration) -> Self {
+        Self { inner, timeout, acceptances: Mutex::new(Vec::new()) }
+    }
+
+    async fn apply(&self, command: CapCommand) -> BMCResult<()> {
+        let acceptance = apply_verified(self.inner.as_ref(), command, self.timeout).await?;
+        trace!("{acceptance:?}");
+        self.acceptances.lock().expect("Cap acceptances poisoned").push(acceptance);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PowerCapController for VerifiedController {
+    async fn current_power(&self) -> BMCResult<u64> {
+        self.inner.current_power().await
+    }
+
+    async fn current_power_reading(&self) -> BMCResult<BMC_PowerReading> {
+        self.inner.current_power_reading().await
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        self.inner.enhanced_power_periods().await
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        self.inner.enhanced_power_reading(period).await
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        self.inner.current_cap_settings().await
+    }
+
+    async fn cap_capabilities(&self) -> BMCResult<CapCapabilities> {
+        self.inner.cap_capabilities().await
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        self.apply(CapCommand::SetLevel(cap)).await
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        self.apply(CapCommand::Activate).await
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.apply(CapCommand::Deactivate).await
+    }
+
+    async fn set_correction_time(&self, correction_time: Duration) -> BMCResult<()> {
+        self.inner.set_correction_time(correction_time).await
+    }
+
+    async fn set_sampling_period(&self, sampling_period: Duration) -> BMCResult<()> {
+        self.inner.set_sampling_period(sampling_period).await
+    }
+
+    async fn set_exception_action(&self, action: ExceptionAction) -> BMCResult<()> {
+        self.inner.set_exception_action(action).await
+    }
+
+    async fn sensor_readings(&self) -> BMCResult<Vec<SensorReading>> {
+        self.inner.sensor_readings().await
+    }
+
+    async fn sel_entries(&self) -> BMCResult<Vec<SelEntry>> {
+        self.inner.sel_entries().await
+    }
+
+    async fn nm_set_policy(&self, policy: &NmPolicy) -> BMCResult<()> {
+        self.inner.nm_set_policy(policy).await
+    }
+
+    async fn nm_remove_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<()> {
+        self.inner.nm_remove_policy(domain, policy_id).await
+    }
+
+    async fn nm_set_policy_enabled(&self, domain: NmDomain, policy_id: u8, enabled: bool) -> BMCResult<()> {
+        self.inner.nm_set_policy_enabled(domain, policy_id, enabled).await
+    }
+
+    async fn nm_policy(&self, domain: NmDomain, policy_id: u8) -> BMCResult<NmPolicy> {
+        self.inner.nm_policy(domain, policy_id).await
+    }
+
+    async fn nm_statistics(&self, domain: NmDomain, policy_id: Option<u8>) -> BMCResult<NmStatistics> {
+        self.inner.nm_statistics(domain, policy_id).await
+    }
+
+    async fn nm_capabilities(&self, domain: NmDomain) -> BMCResult<CapCapabilities> {
+        self.inner.nm_capabilities(domain).await
+    }
+
+    async fn set_workload(&self, params: &FirestarterParams) {
+        self.inner.set_workload(params).await;
+    }
+
+    fn take_cap_acceptances(&self) -> Vec<CapAcceptance> {
+        std::mem::take(&mut *self.acceptances.lock().expect("Cap acceptances poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmc::error::BMCError;
+    use crate::test_util::StubController;
+
+    #[tokio::test]
+    async fn test_acceptance_is_measured() {
+        let lagging = StubController::new(0).with_settings_lag(2);
+        let acceptance = apply_verified(&lagging, CapCommand::SetLevel(230), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(acceptance.command, CapCommand::SetLevel(230));
+        assert_eq!(acceptance.attempts, 3);
+        assert!(acceptance.accepted_millis.unwrap() >= 2 * VERIFY_POLL_INTERVAL_MILLIS);
+
+        let acceptance = apply_verified(&lagging, CapCommand::Activate, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(acceptance.attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_and_fatal_errors() {
+        let lagging = StubController::new(0).with_settings_lag(100);
+        let acceptance = apply_verified(&lagging, CapCommand::Activate, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(acceptance.accepted_millis, None);
+        assert!(acceptance.attempts >= 2);
+
+        // an error that won't go away isn't waited out
+        let locked_out = StubController::new(0).locked_out();
+        let result = apply_verified(&locked_out, CapCommand::Activate, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(BMCError::Authentication(_))));
+    }
+
+    #[tokio::test]
+    async fn test_verified_controller_collects_acceptances() {
+        let controller = VerifiedController::new(Arc::new(StubController::new(0)), Duration::from_secs(1));
+        controller.set_cap_power_level(400).await.unwrap();
+        controller.activate_power_cap().await.unwrap();
+        controller.deactivate_power_cap().await.unwrap();
+
+        let acceptances = controller.take_cap_acceptances();
+        let commands: Vec<CapCommand> = acceptances.iter().map(|a| a.command).collect();
+        assert_eq!(commands, [CapCommand::SetLevel(400), CapCommand::Activate, CapCommand::Deactivate]);
+        assert!(acceptances.iter().all(|a| a.attempts == 1 && a.accepted_millis.is_some()));
+        assert!(controller.take_cap_acceptances().is_empty());
+    }
+}
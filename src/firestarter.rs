@@ -1,11 +1,20 @@
-use log::{error, trace};
+use log::{error, trace, warn};
 use std::fmt::{self, Display, Formatter};
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use lazy_static::lazy_static;
 use crate::model::FirestarterParams;
 
 
-// TODO: Move firestarter path to CLI
-const FIRESTARTER_PATH: &str = "/home_nfs/wainj/local/bin/firestarter";
+pub const FIRESTARTER_PATH: &str = "/home_nfs/wainj/local/bin/firestarter";
+const FIRESTARTER_POLL_MILLIS: u64 = 100;
+
+lazy_static! {
+    // The firestarter currently running, so that it can be stopped from another request
+    static ref RUNNING: Mutex<Option<Child>> = Mutex::new(None);
+}
 
 #[derive(Debug)]
 /// Hold the firestarter configuration
@@ -33,6 +42,13 @@ impl Firestarter {
         }
     }
 
+    /// Runs the firestarter at `path` rather than the default one
+    #[must_use]
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = String::from(path);
+        self
+    }
+
     /// Launches firestarter. This is done on a separate thread.
     // TODO: Might be pertinent to bind threads to processors to see if there's
     //       uneven capping across domains.
@@ -50,11 +66,39 @@ impl Firestarter {
             .arg(self.n_threads.to_string())
             .spawn()
             .expect("Firestarter failed to launch");
+        *RUNNING.lock().expect("Firestarter lock poisoned") = Some(firestarter);
+
+        // Poll rather than wait, so that stop() can get at the child in the meantime
+        loop {
+            let mut running = RUNNING.lock().expect("Firestarter lock poisoned");
+            let Some(firestarter) = running.as_mut() else { break };
+            match firestarter.try_wait() {
+                Ok(Some(status)) => {
+                    trace!("FIRESTARTER exited: {status}");
+                    *running = None;
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("FIRESTARTER failed: {e:?}");
+                    *running = None;
+                    break;
+                }
+            }
+            drop(running);
+            thread::sleep(Duration::from_millis(FIRESTARTER_POLL_MILLIS));
+        }
+    }
 
-        match firestarter.wait_with_output() {
-            Ok(_) => trace!("FIRESTARTER exited successfully"),
-            Err(e) => error!("FIRESTARTER failed: {e:?}"),
+    /// Kills the running firestarter, if any. Returns whether there was one to stop.
+    pub fn stop() -> bool {
+        let mut running = RUNNING.lock().expect("Firestarter lock poisoned");
+        let Some(firestarter) = running.as_mut() else { return false };
+        warn!("FIRESTARTER stopping pid {}", firestarter.id());
+        if let Err(e) = firestarter.kill() {
+            error!("FIRESTARTER failed to stop: {e:?}");
         }
+        true
     }
 }
 
@@ -0,0 +1,77 @@
+use axum::{extract::{Path, State}, response::{IntoResponse, Response}, http::StatusCode, Json};
+use crate::bmc::agent_proxy::status_code;
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::bmc::ExceptionAction;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::model::BmcCapOperation;
+use crate::route::BmcState;
+use log::{error, trace};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The BMC of the agent's host, reached in-band
+type InBandBMC = State<Arc<dyn PowerCapController>>;
+
+/// The result as JSON, or the error message with a status telling the kind of error
+fn respond<T: Serialize>(result: BMCResult<T>) -> Response {
+    match result {
+        Ok(value) => (StatusCode::OK, Json(value)).into_response(),
+        Err(e) => {
+            error!("In-band BMC: {e}");
+            (status_code(&e), e.to_string()).into_response()
+        }
+    }
+}
+
+pub async fn bmc_power_reading_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_power_reading_handler()");
+    respond(bmc.current_power_reading().await)
+}
+
+pub async fn bmc_enhanced_periods_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_enhanced_periods_handler()");
+    respond(bmc.enhanced_power_periods().await)
+}
+
+pub async fn bmc_enhanced_reading_handler(State(bmc): InBandBMC, Path(period_secs): Path<u64>) -> Response {
+    trace!("bmc_enhanced_reading_handler({period_secs})");
+    respond(bmc.enhanced_power_reading(Duration::from_secs(period_secs)).await)
+}
+
+pub async fn bmc_cap_settings_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_cap_settings_handler()");
+    respond(bmc.current_cap_settings().await)
+}
+
+pub async fn bmc_capabilities_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_capabilities_handler()");
+    respond(bmc.cap_capabilities().await)
+}
+
+pub async fn bmc_cap_handler(State(state): State<BmcState>, Json(operation): Json<BmcCapOperation>) -> Response {
+    trace!("bmc_cap_handler({operation:?})");
+    let bmc = state.bmc;
+    let result = match operation {
+        BmcCapOperation::SetPowerLevel(cap) => bmc.set_cap_power_level(cap).await,
+        BmcCapOperation::Activate => bmc.activate_power_cap().await,
+        BmcCapOperation::Deactivate => bmc.deactivate_power_cap().await,
+        BmcCapOperation::SetCorrectionTime(correction_time) => bmc.set_correction_time(correction_time).await,
+        BmcCapOperation::SetSamplingPeriod(sampling_period) => bmc.set_sampling_period(sampling_period).await,
+        BmcCapOperation::SetExceptionAction(ExceptionAction::HardPowerOff) if !state.allow_hard_power_off => Err(BMCError::Unsafe(
+            String::from("the agent won't let its host be powered off by the BMC unless started with --allow-hard-power-off"),
+        )),
+        BmcCapOperation::SetExceptionAction(action) => bmc.set_exception_action(action).await,
+    };
+    respond(result)
+}
+
+pub async fn bmc_sensors_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_sensors_handler()");
+    respond(bmc.sensor_readings().await)
+}
+
+pub async fn bmc_sel_handler(State(bmc): InBandBMC) -> Response {
+    trace!("bmc_sel_handler()");
+    respond(bmc.sel_entries().await)
+}
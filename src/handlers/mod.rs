@@ -1,3 +1,6 @@
 pub mod run_test_handler;
+pub mod stop_test_handler;
 pub mod system_info_handler;
 pub mod fallback_handler;
+pub mod bmc_handler;
+pub mod rapl_limit_handler;
@@ -0,0 +1,85 @@
+use axum::{extract::State, response::{IntoResponse, Response}, http::StatusCode, Json};
+use crate::model::RaplLimitOperation;
+use crate::rapl::power_limit::RaplLimitsSnapshot;
+use crate::rapl::rapl::RAPL;
+use crate::route::AgentState;
+use log::{error, info, trace};
+use serde::Serialize;
+use std::fs;
+use std::io;
+
+/// The result as JSON, or the error message with a status the client turns back into
+/// the matching `BMCError`, see `AgentProxyBMC`
+fn respond<T: Serialize>(result: io::Result<T>) -> Response {
+    match result {
+        Ok(value) => (StatusCode::OK, Json(value)).into_response(),
+        Err(e) => {
+            error!("RAPL limits: {e}");
+            (status_code(&e), e.to_string()).into_response()
+        }
+    }
+}
+
+/// A missing zone or constraint can't be capped, writing the limits needs root, and
+/// the kernel rejects values out of the zone's range
+fn status_code(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::InvalidInput => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn rapl_limits_handler(State(state): State<AgentState>) -> Response {
+    trace!("rapl_limits_handler()");
+    respond(RAPL::from_source(&state.rapl).power_limits())
+}
+
+/// Changes a limit, first saving every limit as it was before the campaign touched any.
+/// No limit is changed unless they're saved.
+pub async fn rapl_limit_handler(State(state): State<AgentState>, Json(operation): Json<RaplLimitOperation>) -> Response {
+    trace!("rapl_limit_handler({operation:?})");
+    let rapl = RAPL::from_source(&state.rapl);
+    let mut original_limits = state.rapl_original_limits.lock().expect("RAPL original limits poisoned");
+    if original_limits.is_none() {
+        let saved = rapl.power_limits().and_then(|limits| {
+            if let Some(path) = &state.rapl_limits_file {
+                RaplLimitsSnapshot::new(limits.clone()).save(path)?;
+                info!("RAPL limits saved to {}", path.display());
+            }
+            Ok(limits)
+        });
+        match saved {
+            Ok(limits) => *original_limits = Some(limits),
+            Err(e) => return respond::<()>(Err(e)),
+        }
+    }
+    respond(rapl.apply_limit(&operation))
+}
+
+/// Puts back the limits saved before the first change, answering whether there were any
+pub async fn rapl_restore_handler(State(state): State<AgentState>) -> Response {
+    trace!("rapl_restore_handler()");
+    let mut original_limits = state.rapl_original_limits.lock().expect("RAPL original limits poisoned");
+    let Some(limits) = original_limits.as_ref() else {
+        return respond(Ok(false));
+    };
+    let result = RAPL::from_source(&state.rapl).restore_limits(limits);
+    if result.is_ok() {
+        info!("Restored the RAPL limits {limits:?}");
+        *original_limits = None;
+        if let Some(path) = &state.rapl_limits_file {
+            if let Err(e) = fs::remove_file(path.as_path()) {
+                error!("Failed to remove the restored RAPL limits {}: {e}", path.display());
+            }
+        }
+    }
+    respond(result.map(|()| true))
+}
+
+/// The limits and throttling counters of packages read through the msr devices
+pub async fn rapl_msr_status_handler(State(state): State<AgentState>) -> Response {
+    trace!("rapl_msr_status_handler()");
+    respond(RAPL::from_source(&state.rapl).msr_status())
+}
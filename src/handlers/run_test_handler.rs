@@ -1,7 +1,8 @@
-use axum::{Json, response::IntoResponse, http::StatusCode};
+use axum::{extract::State, Json, response::IntoResponse, http::StatusCode};
 use crate::model::FirestarterParams;
 use crate::firestarter::Firestarter;
 use crate::rapl::monitor_rapl::monitor_rapl;
+use crate::route::AgentState;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -9,15 +10,16 @@ use log::trace;
 
 const RAPL_END_DELAY_SECS: u64 = 1;
 
-pub async fn run_test_handler(Json(firestarter_params): Json<FirestarterParams>) -> impl IntoResponse {
+pub async fn run_test_handler(State(state): State<AgentState>, Json(firestarter_params): Json<FirestarterParams>) -> impl IntoResponse {
     trace!("run_test_handler({firestarter_params:?})");
     // start rapl monitor
     let (rapl_tx, rapl_rx) = mpsc::channel();
-    let rapl_thread = thread::spawn(move || monitor_rapl(&rapl_rx, firestarter_params.runtime_secs + RAPL_END_DELAY_SECS));
+    let source = state.rapl.clone();
+    let rapl_thread = thread::spawn(move || monitor_rapl(&rapl_rx, firestarter_params.runtime_secs + RAPL_END_DELAY_SECS, &source));
     trace!("Launching firestarter");
 
     // start firestarter
-    let firestarter = Firestarter::new(firestarter_params);
+    let firestarter = Firestarter::new(firestarter_params).with_path(&state.firestarter);
     firestarter.run();
     trace!("Firestarter finished, signalling rapl monitor");
     thread::sleep(Duration::from_secs(RAPL_END_DELAY_SECS));
@@ -31,3 +33,42 @@ pub async fn run_test_handler(Json(firestarter_params): Json<FirestarterParams>)
     println!("RAPL stats: {rapl_stats:?}");
     (StatusCode::OK, Json(rapl_stats))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::RaplRecord;
+    use crate::rapl::powercap_fake::FakePowercap;
+    use crate::rapl::rapl::RaplSource;
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_test_reads_rapl() {
+        // The counters are left where they are, so the power doesn't depend on when the
+        // monitor reads them. The conversion is tested against advancing counters with
+        // the monitor.
+        let fake = FakePowercap::new("run_test");
+        let package = fake.add_zone("amd-rapl", 0, "package-0", 120.0, 65_532_610_987).unwrap();
+        fake.add_subzone(&package, 0, "core", 80.0, 65_532_610_987).unwrap();
+        fake.advance(Duration::from_secs(1)).unwrap();
+
+        // A firestarter that's done at once, leaving the RAPL monitor the end delay
+        let state = AgentState {
+            rapl: Arc::new(RaplSource::Powercap(fake.config(&["intel-rapl", "amd-rapl"]))),
+            firestarter: Arc::new(String::from("true")),
+            ..AgentState::default()
+        };
+        let params = FirestarterParams { runtime_secs: 1, load_pct: 100, load_period_us: 0, n_threads: 1 };
+        let response = run_test_handler(State(state), Json(params)).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let records: Vec<RaplRecord> = serde_json::from_slice(&body).unwrap();
+        assert!(!records.is_empty());
+        for record in &records {
+            let domains: Vec<String> = record.data.iter().map(|data| data.domain.to_string()).collect();
+            assert_eq!(domains, ["package-0", "core-0"]);
+            assert!(record.data.iter().all(|data| data.power_watts == 0.0 && data.energy_uj == 0), "{record:?}");
+        }
+    }
+}
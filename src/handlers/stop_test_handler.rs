@@ -0,0 +1,11 @@
+use axum::{Json, response::IntoResponse, http::StatusCode};
+use crate::firestarter::Firestarter;
+use log::trace;
+
+/// Stops the load started by `run_test_handler`. The pending `run_test` request then
+/// returns with the RAPL stats collected so far.
+pub async fn stop_test_handler() -> impl IntoResponse {
+    trace!("stop_test_handler()");
+    let stopped = Firestarter::stop();
+    (StatusCode::OK, Json(stopped))
+}
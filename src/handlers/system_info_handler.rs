@@ -1,3 +1,5 @@
+#![allow(clippy::empty_line_after_outer_attr)]
+
 use std::process::Command;
 use serde_json::{self, Value};
 use crate::am_root;
@@ -1,3 +1,4 @@
+
 pub mod handlers;
 pub mod model;
 pub mod route;
@@ -6,11 +7,21 @@ pub mod rapl;
 pub mod firestarter;
 pub mod bmc;
 pub mod test;
+#[cfg(test)]
+pub(crate) mod test_util;
 
+use std::io::{self, IsTerminal, Write};
 use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
 use clap::Parser;
 use chrono::{DateTime, Utc, Local};
 use lazy_static::lazy_static;
+use bmc::bmc::ExceptionAction;
+use bmc::controller::ControllerType;
+use bmc::credentials::{CredentialProvider, EnvPassword, PasswordFile, StdinPassword, IPMI_PASSWORD_ENV};
+use bmc::vendor::Vendor;
+use test::CapMechanism;
 
 pub type Timestamps = (DateTime<Utc>, DateTime<Utc>, DateTime<Utc>);
 
@@ -28,6 +39,7 @@ const SETUP_PAUSE_MILLIS: u64 = 300;
 // const AGENT_RUN_TEST_ENDPOINT: &str = "http://oahu10000:8000/api/run_test";
 const AGENT_INFO_ENDPOINT: &str = "/api/system_info";
 const AGENT_RUN_TEST_ENDPOINT: &str = "/api/run_test";
+const AGENT_STOP_TEST_ENDPOINT: &str = "/api/stop_test";
 
 // Move this to the CLI?
 const CAP_STEP_SIZE_WATTS: u64 = 100;
@@ -54,9 +66,20 @@ lazy_static! {
 
 #[derive(Debug)]
 pub struct Configuration {
+    /// Empty for `restore` when not given, the snapshot tells the host
     pub bmc_hostname: String,
+    /// Empty for `restore` when not given
     pub bmc_username: String,
-    pub bmc_password: String,
+    pub bmc_credentials: Arc<dyn CredentialProvider>,
+    pub bmc_type: ControllerType,
+    pub bmc_vendor: Option<Vendor>,
+    pub verify_cap: bool,
+    pub allow_hard_power_off: bool,
+    pub campaign_exception_action: Option<ExceptionAction>,
+    pub cap_mechanisms: Vec<CapMechanism>,
+    pub verify_timeout_millis: u64,
+    pub bmc_poll_interval_millis: u64,
+    pub sensor_poll_interval_millis: u64,
     pub warmup_secs: u64,
     pub test_time_secs: u64,
     pub cap_low_watts: u64,
@@ -68,19 +91,32 @@ pub struct Configuration {
     // pub firestarter: String,
     pub ipmi: String,
     pub setup_pause_millis: u64,
+    /// Empty for `restore` when not given, leaving the agent alone
+    pub agent_url: String,
     pub agent_info_endpoint: String,
     pub agent_run_test_endpoint: String,
+    pub agent_stop_test_endpoint: String,
+    pub restore_snapshot: Option<String>,
 }
 
 impl Configuration {
     fn new() -> Self {
         let args = CLI::parse();
-        let agent = args.agent;
+        let agent = args.agent.unwrap_or_default();
 
         Configuration {
-            bmc_hostname: args.bmc_hostname,
-            bmc_username: args.bmc_username,
-            bmc_password: args.bmc_password,
+            bmc_hostname: args.bmc_hostname.unwrap_or_default(),
+            bmc_username: args.bmc_username.unwrap_or_default(),
+            bmc_credentials: credentials(args.password_stdin, args.password_env, args.password_file),
+            bmc_type: args.bmc_type,
+            bmc_vendor: args.bmc_vendor,
+            verify_cap: args.verify_cap,
+            allow_hard_power_off: args.allow_hard_power_off,
+            campaign_exception_action: args.campaign_exception_action,
+            cap_mechanisms: args.cap_mechanisms,
+            verify_timeout_millis: args.verify_timeout,
+            bmc_poll_interval_millis: args.bmc_poll_interval,
+            sensor_poll_interval_millis: args.sensor_poll_interval,
             warmup_secs: args.warmup,
             test_time_secs: args.test_time,
             cap_low_watts: args.cap_low_watts,
@@ -92,8 +128,13 @@ impl Configuration {
             // firestarter: args.firestarter,
             ipmi: args.ipmi,
             setup_pause_millis: SETUP_PAUSE_MILLIS,
+            agent_url: agent.clone(),
             agent_info_endpoint: format!("{agent}{AGENT_INFO_ENDPOINT}"),
             agent_run_test_endpoint: format!("{agent}{AGENT_RUN_TEST_ENDPOINT}"),
+            agent_stop_test_endpoint: format!("{agent}{AGENT_STOP_TEST_ENDPOINT}"),
+            restore_snapshot: args.command.map(|command| match command {
+                ClientCommand::Restore { snapshot } => snapshot,
+            }),
         }
     }
 
@@ -104,6 +145,22 @@ impl Configuration {
     }
 }
 
+/// The password source picked on the command line, `$IPMI_PASSWORD` by default
+fn credentials(password_stdin: bool, password_env: Option<String>, password_file: Option<PathBuf>) -> Arc<dyn CredentialProvider> {
+    match (password_stdin, password_env, password_file) {
+        (true, _, _) => {
+            let stdin = io::stdin();
+            if stdin.is_terminal() {
+                eprint!("BMC password: ");
+                let _ = io::stderr().flush();
+            }
+            Arc::new(StdinPassword::read(stdin.lock()).expect("Failed to read the BMC password"))
+        }
+        (false, _, Some(path)) => Arc::new(PasswordFile::new(&path)),
+        (false, var, None) => Arc::new(EnvPassword::new(var.as_deref().unwrap_or(IPMI_PASSWORD_ENV))),
+    }
+}
+
 /*
     >>> ATTENTION <<<
 
@@ -113,21 +170,114 @@ impl Configuration {
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Parser)]
-#[command(author, version, about, long_about=None)]
+#[command(author, version, about, long_about=None, subcommand_negates_reqs = true)]
 struct CLI {
-    // Passing default values here for the tests - to to deleted
-    #[arg(long, short = 'H', name = "host")]
-    bmc_hostname: String,
+    // Only `restore` can do without the host, user and agent
+    #[arg(long, short = 'H', name = "host", required = true, help = "BMC host. `restore` defaults to the one of the snapshot")]
+    bmc_hostname: Option<String>,
+
+    #[arg(long, short = 'U', name = "user", required = true)]
+    bmc_username: Option<String>,
+
+    #[arg(
+        long,
+        name = "password stdin",
+        conflicts_with_all = ["password env", "password file"],
+        help = "Read the BMC password from the first line of stdin"
+    )]
+    password_stdin: bool,
+
+    #[arg(
+        long,
+        name = "password env",
+        conflicts_with = "password file",
+        help = "Environment variable holding the BMC password [default: IPMI_PASSWORD]"
+    )]
+    password_env: Option<String>,
 
-    #[arg(long, short = 'U', name = "user")]
-    bmc_username: String,
+    #[arg(
+        long,
+        name = "password file",
+        help = "File whose first line is the BMC password"
+    )]
+    password_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ControllerType::Ipmi,
+        name = "bmc type",
+        help = "Capping mechanism used to drive the BMC"
+    )]
+    bmc_type: ControllerType,
 
-    #[arg(long, short = 'P', name = "password")]
-    bmc_password: String,
+    #[arg(
+        long,
+        value_enum,
+        name = "bmc vendor",
+        help = "Vendor profile to parse the ipmitool output with. Detected from the BMC manufacturer ID when not given"
+    )]
+    bmc_vendor: Option<Vendor>,
+
+    #[arg(
+        long,
+        help = "Read back every cap change until the BMC reports it, recording how long it took"
+    )]
+    verify_cap: bool,
+
+    #[arg(
+        long,
+        help = "Run the campaign even though the BMC may power the node off when a cap isn't met in time"
+    )]
+    allow_hard_power_off: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        name = "exception action",
+        help = "Exception action to set for the campaign, the original one is restored afterwards"
+    )]
+    campaign_exception_action: Option<ExceptionAction>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "dcmi",
+        name = "cap mechanisms",
+        help = "Mechanisms to cap through, comma separated. Node Manager policies need an Intel Management Engine, RAPL limits an agent running as root"
+    )]
+    cap_mechanisms: Vec<CapMechanism>,
+
+    #[arg(
+        long,
+        default_value_t = 10_000,
+        name = "verify timeout millis",
+        help = "How long to wait for the BMC to report a cap change before giving up"
+    )]
+    verify_timeout: u64,
+
+    #[arg(
+        long,
+        default_value_t = 1_000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        name = "bmc poll interval millis",
+        help = "Period of the BMC power and cap readings. Reads that overrun it are recorded as missed ticks"
+    )]
+    bmc_poll_interval: u64,
+
+    #[arg(
+        long,
+        default_value_t = 2_000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        name = "sensor poll interval millis",
+        help = "Period of the BMC sensor (PSU, temperature, fan) readings"
+    )]
+    sensor_poll_interval: u64,
 
     //
-    #[arg(long, short, help="Agent listen address:port, eg: oahu10000:8080")]
-    agent: String,
+    #[arg(long, short, required = true, help="Agent listen address:port, eg: oahu10000:8080")]
+    agent: Option<String>,
 
     #[arg(
         long,
@@ -188,4 +338,16 @@ struct CLI {
         help = "Path to ipmi executable (relative or absolute)"
     )]
     ipmi: String,
+
+    #[command(subcommand)]
+    command: Option<ClientCommand>,
+}
+
+#[derive(clap::Subcommand)]
+enum ClientCommand {
+    /// Stop the agent load and put back the cap settings saved at the start of a campaign
+    Restore {
+        #[arg(help = "Snapshot file, eg: ./stats/cap_snapshot_230509_1424.json")]
+        snapshot: String,
+    },
 }
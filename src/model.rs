@@ -1,8 +1,12 @@
 use chrono::{DateTime, Utc, serde::ts_milliseconds_option};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use std::fmt;
+use crate::bmc::bmc::ExceptionAction;
+use crate::rapl::power_limit::RaplConstraint;
+use crate::rapl::rapl::RaplDomain;
 
 pub type Semaphore = Arc<RwLock<bool>>;
 pub fn is_running() -> Semaphore {
@@ -42,8 +46,15 @@ pub struct ServerInfo {
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RaplData {
-    pub domain: String,
-    pub power_watts: u64,
+    pub domain: RaplDomain,
+    /// Average power since the previous sample
+    pub power_watts: f64,
+    /// Energy used by the domain since the first sample, wrap-arounds included
+    pub energy_uj: u64,
+    /// Time the package was throttled by RAPL since the first sample, for packages read
+    /// through the msr devices
+    #[serde(default)]
+    pub throttled_us: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -61,6 +72,25 @@ pub struct FirestarterParams {
     pub n_threads: u64,
 }
 
+/// A cap change the agent makes in-band on the BMC of its host
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum BmcCapOperation {
+    SetPowerLevel(u64),
+    Activate,
+    Deactivate,
+    SetCorrectionTime(Duration),
+    SetSamplingPeriod(Duration),
+    SetExceptionAction(ExceptionAction),
+}
+
+/// A change the agent makes to the RAPL limits of its host
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum RaplLimitOperation {
+    SetPowerLimit { domain: RaplDomain, constraint: RaplConstraint, power_limit_uw: u64 },
+    SetTimeWindow { domain: RaplDomain, constraint: RaplConstraint, time_window_us: u64 },
+    SetEnabled { domain: RaplDomain, enabled: bool },
+}
+
 impl fmt::Display for SystemInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f,
@@ -1,3 +1,9 @@
 pub mod monitor_rapl;
+pub mod msr;
+#[cfg(test)]
+pub mod msr_fake;
+pub mod power_limit;
+#[cfg(test)]
+pub mod powercap_fake;
 #[allow(clippy::module_inception)]
 pub mod rapl;
@@ -1,30 +1,35 @@
-use crate::rapl::rapl::{RAPL_Readings, RAPL};
+use crate::rapl::rapl::{RaplSource, RAPL_Readings, RAPL};
 use crate::model::{RaplData, RaplRecord};
 
-use log::{info, trace};
+use log::{info, trace, warn};
 use std::thread;
 use std::time::Duration;
 use std::sync::mpsc::Receiver;
 
 const POLL_FREQ_HZ: u64 = 2;
 
-/// Periodically reads all the `energy_uj` files and saves the result. Runs on its own thread.
+/// Periodically reads all the energy and throttling counters and saves the result. Runs
+/// on its own thread. A sample that can't be read is skipped.
 /// Each time through the loop, checks for a message from the main monitor thread that signals
 /// that this thread can exit. Before exiting, saves results to CSV file.
-pub fn monitor_rapl(rx: &Receiver<()>, runtime_secs: u64) -> Vec<RaplRecord> {
+pub fn monitor_rapl(rx: &Receiver<()>, runtime_secs: u64, source: &RaplSource) -> Vec<RaplRecord> {
     info!("\tRAPL: launched");
 
     let mut stats = Vec::<RAPL_Readings>::with_capacity((POLL_FREQ_HZ * (runtime_secs + 5)) as usize);
-    let rapl = RAPL::new();
+    let rapl = RAPL::from_source(source);
     let sleep_millis = 1000 / POLL_FREQ_HZ;
     loop {
         if rx.try_recv().is_ok() {
             trace!("\tRAPL: got message - exiting");
             break;
         }
-        let energy_reading = rapl.read_current_energy();
-        trace!("{energy_reading}");
-        stats.push(energy_reading);
+        match rapl.read_current_energy() {
+            Ok(energy_reading) => {
+                trace!("{energy_reading}");
+                stats.push(energy_reading);
+            }
+            Err(e) => warn!("\tRAPL: sample skipped: {e}"),
+        }
         thread::sleep(Duration::from_millis(sleep_millis));
     }
     convert_energy_to_power(&stats)
@@ -33,20 +38,24 @@ pub fn monitor_rapl(rx: &Receiver<()>, runtime_secs: u64) -> Vec<RaplRecord> {
 
 
 /// Does what it says on the packet - divides energy deltas by time deltas to give power.
-fn convert_energy_to_power(stats: &Vec<RAPL_Readings>) -> Vec<RaplRecord> {
+fn convert_energy_to_power(stats: &[RAPL_Readings]) -> Vec<RaplRecord> {
     // The units of reading are µJ
 
     let mut readings = Vec::with_capacity(stats.len());
+    let Some(first) = stats.first() else {
+        return readings;
+    };
     // sanity check: ensure all reading have same # entries
-    let n_domains = stats[0].readings.len();
+    let n_domains = first.readings.len();
 
     // for stats[1...], calculate power by calculating the
     // energy change from the previous reading and dividing by
     // the time delta for each RAPL domain. The total power is
     // the sum of the domains.
 
-    // need to check for wrap-around - keep tabs on max_energy_uj and previous reading
-    let max_energy_uj = RAPL::max_energy();
+    // need to check for wrap-around - keep tabs on each domain's max_energy_uj and previous reading
+    let mut cumulative_energy_uj = vec![0_u64; n_domains];
+    let mut cumulative_throttled_us = vec![0_u64; n_domains];
 
     // By using skip(1), the index from the enumerate is one behind the
     // current row, i.e. it points to the preceding row, which is exactly
@@ -56,27 +65,30 @@ fn convert_energy_to_power(stats: &Vec<RAPL_Readings>) -> Vec<RaplRecord> {
         let mut power_readings: Vec<RaplData> = Vec::with_capacity(n_domains);
         let time_delta = stat.timestamp - stats[stat_index].timestamp;
         let time_midpoint = stat.timestamp - (time_delta / 2);
+        #[allow(clippy::cast_precision_loss)]
+        let time_delta_us = time_delta.num_microseconds().expect("failed to get time ∆ microseconds") as f64;
 
         // Loop over the domains
         for (domain_index, reading) in stat.readings.iter().enumerate() {
-            let previous_reading = stats[stat_index].readings[domain_index].reading;
-            let current_reading = reading.reading;
-
-            // check for wrap-around
-            let energy_delta_uj = {
-                if current_reading < previous_reading {
-                    max_energy_uj - previous_reading + current_reading // wrapped
-                } else {
-                    current_reading - previous_reading // no wrap
-                }
-            };
-
-            // time delta is always positive so no loss of sign - and in any case makes no difference
-            #[allow(clippy::cast_sign_loss)]
-            let power_watts = energy_delta_uj / time_delta.num_microseconds().expect("failed to get time ∆ microseconds") as u64;
+            let previous = &stats[stat_index].readings[domain_index];
+            let energy_delta_uj = counter_delta(previous.reading, reading.reading, reading.max_energy_uj);
+            cumulative_energy_uj[domain_index] += energy_delta_uj;
+
+            // The throttling counter wraps around too
+            let throttled_us = previous.throttled.zip(reading.throttled).map(|(previous, current)| {
+                cumulative_throttled_us[domain_index] +=
+                    counter_delta(previous.throttled_us, current.throttled_us, current.max_throttled_us);
+                cumulative_throttled_us[domain_index]
+            });
+
+            // µJ/µs = W, the counters are well within the 52 bits an f64 holds exactly
+            #[allow(clippy::cast_precision_loss)]
+            let power_watts = energy_delta_uj as f64 / time_delta_us;
             power_readings.push(RaplData {
-                domain: reading.domain.clone(),
+                domain: reading.domain,
                 power_watts,
+                energy_uj: cumulative_energy_uj[domain_index],
+                throttled_us,
             });
         }
         let datapoint = RaplRecord {timestamp: Some(time_midpoint), data: power_readings};
@@ -85,25 +97,42 @@ fn convert_energy_to_power(stats: &Vec<RAPL_Readings>) -> Vec<RaplRecord> {
     readings
 }
 
+/// How far a counter went from `previous` to `current`, wrapping around at `max`
+fn counter_delta(previous: u64, current: u64, max: u64) -> u64 {
+    if current < previous {
+        max - previous + current // wrapped
+    } else {
+        current - previous // no wrap
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rapl::rapl::{RaplDomain, RaplDomainType, RAPL_Reading};
+    use crate::rapl::msr::{MSR_PKG_ENERGY_STATUS, MSR_PKG_PERF_STATUS};
+    use crate::rapl::msr_fake::FakeMsr;
+    use crate::rapl::powercap_fake::FakePowercap;
     use chrono::Utc;
 
+    const MAX_ENERGY_UJ: u64 = 262_143_328_850;
+
 
     #[test]
     fn test_energy_to_power() {
-        let r1 = RAPL_Reading {domain: String::from("0"), reading: 0};
-        let r2 = RAPL_Reading {domain: String::from("1"), reading: 0};
-        let r3 = RAPL_Reading {domain: String::from("0"), reading: 100_000_000};
-        let r4 = RAPL_Reading {domain: String::from("1"), reading:  50_000_000};
-        let r5 = RAPL_Reading {domain: String::from("0"), reading: 200_000_000};
-        let r6 = RAPL_Reading {domain: String::from("1"), reading: 100_000_000};
-        let r7 = RAPL_Reading {domain: String::from("0"), reading: 200_000_000};
-        let r8 = RAPL_Reading {domain: String::from("1"), reading: 100_000_000};
-        let r9 = RAPL_Reading {domain: String::from("0"), reading: 400_000_000};
-        let r10 = RAPL_Reading {domain: String::from("1"), reading: 200_000_000};
+        let package = RaplDomain::new(RaplDomainType::Package, Some(0));
+        let dram = RaplDomain::new(RaplDomainType::Dram, Some(0));
+        let r1 = RAPL_Reading::new(package, 0, MAX_ENERGY_UJ);
+        let r2 = RAPL_Reading::new(dram, 0, MAX_ENERGY_UJ);
+        let r3 = RAPL_Reading::new(package, 100_000_000, MAX_ENERGY_UJ);
+        let r4 = RAPL_Reading::new(dram, 50_000_000, MAX_ENERGY_UJ);
+        let r5 = RAPL_Reading::new(package, 200_000_000, MAX_ENERGY_UJ);
+        let r6 = RAPL_Reading::new(dram, 100_000_000, MAX_ENERGY_UJ);
+        let r7 = RAPL_Reading::new(package, 200_000_000, MAX_ENERGY_UJ);
+        let r8 = RAPL_Reading::new(dram, 100_000_000, MAX_ENERGY_UJ);
+        let r9 = RAPL_Reading::new(package, 400_000_000, MAX_ENERGY_UJ);
+        let r10 = RAPL_Reading::new(dram, 200_000_000, MAX_ENERGY_UJ);
 
         let t0 = Utc::now();
         let t1 = t0 + chrono::Duration::milliseconds(1000);
@@ -122,20 +151,116 @@ mod tests {
 
         assert_eq!(power_stats.len(), energy_stats.len() - 1);
 
+        assert_eq!(power_stats[0].data[1].domain, dram);
+
         // check power
-        assert_eq!(power_stats[0].readings[0].reading, 100);
-        assert_eq!(power_stats[0].readings[1].reading,  50);
-        assert_eq!(power_stats[1].readings[0].reading, 100);
-        assert_eq!(power_stats[1].readings[1].reading,  50);
-        assert_eq!(power_stats[2].readings[0].reading,   0);
-        assert_eq!(power_stats[2].readings[1].reading,   0);
-        assert_eq!(power_stats[3].readings[0].reading, 100);
-        assert_eq!(power_stats[3].readings[1].reading,  50);
+        assert_eq!(power_stats[0].data[0].power_watts, 100.0);
+        assert_eq!(power_stats[0].data[1].power_watts,  50.0);
+        assert_eq!(power_stats[1].data[0].power_watts, 100.0);
+        assert_eq!(power_stats[1].data[1].power_watts,  50.0);
+        assert_eq!(power_stats[2].data[0].power_watts,   0.0);
+        assert_eq!(power_stats[2].data[1].power_watts,   0.0);
+        assert_eq!(power_stats[3].data[0].power_watts, 100.0);
+        assert_eq!(power_stats[3].data[1].power_watts,  50.0);
+
+        // check cumulative energy
+        assert_eq!(power_stats[3].data[0].energy_uj, 400_000_000);
+        assert_eq!(power_stats[3].data[1].energy_uj, 200_000_000);
 
         // check timestamps
-        assert_eq!(power_stats[0].timestamp, t0 + chrono::Duration::milliseconds(500));
-        assert_eq!(power_stats[1].timestamp, t0 + chrono::Duration::milliseconds(1500));
-        assert_eq!(power_stats[2].timestamp, t0 + chrono::Duration::milliseconds(2500));
-        assert_eq!(power_stats[3].timestamp, t0 + chrono::Duration::milliseconds(4000));
+        assert_eq!(power_stats[0].timestamp, Some(t0 + chrono::Duration::milliseconds(500)));
+        assert_eq!(power_stats[1].timestamp, Some(t0 + chrono::Duration::milliseconds(1500)));
+        assert_eq!(power_stats[2].timestamp, Some(t0 + chrono::Duration::milliseconds(2500)));
+        assert_eq!(power_stats[3].timestamp, Some(t0 + chrono::Duration::milliseconds(4000)));
+    }
+
+    #[test]
+    fn test_per_domain_wrap() {
+        // The dram counter wraps at a quarter of the package's range, and draws 1.5W
+        let package = RaplDomain::new(RaplDomainType::Package, Some(0));
+        let dram = RaplDomain::new(RaplDomainType::Dram, Some(0));
+        let dram_max_uj = MAX_ENERGY_UJ / 4;
+
+        let t0 = Utc::now();
+        let t1 = t0 + chrono::Duration::milliseconds(500);
+        let t2 = t0 + chrono::Duration::milliseconds(1000);
+        let energy_stats = vec![
+            RAPL_Readings{timestamp: t0, readings: vec![RAPL_Reading::new(package, MAX_ENERGY_UJ - 10_000_000, MAX_ENERGY_UJ), RAPL_Reading::new(dram, dram_max_uj - 250_000, dram_max_uj)]},
+            RAPL_Readings{timestamp: t1, readings: vec![RAPL_Reading::new(package, 40_000_000, MAX_ENERGY_UJ), RAPL_Reading::new(dram, 500_000, dram_max_uj)]},
+            RAPL_Readings{timestamp: t2, readings: vec![RAPL_Reading::new(package, 90_000_000, MAX_ENERGY_UJ), RAPL_Reading::new(dram, 1_250_000, dram_max_uj)]},
+        ];
+        let power_stats = convert_energy_to_power(&energy_stats);
+
+        assert_eq!(power_stats[0].data[0].power_watts, 100.0);
+        assert_eq!(power_stats[0].data[1].power_watts, 1.5);
+        assert_eq!(power_stats[1].data[1].power_watts, 1.5);
+        assert_eq!(power_stats[1].data[0].energy_uj, 100_000_000);
+        assert_eq!(power_stats[1].data[1].energy_uj, 1_500_000);
+    }
+
+    #[test]
+    fn test_msr_wrap() {
+        // 1J either side of the 32 bit wrap of the register, a second apart
+        let fake = FakeMsr::xeon("monitor_msr").unwrap();
+        let rapl = RAPL::msr(&fake.config());
+        fake.write(0, MSR_PKG_ENERGY_STATUS, 0xffff_c000).unwrap();
+        let mut before = rapl.read_current_energy().unwrap();
+        fake.write(0, MSR_PKG_ENERGY_STATUS, 0x4000).unwrap();
+        let mut after = rapl.read_current_energy().unwrap();
+        before.timestamp = Utc::now();
+        after.timestamp = before.timestamp + chrono::Duration::seconds(1);
+
+        let power_stats = convert_energy_to_power(&[before, after]);
+        assert_eq!(power_stats[0].data[0].power_watts, 2.0);
+        assert_eq!(power_stats[0].data[0].energy_uj, 2_000_000);
+    }
+
+    #[test]
+    fn test_msr_throttling() {
+        // 1s throttled across the 32 bit wrap of the register, then another half second
+        let fake = FakeMsr::xeon("monitor_throttling").unwrap();
+        let rapl = RAPL::msr(&fake.config());
+        let start = Utc::now();
+        let mut stats = Vec::new();
+        for (index, perf_status) in [0xffff_fc00, 0, 0x200].into_iter().enumerate() {
+            fake.write(0, MSR_PKG_PERF_STATUS, perf_status).unwrap();
+            let mut reading = rapl.read_current_energy().unwrap();
+            reading.timestamp = start + chrono::Duration::seconds(i64::try_from(index).unwrap());
+            stats.push(reading);
+        }
+
+        let power_stats = convert_energy_to_power(&stats);
+        let throttled: Vec<Vec<Option<u64>>> =
+            power_stats.iter().map(|record| record.data.iter().map(|data| data.throttled_us).collect()).collect();
+        // package-0, dram-0, package-1
+        assert_eq!(throttled, [[Some(1_000_000), None, Some(0)], [Some(1_500_000), None, Some(0)]]);
+    }
+
+    #[test]
+    fn test_fake_powercap_to_power() {
+        // Half a second of the scripted power between reads
+        let fake = FakePowercap::xeon("monitor_rapl", "intel-rapl", 200.0).unwrap();
+        let rapl = RAPL::from_source(&RaplSource::Powercap(fake.config(&["intel-rapl"])));
+        let start = Utc::now();
+        let mut stats = Vec::new();
+        for index in 0..3 {
+            if index > 0 {
+                fake.advance(Duration::from_millis(500)).unwrap();
+            }
+            let mut reading = rapl.read_current_energy().unwrap();
+            reading.timestamp = start + chrono::Duration::milliseconds(500 * index);
+            stats.push(reading);
+        }
+
+        let records = convert_energy_to_power(&stats);
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            let domains: Vec<String> = record.data.iter().map(|data| data.domain.to_string()).collect();
+            assert_eq!(domains, ["package-0", "dram-0", "core-0", "package-1", "dram-1", "core-1"]);
+            assert_eq!(record.data[0].power_watts, 200.0);
+            assert_eq!(record.data[1].power_watts, 50.0);
+            assert_eq!(record.data[2].power_watts, 100.0);
+        }
+        assert_eq!(records[1].data[0].energy_uj, 200_000_000);
     }
 }
@@ -0,0 +1,342 @@
+use crate::rapl::rapl::{RaplDomain, RAPL};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// Where the powercap driver is missing or locked down, the RAPL registers are read
+// directly through the msr driver: `/dev/cpu/<cpu>/msr`, read at the register's
+// address. The registers are per package, so any CPU of the package will do - the
+// first one is used. Needs root, and the msr module loaded.
+pub const MSR_ROOT: &str = "/dev/cpu";
+pub const CPU_ROOT: &str = "/sys/devices/system/cpu";
+pub const CPUINFO_PATH: &str = "/proc/cpuinfo";
+pub(crate) const MSR_DEVICE_FILE: &str = "msr";
+pub(crate) const PACKAGE_ID_FILE: &str = "topology/physical_package_id";
+
+pub const MSR_RAPL_POWER_UNIT: u64 = 0x606;
+pub const MSR_PKG_POWER_LIMIT: u64 = 0x610;
+pub const MSR_PKG_ENERGY_STATUS: u64 = 0x611;
+pub const MSR_PKG_PERF_STATUS: u64 = 0x613;
+pub const MSR_DRAM_ENERGY_STATUS: u64 = 0x619;
+
+/// The energy and throttling counters are the low 32 bits of their registers, and
+/// wrap around at that
+const COUNTER_MASK: u64 = 0xffff_ffff;
+pub(crate) const COUNTER_RANGE: u64 = 1 << 32;
+
+/// The Intel family 6 server models that count dram energy in 2^-16 J, whatever
+/// MSR_RAPL_POWER_UNIT says - the list of the kernel's intel_rapl driver: Haswell-X,
+/// Broadwell-X and -D, Skylake-X, Xeon Phi, Ice Lake-X and -D, Sapphire, Emerald and
+/// Granite Rapids
+const SERVER_DRAM_MODELS: [u64; 11] = [0x3f, 0x4f, 0x56, 0x55, 0x57, 0x85, 0x6a, 0x6c, 0x8f, 0xcf, 0xad];
+const SERVER_DRAM_ENERGY_STATUS_UNITS: u32 = 16;
+
+const MICROS: f64 = 1_000_000.0;
+
+/// Where to find the msr devices, and how to decode them
+#[derive(Debug, Clone)]
+pub struct MsrConfig {
+    /// Holds a directory per CPU, each with its `msr` device
+    pub msr_root: PathBuf,
+    /// Holds a `cpu<N>` directory per CPU, telling its package
+    pub cpu_root: PathBuf,
+    /// Tells the CPU model, which the dram energy status units depend on
+    pub cpuinfo: PathBuf,
+    /// The energy status units of dram, overriding those of the CPU model. Xeons since
+    /// Haswell count dram in units of 2^-16 J, others in the package's units of
+    /// MSR_RAPL_POWER_UNIT.
+    pub dram_energy_status_units: Option<u32>,
+}
+
+impl MsrConfig {
+    #[must_use]
+    pub fn new(msr_root: &Path, cpu_root: &Path) -> Self {
+        Self {
+            msr_root: PathBuf::from(msr_root),
+            cpu_root: PathBuf::from(cpu_root),
+            cpuinfo: PathBuf::from(CPUINFO_PATH),
+            dram_energy_status_units: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_cpuinfo(mut self, cpuinfo: &Path) -> Self {
+        self.cpuinfo = PathBuf::from(cpuinfo);
+        self
+    }
+
+    #[must_use]
+    pub fn with_dram_energy_status_units(mut self, units: Option<u32>) -> Self {
+        self.dram_energy_status_units = units;
+        self
+    }
+
+    /// The dram energy status units when they're not those of the package: the ones
+    /// given, or else those of the CPU model
+    #[must_use]
+    pub fn dram_energy_units(&self) -> Option<u32> {
+        self.dram_energy_status_units.or_else(|| match fs::read_to_string(&self.cpuinfo) {
+            Ok(cpuinfo) => server_dram_energy_status_units(&cpuinfo),
+            Err(e) => {
+                warn!("RAPL can't read the CPU model from {}, dram is taken to count in the package's units: {e}", self.cpuinfo.display());
+                None
+            }
+        })
+    }
+}
+
+/// 2^-16 J on the Intel server models that count dram in those units, from the first
+/// CPU of `/proc/cpuinfo`
+#[must_use]
+pub fn server_dram_energy_status_units(cpuinfo: &str) -> Option<u32> {
+    let field = |name: &str| {
+        cpuinfo.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name).then(|| value.trim())
+        })
+    };
+    let vendor = field("vendor_id")?;
+    let family: u64 = field("cpu family")?.parse().ok()?;
+    let model: u64 = field("model")?.parse().ok()?;
+    trace!("RAPL CPU: {vendor} family {family} model {model:#x}");
+    (vendor == "GenuineIntel" && family == 6 && SERVER_DRAM_MODELS.contains(&model)).then_some(SERVER_DRAM_ENERGY_STATUS_UNITS)
+}
+
+impl Default for MsrConfig {
+    fn default() -> Self {
+        Self::new(Path::new(MSR_ROOT), Path::new(CPU_ROOT))
+    }
+}
+
+/// The units of the RAPL registers, decoded from MSR_RAPL_POWER_UNIT: each is 1/2^N
+/// of a watt, joule or second
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RaplUnits {
+    pub power_w: f64,
+    pub energy_uj: f64,
+    pub time_us: f64,
+}
+
+impl RaplUnits {
+    #[must_use]
+    pub fn decode(value: u64) -> Self {
+        Self {
+            power_w: 1.0 / f64::from(1_u32 << (value & 0xf)),
+            energy_uj: energy_unit_uj(((value >> 8) & 0x1f) as u32),
+            time_us: MICROS / f64::from(1_u32 << ((value >> 16) & 0xf)),
+        }
+    }
+}
+
+/// The µJ of a count, for energy status units of 1/2^`units` J
+#[must_use]
+pub fn energy_unit_uj(units: u32) -> f64 {
+    MICROS / 2_f64.powi(i32::try_from(units).unwrap_or(i32::MAX))
+}
+
+/// One of the two limits of MSR_PKG_POWER_LIMIT
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsrPowerLimit {
+    pub power_limit_uw: u64,
+    pub enabled: bool,
+    /// Allowed to go below the OS requested frequency to keep to the limit
+    pub clamped: bool,
+    pub time_window_us: u64,
+}
+
+impl MsrPowerLimit {
+    /// Decodes the 24 bit limit at the bottom of `value`: 15 bits of power, the enable
+    /// and clamp bits, then the window as 2^Y × (1 + Z/4) time units, Y in 5 bits and Z in 2
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    pub fn decode(value: u64, units: &RaplUnits) -> Self {
+        let y = (value >> 17) & 0x1f;
+        let z = (value >> 22) & 0x3;
+        let window = (1_u64 << y) as f64 * (1.0 + z as f64 / 4.0);
+        Self {
+            power_limit_uw: ((value & 0x7fff) as f64 * units.power_w * MICROS) as u64,
+            enabled: value & (1 << 15) != 0,
+            clamped: value & (1 << 16) != 0,
+            time_window_us: (window * units.time_us) as u64,
+        }
+    }
+}
+
+/// MSR_PKG_POWER_LIMIT: the long term limit (PL1) in the low half, the short term one
+/// (PL2) in the high half
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsrPackageLimits {
+    pub long_term: MsrPowerLimit,
+    pub short_term: MsrPowerLimit,
+    /// Set by the firmware, the limits can't be changed until the next reset
+    pub locked: bool,
+}
+
+impl MsrPackageLimits {
+    #[must_use]
+    pub fn decode(value: u64, units: &RaplUnits) -> Self {
+        Self {
+            long_term: MsrPowerLimit::decode(value, units),
+            short_term: MsrPowerLimit::decode(value >> 32, units),
+            locked: value & (1 << 63) != 0,
+        }
+    }
+}
+
+/// The limits of a package and how long they've throttled it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsrPackageStatus {
+    pub domain: RaplDomain,
+    pub limits: MsrPackageLimits,
+    /// Time spent throttled by RAPL, from MSR_PKG_PERF_STATUS. A counter: it only means
+    /// something as the difference of two reads, and wraps around like the energy counters.
+    pub throttled_us: u64,
+}
+
+impl RAPL {
+    /// The limits and throttling counter of each package read through the msr devices
+    ///
+    /// # Errors
+    /// If a register can't be read
+    pub fn msr_status(&self) -> io::Result<Vec<MsrPackageStatus>> {
+        self.msr_packages()
+            .map(|(domain, device)| {
+                let units = RaplUnits::decode(read_msr(device, MSR_RAPL_POWER_UNIT)?);
+                let limits = MsrPackageLimits::decode(read_msr(device, MSR_PKG_POWER_LIMIT)?, &units);
+                let throttled_us = read_throttled_us(device, units.time_us)?;
+                Ok(MsrPackageStatus { domain, limits, throttled_us })
+            })
+            .collect()
+    }
+}
+
+/// The msr device of the first CPU of each package, by package
+pub(crate) fn package_devices(config: &MsrConfig) -> BTreeMap<u64, PathBuf> {
+    let entries = match fs::read_dir(&config.cpu_root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("RAPL can't list the CPUs in {}: {e}", config.cpu_root.display());
+            return BTreeMap::new();
+        }
+    };
+    let mut first_cpus: BTreeMap<u64, u64> = BTreeMap::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(cpu) = name.to_str().and_then(|name| name.strip_prefix("cpu")).and_then(|cpu| cpu.parse::<u64>().ok()) else {
+            continue;
+        };
+        let Some(package) = fs::read_to_string(entry.path().join(PACKAGE_ID_FILE)).ok().and_then(|id| id.trim().parse().ok()) else {
+            trace!("RAPL: no package for CPU {cpu}");
+            continue;
+        };
+        let first = first_cpus.entry(package).or_insert(cpu);
+        *first = (*first).min(cpu);
+    }
+    first_cpus
+        .into_iter()
+        .map(|(package, cpu)| (package, config.msr_root.join(cpu.to_string()).join(MSR_DEVICE_FILE)))
+        .collect()
+}
+
+/// Reads a register through an msr device
+///
+/// # Errors
+/// If the device can't be opened, eg without root, or the CPU doesn't have the register
+pub fn read_msr(device: &Path, register: u64) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    File::open(device)?.read_exact_at(&mut bytes, register)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// An energy status register in µJ
+///
+/// # Errors
+/// If the register can't be read
+pub(crate) fn read_energy(device: &Path, register: u64, energy_unit_uj: f64) -> io::Result<u64> {
+    read_counter(device, register, energy_unit_uj)
+}
+
+/// The time MSR_PKG_PERF_STATUS counts the package throttled, in µs
+///
+/// # Errors
+/// If the register can't be read, eg on CPUs without it
+pub(crate) fn read_throttled_us(device: &Path, time_unit_us: f64) -> io::Result<u64> {
+    read_counter(device, MSR_PKG_PERF_STATUS, time_unit_us)
+}
+
+/// Where a counter of `unit` wraps around
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+pub(crate) fn counter_range(unit: f64) -> u64 {
+    (COUNTER_RANGE as f64 * unit) as u64
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+fn read_counter(device: &Path, register: u64, unit: f64) -> io::Result<u64> {
+    let count = read_msr(device, register)? & COUNTER_MASK;
+    Ok((count as f64 * unit) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rapl::msr_fake::{FakeMsr, XEON_CPUINFO, XEON_POWER_UNIT};
+    use crate::rapl::rapl::RaplDomainType;
+
+    #[test]
+    fn test_decode_units() {
+        let units = RaplUnits::decode(XEON_POWER_UNIT);
+        assert_eq!(units.power_w, 0.125);
+        assert_eq!(units.energy_uj, 61.035_156_25);
+        assert_eq!(units.time_us, 976.5625);
+        assert_eq!(energy_unit_uj(16), 15.258_789_062_5);
+    }
+
+    #[test]
+    fn test_decode_power_limit() {
+        let units = RaplUnits::decode(XEON_POWER_UNIT);
+        // PL1 150W enabled and clamped over 2^10 time units, PL2 180W over 2^1 × 1.25, locked
+        let long_term = 1200 | 1 << 15 | 1 << 16 | 10 << 17;
+        let short_term = 1440 | 1 << 15 | 1 << 17 | 1 << 22;
+        let limits = MsrPackageLimits::decode(long_term | short_term << 32 | 1 << 63, &units);
+        assert_eq!(
+            limits.long_term,
+            MsrPowerLimit { power_limit_uw: 150_000_000, enabled: true, clamped: true, time_window_us: 1_000_000 }
+        );
+        assert_eq!(
+            limits.short_term,
+            MsrPowerLimit { power_limit_uw: 180_000_000, enabled: true, clamped: false, time_window_us: 2_441 }
+        );
+        assert!(limits.locked);
+        assert!(!MsrPackageLimits::decode(long_term, &units).locked);
+    }
+
+    #[test]
+    fn test_server_dram_units() {
+        assert_eq!(server_dram_energy_status_units(XEON_CPUINFO), Some(16));
+        // A client part, an EPYC, and no model at all
+        assert_eq!(server_dram_energy_status_units("vendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 158\n"), None);
+        assert_eq!(server_dram_energy_status_units("vendor_id\t: AuthenticAMD\ncpu family\t: 25\nmodel\t\t: 1\n"), None);
+        assert_eq!(server_dram_energy_status_units(""), None);
+    }
+
+    #[test]
+    fn test_fake_msr_status() {
+        let fake = FakeMsr::xeon("msr_status").unwrap();
+        fake.write(2, MSR_PKG_POWER_LIMIT, 1600 | 1 << 15 | 10 << 17).unwrap();
+        // 3 s throttled
+        fake.write(2, MSR_PKG_PERF_STATUS, 3072).unwrap();
+
+        let rapl = RAPL::msr(&fake.config());
+        let status = rapl.msr_status().unwrap();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[1].domain, RaplDomain::new(RaplDomainType::Package, Some(1)));
+        assert_eq!(status[1].limits.long_term.power_limit_uw, 200_000_000);
+        assert_eq!(status[1].throttled_us, 3_000_000);
+        assert_eq!(status[0].throttled_us, 0);
+    }
+}
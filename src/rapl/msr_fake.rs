@@ -0,0 +1,102 @@
+use crate::rapl::msr::{
+    MsrConfig, MSR_DEVICE_FILE, MSR_DRAM_ENERGY_STATUS, MSR_PKG_ENERGY_STATUS, MSR_PKG_PERF_STATUS, MSR_PKG_POWER_LIMIT,
+    MSR_RAPL_POWER_UNIT, PACKAGE_ID_FILE,
+};
+use crate::test_util::TempDir;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// MSR_RAPL_POWER_UNIT of a Xeon: 1/8 W, 2^-14 J and 2^-10 s
+pub const XEON_POWER_UNIT: u64 = 0x000a_0e03;
+/// The `/proc/cpuinfo` of a Skylake-X, trimmed to what tells the model
+pub const XEON_CPUINFO: &str = "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 85\nmodel name\t: Intel(R) Xeon(R) Gold 6148 CPU @ 2.40GHz\n";
+
+/// Fake msr devices and CPU topology, so the MSR RAPL code runs off-hardware. Each
+/// device is a sparse file holding the value of a register at its address. They're
+/// removed when the fake is dropped.
+pub struct FakeMsr {
+    dir: TempDir,
+}
+
+impl FakeMsr {
+    /// No CPUs yet, in a directory named after `name`
+    ///
+    /// # Errors
+    /// If the directories can't be created
+    pub fn new(name: &str) -> io::Result<Self> {
+        let fake = Self { dir: TempDir::new(name) };
+        fs::create_dir_all(fake.msr_root())?;
+        fs::create_dir_all(fake.cpu_root())?;
+        Ok(fake)
+    }
+
+    /// Two packages of two CPUs of a Skylake-X, package 1 starting at CPU 2, each with
+    /// Xeon units. Only package 0 has a dram counter.
+    ///
+    /// # Errors
+    /// If the devices can't be written
+    pub fn xeon(name: &str) -> io::Result<Self> {
+        let fake = FakeMsr::new(name)?;
+        for cpu in 0..4 {
+            fake.add_cpu(cpu, cpu / 2)?;
+            fake.write(cpu, MSR_RAPL_POWER_UNIT, XEON_POWER_UNIT)?;
+            for register in [MSR_PKG_POWER_LIMIT, MSR_PKG_ENERGY_STATUS, MSR_PKG_PERF_STATUS] {
+                fake.write(cpu, register, 0)?;
+            }
+        }
+        fake.write(0, MSR_DRAM_ENERGY_STATUS, 0)?;
+        fake.set_cpuinfo(XEON_CPUINFO)?;
+        Ok(fake)
+    }
+
+    /// The configuration finding these devices
+    #[must_use]
+    pub fn config(&self) -> MsrConfig {
+        MsrConfig::new(&self.msr_root(), &self.cpu_root()).with_cpuinfo(&self.dir.join("cpuinfo"))
+    }
+
+    /// Sets the `/proc/cpuinfo` telling the CPU model, there's none until then
+    ///
+    /// # Errors
+    /// If it can't be written
+    pub fn set_cpuinfo(&self, cpuinfo: &str) -> io::Result<()> {
+        fs::write(self.dir.join("cpuinfo"), cpuinfo)
+    }
+
+    /// Adds a CPU of `package`, with an empty msr device
+    ///
+    /// # Errors
+    /// If the CPU can't be written
+    pub fn add_cpu(&self, cpu: u64, package: u64) -> io::Result<()> {
+        let topology = self.cpu_root().join(format!("cpu{cpu}")).join(PACKAGE_ID_FILE);
+        fs::create_dir_all(topology.parent().unwrap_or(self.dir.path()))?;
+        fs::write(topology, format!("{package}\n"))?;
+        let device = self.device(cpu);
+        fs::create_dir_all(device.parent().unwrap_or(self.dir.path()))?;
+        fs::write(device, [])
+    }
+
+    /// Sets a register of a CPU
+    ///
+    /// # Errors
+    /// If the device can't be written
+    pub fn write(&self, cpu: u64, register: u64, value: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(self.device(cpu))?.write_all_at(&value.to_le_bytes(), register)
+    }
+
+    /// The msr device of a CPU
+    #[must_use]
+    pub fn device(&self, cpu: u64) -> PathBuf {
+        self.msr_root().join(cpu.to_string()).join(MSR_DEVICE_FILE)
+    }
+
+    fn msr_root(&self) -> PathBuf {
+        self.dir.join("dev/cpu")
+    }
+
+    fn cpu_root(&self) -> PathBuf {
+        self.dir.join("sys/devices/system/cpu")
+    }
+}
@@ -0,0 +1,330 @@
+use crate::model::RaplLimitOperation;
+use crate::rapl::rapl::{RaplDomain, RAPL};
+use chrono::{DateTime, Utc};
+use log::{error, info, trace};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+// Each zone limits its power through numbered constraints, each a power limit averaged
+// over a time window. Which constraint is which is only told by `constraint_N_name`:
+// a package has a long term and a short term one, some subzones only the long term one.
+// The `enabled` file of the zone turns all its limits on or off. The kernel may tell
+// the most a limit can be set to, in `constraint_N_max_power_uw`.
+pub(crate) const ZONE_ENABLED_FILE: &str = "enabled";
+
+/// Where the agent keeps the limits it changed. A reboot resets the limits, and
+/// empties `/run` along with them.
+pub const RAPL_LIMITS_FILE: &str = "/run/agent_rapl_limits.json";
+pub(crate) const CONSTRAINT_NAME_SUFFIX: &str = "name";
+pub(crate) const CONSTRAINT_POWER_LIMIT_SUFFIX: &str = "power_limit_uw";
+pub(crate) const CONSTRAINT_TIME_WINDOW_SUFFIX: &str = "time_window_us";
+pub(crate) const CONSTRAINT_MAX_POWER_SUFFIX: &str = "max_power_uw";
+
+/// A RAPL constraint, by its name in the kernel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RaplConstraint {
+    /// PL1, the sustained limit
+    LongTerm,
+    /// PL2, the limit of short bursts
+    ShortTerm,
+}
+
+impl RaplConstraint {
+    /// The constraint named by a `constraint_N_name` file. Others, eg `peak_power`, are
+    /// left alone.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "long_term" => Some(RaplConstraint::LongTerm),
+            "short_term" => Some(RaplConstraint::ShortTerm),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            RaplConstraint::LongTerm => "long_term",
+            RaplConstraint::ShortTerm => "short_term",
+        }
+    }
+}
+
+/// The settings of one constraint of a zone
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaplConstraintLimit {
+    pub constraint: RaplConstraint,
+    pub power_limit_uw: u64,
+    /// Not every constraint has a window of its own
+    pub time_window_us: Option<u64>,
+    /// The highest limit the zone takes, when the kernel tells
+    #[serde(default)]
+    pub max_power_uw: Option<u64>,
+}
+
+/// The limits of one RAPL domain, as read from its zone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaplPowerLimits {
+    pub domain: RaplDomain,
+    pub enabled: bool,
+    pub constraints: Vec<RaplConstraintLimit>,
+}
+
+/// The limits of every domain from before the agent first changed one, kept on disk so
+/// they can be put back even after the agent restarts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaplLimitsSnapshot {
+    pub timestamp: DateTime<Utc>,
+    pub limits: Vec<RaplPowerLimits>,
+}
+
+impl RaplLimitsSnapshot {
+    #[must_use]
+    pub fn new(limits: Vec<RaplPowerLimits>) -> Self {
+        Self { timestamp: Utc::now(), limits }
+    }
+
+    /// # Errors
+    /// If the file can't be written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// # Errors
+    /// If the file can't be read or isn't a snapshot
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+impl RAPL {
+    /// The limits of every domain with an `enabled` file. Domains that can only be
+    /// measured are left out.
+    ///
+    /// # Errors
+    /// If a limit can't be read
+    pub fn power_limits(&self) -> io::Result<Vec<RaplPowerLimits>> {
+        let mut limits = Vec::new();
+        for (domain, path) in self.zone_paths() {
+            if !path.join(ZONE_ENABLED_FILE).exists() {
+                trace!("RAPL zone {} has no limits", path.display());
+                continue;
+            }
+            let mut constraints = Vec::new();
+            for (constraint, index) in zone_constraints(path)? {
+                let time_window = path.join(constraint_file(index, CONSTRAINT_TIME_WINDOW_SUFFIX));
+                constraints.push(RaplConstraintLimit {
+                    constraint,
+                    power_limit_uw: read_value(&path.join(constraint_file(index, CONSTRAINT_POWER_LIMIT_SUFFIX)))?,
+                    time_window_us: if time_window.exists() { Some(read_value(&time_window)?) } else { None },
+                    max_power_uw: read_max_power(path, index),
+                });
+            }
+            let enabled = read_value(&path.join(ZONE_ENABLED_FILE))? != 0;
+            limits.push(RaplPowerLimits { domain, enabled, constraints });
+        }
+        Ok(limits)
+    }
+
+    /// Makes one change to the limits of a domain
+    ///
+    /// # Errors
+    /// `NotFound` if the domain or its constraint doesn't exist, `InvalidInput` if the
+    /// kernel rejects the value, `PermissionDenied` unless run as root
+    pub fn apply_limit(&self, operation: &RaplLimitOperation) -> io::Result<()> {
+        info!("RAPL {operation:?}");
+        match *operation {
+            RaplLimitOperation::SetPowerLimit { domain, constraint, power_limit_uw } => {
+                let file = self.constraint_path(domain, constraint, CONSTRAINT_POWER_LIMIT_SUFFIX)?;
+                write_value(&file, power_limit_uw)
+            }
+            RaplLimitOperation::SetTimeWindow { domain, constraint, time_window_us } => {
+                let file = self.constraint_path(domain, constraint, CONSTRAINT_TIME_WINDOW_SUFFIX)?;
+                write_value(&file, time_window_us)
+            }
+            RaplLimitOperation::SetEnabled { domain, enabled } => {
+                write_value(&self.domain_path(domain)?.join(ZONE_ENABLED_FILE), u64::from(enabled))
+            }
+        }
+    }
+
+    /// Puts back limits read by `power_limits`, each domain's constraints before it's
+    /// enabled or disabled. Every domain is restored even if one fails.
+    ///
+    /// # Errors
+    /// The first limit that couldn't be written
+    pub fn restore_limits(&self, limits: &[RaplPowerLimits]) -> io::Result<()> {
+        let mut result = Ok(());
+        for domain_limits in limits {
+            let domain = domain_limits.domain;
+            let operations = domain_limits.constraints
+                .iter()
+                .flat_map(|limit| {
+                    let constraint = limit.constraint;
+                    let power_limit = RaplLimitOperation::SetPowerLimit { domain, constraint, power_limit_uw: limit.power_limit_uw };
+                    let time_window = limit.time_window_us
+                        .map(|time_window_us| RaplLimitOperation::SetTimeWindow { domain, constraint, time_window_us });
+                    std::iter::once(power_limit).chain(time_window)
+                })
+                .chain(std::iter::once(RaplLimitOperation::SetEnabled { domain, enabled: domain_limits.enabled }));
+            for operation in operations {
+                if let Err(e) = self.apply_limit(&operation) {
+                    error!("RAPL failed to restore {operation:?}: {e}");
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn domain_path(&self, domain: RaplDomain) -> io::Result<&Path> {
+        self.zone_paths()
+            .find(|(zone_domain, _)| *zone_domain == domain)
+            .map(|(_, path)| path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no RAPL zone for {domain}")))
+    }
+
+    fn constraint_path(&self, domain: RaplDomain, constraint: RaplConstraint, suffix: &str) -> io::Result<PathBuf> {
+        let path = self.domain_path(domain)?;
+        zone_constraints(path)?
+            .into_iter()
+            .find(|(zone_constraint, _)| *zone_constraint == constraint)
+            .map(|(_, index)| path.join(constraint_file(index, suffix)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no {} constraint for {domain}", constraint.name())))
+    }
+}
+
+/// The known constraints of a zone and their numbers
+fn zone_constraints(path: &Path) -> io::Result<Vec<(RaplConstraint, u32)>> {
+    let mut constraints = Vec::new();
+    for index in 0.. {
+        let name_file = path.join(constraint_file(index, CONSTRAINT_NAME_SUFFIX));
+        if !name_file.exists() {
+            break;
+        }
+        let name = fs::read_to_string(name_file)?;
+        match RaplConstraint::from_name(name.trim()) {
+            Some(constraint) => constraints.push((constraint, index)),
+            None => trace!("RAPL zone {} constraint {index} '{}' skipped", path.display(), name.trim()),
+        }
+    }
+    Ok(constraints)
+}
+
+pub(crate) fn constraint_file(index: u32, suffix: &str) -> String {
+    format!("constraint_{index}_{suffix}")
+}
+
+/// Some zones have the file but fail to read it, which is just as unknown
+fn read_max_power(zone: &Path, index: u32) -> Option<u64> {
+    let path = zone.join(constraint_file(index, CONSTRAINT_MAX_POWER_SUFFIX));
+    match read_value(&path) {
+        Ok(max_power_uw) => Some(max_power_uw),
+        Err(e) => {
+            trace!("RAPL zone {} constraint {index} has no max power: {e}", zone.display());
+            None
+        }
+    }
+}
+
+fn read_value(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+}
+
+fn write_value(path: &Path, value: u64) -> io::Result<()> {
+    trace!("RAPL writing {value} to {}", path.display());
+    fs::write(path, value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rapl::powercap_fake::FakePowercap;
+    use crate::rapl::rapl::{RaplDomainType, RAPL_CONTROL_TYPES};
+
+    #[test]
+    fn test_read_and_set_limits() {
+        let fake = FakePowercap::xeon("rapl_limits", "intel-rapl", 200.0).unwrap();
+        let rapl = RAPL::new(&fake.config(&RAPL_CONTROL_TYPES));
+        let package = RaplDomain::new(RaplDomainType::Package, Some(1));
+
+        let original = rapl.power_limits().unwrap();
+        assert_eq!(original.len(), 6);
+        assert_eq!(original[3].domain, package);
+        assert!(original[3].enabled);
+        assert_eq!(
+            original[3].constraints,
+            [
+                RaplConstraintLimit {
+                    constraint: RaplConstraint::LongTerm,
+                    power_limit_uw: 250_000_000,
+                    time_window_us: Some(999_424),
+                    max_power_uw: Some(300_000_000),
+                },
+                RaplConstraintLimit {
+                    constraint: RaplConstraint::ShortTerm,
+                    power_limit_uw: 300_000_000,
+                    time_window_us: Some(2_440),
+                    max_power_uw: None,
+                },
+            ]
+        );
+        // dram only has a long term limit
+        assert_eq!(original[1].constraints.len(), 1);
+
+        let constraint = RaplConstraint::LongTerm;
+        rapl.apply_limit(&RaplLimitOperation::SetPowerLimit { domain: package, constraint, power_limit_uw: 150_000_000 }).unwrap();
+        rapl.apply_limit(&RaplLimitOperation::SetTimeWindow { domain: package, constraint, time_window_us: 5_000_000 }).unwrap();
+        rapl.apply_limit(&RaplLimitOperation::SetEnabled { domain: package, enabled: false }).unwrap();
+        let changed = rapl.power_limits().unwrap();
+        assert!(!changed[3].enabled);
+        assert_eq!(changed[3].constraints[0].power_limit_uw, 150_000_000);
+        assert_eq!(changed[3].constraints[0].time_window_us, Some(5_000_000));
+        assert_eq!(changed[0], original[0]);
+
+        let dram = RaplDomain::new(RaplDomainType::Dram, Some(0));
+        let error = rapl.apply_limit(&RaplLimitOperation::SetPowerLimit {
+            domain: dram,
+            constraint: RaplConstraint::ShortTerm,
+            power_limit_uw: 1,
+        });
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        rapl.restore_limits(&original).unwrap();
+        assert_eq!(rapl.power_limits().unwrap(), original);
+    }
+
+    #[test]
+    fn test_unknown_constraints_skipped() {
+        let fake = FakePowercap::new("rapl_peak");
+        let package = fake.add_zone("intel-rapl", 0, "package-0", 100.0, 1_000_000).unwrap();
+        fake.add_constraint(&package, "long_term", 120_000_000, Some(1_000_000)).unwrap();
+        fake.add_constraint(&package, "peak_power", 400_000_000, None).unwrap();
+        fake.add_constraint(&package, "short_term", 150_000_000, None).unwrap();
+
+        let limits = RAPL::new(&fake.config(&["intel-rapl"])).power_limits().unwrap();
+        let constraints: Vec<(RaplConstraint, Option<u64>)> =
+            limits[0].constraints.iter().map(|limit| (limit.constraint, limit.time_window_us)).collect();
+        assert_eq!(constraints, [(RaplConstraint::LongTerm, Some(1_000_000)), (RaplConstraint::ShortTerm, None)]);
+        assert_eq!(limits[0].constraints[0].max_power_uw, None);
+    }
+
+    #[test]
+    fn test_snapshot_save_and_load() {
+        let fake = FakePowercap::xeon("rapl_snapshot", "intel-rapl", 200.0).unwrap();
+        let snapshot = RaplLimitsSnapshot::new(RAPL::new(&fake.config(&["intel-rapl"])).power_limits().unwrap());
+        let path = fake.root().join("rapl_original_limits.json");
+        snapshot.save(&path).unwrap();
+        assert_eq!(RaplLimitsSnapshot::load(&path).unwrap(), snapshot);
+    }
+}
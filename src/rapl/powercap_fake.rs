@@ -0,0 +1,176 @@
+use crate::rapl::power_limit::{
+    constraint_file, CONSTRAINT_MAX_POWER_SUFFIX, CONSTRAINT_NAME_SUFFIX, CONSTRAINT_POWER_LIMIT_SUFFIX,
+    CONSTRAINT_TIME_WINDOW_SUFFIX, ZONE_ENABLED_FILE,
+};
+use crate::rapl::rapl::{PowercapConfig, ZONE_ENERGY_FILE, ZONE_MAX_ENERGY_FILE, ZONE_NAME_FILE};
+use crate::test_util::TempDir;
+use log::trace;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The range of a package counter on a current Xeon, about 262 kJ
+pub const FAKE_MAX_ENERGY_UJ: u64 = 262_143_328_850;
+
+/// A zone of the fake tree and the power its energy counter is scripted to
+#[derive(Debug, Clone)]
+pub struct FakeZone {
+    pub path: PathBuf,
+    pub watts: f64,
+    pub max_energy_uj: u64,
+    pub energy_uj: u64,
+}
+
+/// A fake powercap sysfs tree, so the RAPL code runs off-hardware. Each zone's
+/// energy counter is advanced by its scripted power with `advance`, never by the
+/// clock, and wraps around like the real thing. The tree is removed when the fake is
+/// dropped.
+pub struct FakePowercap {
+    dir: TempDir,
+    zones: Mutex<Vec<FakeZone>>,
+}
+
+impl FakePowercap {
+    /// An empty powercap class directory, named after `name`
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            dir: TempDir::new(name),
+            zones: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A two socket Xeon under `control_type`, where subzone 0 is dram rather than core:
+    /// each package draws `package_watts`, of which dram and core draw a quarter and a half.
+    /// A package is limited to 1.25 × `package_watts` long term, of at most 1.5 ×, and 1.5 ×
+    /// short term, a subzone to its own power long term.
+    ///
+    /// # Errors
+    /// If the tree can't be written
+    pub fn xeon(name: &str, control_type: &str, package_watts: f64) -> io::Result<Self> {
+        let fake = FakePowercap::new(name);
+        for package in 0..2 {
+            let zone = fake.add_zone(control_type, package, &format!("package-{package}"), package_watts, FAKE_MAX_ENERGY_UJ)?;
+            let long_term = fake.add_constraint(&zone, "long_term", microwatts(package_watts * 1.25), Some(999_424))?;
+            fake.set_max_power(&zone, long_term, microwatts(package_watts * 1.5))?;
+            fake.add_constraint(&zone, "short_term", microwatts(package_watts * 1.5), Some(2_440))?;
+            for (index, name, watts) in [(0, "dram", package_watts / 4.0), (1, "core", package_watts / 2.0)] {
+                let subzone = fake.add_subzone(&zone, index, name, watts, FAKE_MAX_ENERGY_UJ)?;
+                fake.add_constraint(&subzone, "long_term", microwatts(watts), Some(976))?;
+            }
+        }
+        Ok(fake)
+    }
+
+    /// The powercap class directory
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The configuration finding this tree's zones
+    #[must_use]
+    pub fn config(&self, control_types: &[&str]) -> PowercapConfig {
+        PowercapConfig::new(self.root(), control_types)
+    }
+
+    /// Adds the top level zone `<control_type>:<index>`, returning its path
+    ///
+    /// # Errors
+    /// If the zone can't be written
+    pub fn add_zone(&self, control_type: &str, index: u64, name: &str, watts: f64, max_energy_uj: u64) -> io::Result<PathBuf> {
+        let path = self.dir.join(control_type).join(format!("{control_type}:{index}"));
+        self.write_zone(path, name, watts, max_energy_uj)
+    }
+
+    /// Adds the subzone `<parent>:<index>`, returning its path
+    ///
+    /// # Errors
+    /// If the subzone can't be written
+    pub fn add_subzone(&self, parent: &Path, index: u64, name: &str, watts: f64, max_energy_uj: u64) -> io::Result<PathBuf> {
+        let parent_name = parent.file_name().unwrap_or_default().to_string_lossy();
+        let path = parent.join(format!("{parent_name}:{index}"));
+        self.write_zone(path, name, watts, max_energy_uj)
+    }
+
+    fn write_zone(&self, path: PathBuf, name: &str, watts: f64, max_energy_uj: u64) -> io::Result<PathBuf> {
+        fs::create_dir_all(&path)?;
+        fs::write(path.join(ZONE_NAME_FILE), format!("{name}\n"))?;
+        fs::write(path.join(ZONE_MAX_ENERGY_FILE), format!("{max_energy_uj}\n"))?;
+        let zone = FakeZone { path: path.clone(), watts, max_energy_uj, energy_uj: 0 };
+        write_energy(&zone)?;
+        self.zones.lock().expect("Fake powercap poisoned").push(zone);
+        Ok(path)
+    }
+
+    /// Adds the next constraint of a zone and enables its limits, returning the
+    /// constraint's number
+    ///
+    /// # Errors
+    /// If the constraint can't be written
+    pub fn add_constraint(&self, zone: &Path, name: &str, power_limit_uw: u64, time_window_us: Option<u64>) -> io::Result<u32> {
+        let mut index = 0;
+        while zone.join(constraint_file(index, CONSTRAINT_NAME_SUFFIX)).exists() {
+            index += 1;
+        }
+        fs::write(zone.join(constraint_file(index, CONSTRAINT_NAME_SUFFIX)), format!("{name}\n"))?;
+        fs::write(zone.join(constraint_file(index, CONSTRAINT_POWER_LIMIT_SUFFIX)), format!("{power_limit_uw}\n"))?;
+        if let Some(time_window_us) = time_window_us {
+            fs::write(zone.join(constraint_file(index, CONSTRAINT_TIME_WINDOW_SUFFIX)), format!("{time_window_us}\n"))?;
+        }
+        fs::write(zone.join(ZONE_ENABLED_FILE), "1\n")?;
+        Ok(index)
+    }
+
+    /// Sets the highest limit a constraint of a zone takes
+    ///
+    /// # Errors
+    /// If the file can't be written
+    pub fn set_max_power(&self, zone: &Path, index: u32, max_power_uw: u64) -> io::Result<()> {
+        fs::write(zone.join(constraint_file(index, CONSTRAINT_MAX_POWER_SUFFIX)), format!("{max_power_uw}\n"))
+    }
+
+    /// Sets a zone's counter, eg just short of its wrap around
+    ///
+    /// # Errors
+    /// If the counter can't be written
+    pub fn set_energy(&self, path: &Path, energy_uj: u64) -> io::Result<()> {
+        let mut zones = self.zones.lock().expect("Fake powercap poisoned");
+        for zone in zones.iter_mut().filter(|zone| zone.path == path) {
+            zone.energy_uj = energy_uj % zone.max_energy_uj;
+            write_energy(zone)?;
+        }
+        Ok(())
+    }
+
+    /// Advances every counter by its zone's power over `elapsed`
+    ///
+    /// # Errors
+    /// If a counter can't be written
+    pub fn advance(&self, elapsed: Duration) -> io::Result<()> {
+        let mut zones = self.zones.lock().expect("Fake powercap poisoned");
+        for zone in zones.iter_mut() {
+            // W × µs = µJ, the fraction of a µJ is lost as in the real counters
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let energy_uj = (zone.watts * elapsed.as_micros() as f64) as u64;
+            zone.energy_uj = (zone.energy_uj + energy_uj) % zone.max_energy_uj;
+            write_energy(zone)?;
+        }
+        trace!("Fake powercap advanced {elapsed:?}");
+        Ok(())
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn microwatts(watts: f64) -> u64 {
+    (watts * 1_000_000.0) as u64
+}
+
+/// Writes the counter through a rename, so a reader never sees a half written file
+fn write_energy(zone: &FakeZone) -> io::Result<()> {
+    let temporary = zone.path.join(format!(".{ZONE_ENERGY_FILE}"));
+    fs::write(&temporary, format!("{}\n", zone.energy_uj))?;
+    fs::rename(temporary, zone.path.join(ZONE_ENERGY_FILE))
+}
@@ -1,48 +1,191 @@
+use crate::rapl::msr::{self, MsrConfig, RaplUnits, MSR_DRAM_ENERGY_STATUS, MSR_PKG_ENERGY_STATUS, MSR_RAPL_POWER_UNIT};
 use chrono::{DateTime, Utc, SecondsFormat};
 use glob::glob;
-use log::trace;
-use std::collections::HashMap;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
-// There is one sub-directory of this directory for each RAPL domain - usually a
-// domain maps to a socket. For each domain energy readings for the core and memory
-// are available in sub-domains. The assumption, based on anecdotal evidence only,
-// is that sub-domain 0 is the processor core. A more rigorous approach would read
-// the name of each sub-domain to identify each part. For future work perhaps?
-const RAPL_GLOB: &str = "/sys/devices/virtual/powercap/intel-rapl/intel-rapl:*";
-const MAX_ENERGY_PATH: &str ="/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0/max_energy_range_uj";
+// Each control type is a directory of the powercap root, eg `intel-rapl`. There is one
+// zone directory in it for each package (socket), and on some platforms one for the
+// whole platform (psys). A package zone has subzones for the parts of the package -
+// core, uncore, dram - in no fixed order, so every zone is identified by the contents
+// of its `name` file.
+pub const POWERCAP_ROOT: &str = "/sys/devices/virtual/powercap";
+pub const RAPL_CONTROL_TYPES: [&str; 2] = ["intel-rapl", "amd-rapl"];
+pub(crate) const ZONE_NAME_FILE: &str = "name";
+pub(crate) const ZONE_ENERGY_FILE: &str = "energy_uj";
+pub(crate) const ZONE_MAX_ENERGY_FILE: &str = "max_energy_range_uj";
+const PACKAGE_ZONE_PREFIX: &str = "package-";
 
+/// Where the RAPL zones are found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowercapConfig {
+    /// The powercap class directory, a fake tree for tests
+    pub root: PathBuf,
+    /// The control types scanned for zones, eg `intel-rapl`
+    pub control_types: Vec<String>,
+}
+
+impl PowercapConfig {
+    #[must_use]
+    pub fn new(root: &Path, control_types: &[&str]) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            control_types: control_types.iter().map(|control_type| String::from(*control_type)).collect(),
+        }
+    }
+}
+
+/// How RAPL is read: from the powercap zones, or straight from the registers where
+/// the powercap driver isn't there
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RaplBackend {
+    #[default]
+    Powercap,
+    Msr,
+}
+
+/// Where RAPL is read from, for the backend chosen when the agent starts
+#[derive(Debug, Clone)]
+pub enum RaplSource {
+    Powercap(PowercapConfig),
+    Msr(MsrConfig),
+}
+
+impl Default for RaplSource {
+    fn default() -> Self {
+        RaplSource::Powercap(PowercapConfig::default())
+    }
+}
+
+impl Default for PowercapConfig {
+    fn default() -> Self {
+        Self::new(Path::new(POWERCAP_ROOT), &RAPL_CONTROL_TYPES)
+    }
+}
+
+/// What a powercap zone measures
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RaplDomainType {
+    Package,
+    Core,
+    /// The integrated graphics of client parts
+    Uncore,
+    Dram,
+    /// The whole platform, not a package
+    Psys,
+}
+
+impl RaplDomainType {
+    /// The type of a zone from its `name`, eg "package-0" or "dram"
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "core" => Some(RaplDomainType::Core),
+            "uncore" => Some(RaplDomainType::Uncore),
+            "dram" => Some(RaplDomainType::Dram),
+            "psys" => Some(RaplDomainType::Psys),
+            _ if name.starts_with(PACKAGE_ZONE_PREFIX) => Some(RaplDomainType::Package),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            RaplDomainType::Package => "package",
+            RaplDomainType::Core => "core",
+            RaplDomainType::Uncore => "uncore",
+            RaplDomainType::Dram => "dram",
+            RaplDomainType::Psys => "psys",
+        }
+    }
+}
+
+/// A RAPL domain: what is measured, and on which package. Platform wide domains
+/// have no package.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RaplDomain {
+    pub domain_type: RaplDomainType,
+    pub package: Option<u64>,
+}
+
+impl RaplDomain {
+    #[must_use]
+    pub fn new(domain_type: RaplDomainType, package: Option<u64>) -> Self {
+        Self { domain_type, package }
+    }
+}
+
+/// The zone names of the kernel, with the package of a subzone added: "package-0",
+/// "core-0", "dram-1", "psys"
+impl Display for RaplDomain {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.package {
+            Some(package) => write!(f, "{}-{package}", self.domain_type.name()),
+            None => write!(f, "{}", self.domain_type.name()),
+        }
+    }
+}
+
+/// Where a zone's energy counter is read
+#[derive(Debug, Clone)]
+enum ZoneCounter {
+    /// The zone directory, eg `.../intel-rapl:0/intel-rapl:0:1`
+    Powercap(PathBuf),
+    /// An energy status register, through the msr device of a CPU of the package. The
+    /// package's throttling counter is read along with its energy, in `time_unit_us`.
+    Msr { device: PathBuf, register: u64, energy_unit_uj: f64, time_unit_us: f64 },
+}
+
+/// A powercap zone or subzone, or an energy status register, and the domain it measures
+#[derive(Debug, Clone)]
+struct RaplZone {
+    domain: RaplDomain,
+    counter: ZoneCounter,
+    /// Where the zone's counter wraps around. Zones of one package have different ranges.
+    max_energy_uj: u64,
+}
 
 // Holds the concrete (non-globbed) RAPL paths
 #[derive(Debug)]
 pub struct RAPL {
-    /// A list of fully-qualified paths for the core energy files for every domain.
-    pkg_paths: HashMap<u64, PathBuf>,
-    core_paths: HashMap<u64, PathBuf>,
+    /// Every zone and subzone, each package followed by its subzones
+    zones: Vec<RaplZone>,
 }
 
 impl Default for RAPL {
     fn default() -> Self {
-        Self::new()
+        Self::new(&PowercapConfig::default())
     }
 }
 
-/// Holds the energy reading for a package or core for a given socket (domain)
+/// Holds the energy reading of one RAPL domain
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone)]
 pub struct RAPL_Reading {
-    /// domain id: "core0", "core1", "pkg1", "pkg2",...
-    pub domain: String,
+    pub domain: RaplDomain,
     /// The reading. For the RAPL object, this reading is in µJ.
-    /// The structure is also used in `monitor/monitor_rapl.rs` when converting energy to
-    /// power, with units in Watts.
     pub reading: u64,
+    /// Where the domain's counter wraps around, in µJ
+    pub max_energy_uj: u64,
+    /// How long RAPL has throttled the package, for packages read through the msr devices
+    pub throttled: Option<ThrottledTime>,
 }
 
+/// The throttling counter of a package, MSR_PKG_PERF_STATUS
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThrottledTime {
+    pub throttled_us: u64,
+    /// Where the counter wraps around
+    pub max_throttled_us: u64,
+}
 
-/// A timestamped collection of energy readings for package and cores on all sockets
+
+/// A timestamped collection of energy readings for every RAPL domain
 #[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub struct RAPL_Readings {
@@ -52,44 +195,173 @@ pub struct RAPL_Readings {
 }
 
 impl RAPL {
+    /// Finds the zones of every control type in `config` that has any
     #[must_use]
-    pub fn new() -> Self {
-        let mut pkg_paths = HashMap::new();
-        let mut core_paths = HashMap::new();
-
-        // This glob pattern picks up all the energy files for the "core" energy
-        // for all of the available domains (i.e. sockets) in the server
-        for glob_result in glob(RAPL_GLOB).expect("RAPL failed to glob directory") {
-            let path = glob_result.expect("RAPL failed to read rapl directory");
-            let domain = RAPL::domain_from_path(&path);
-            pkg_paths.insert(domain, path.join("energy_uj"));
-
-            let core_dir = path.file_name()
-                .expect("Failed to get rapl dir")
-                .to_string_lossy()
-                .to_string() + ":0";
-            core_paths.insert(domain, path.join(core_dir).join("energy_uj"));
+    pub fn new(config: &PowercapConfig) -> Self {
+        let mut zones = Vec::new();
+        for control_type in &config.control_types {
+            let zone_glob = config.root.join(control_type).join(format!("{control_type}:*"));
+            let mut top_zones: Vec<PathBuf> = glob(&zone_glob.to_string_lossy())
+                .expect("RAPL failed to glob directory")
+                .map(|glob_result| glob_result.expect("RAPL failed to read rapl directory"))
+                .collect();
+            // intel-rapl:10 after intel-rapl:9
+            top_zones.sort_by_key(|path| RAPL::domain_from_path(path));
+            RAPL::add_zones(&mut zones, top_zones);
         }
 
-        trace!("RAPL pkg_paths: {pkg_paths:?}");
-        trace!("RAPL core_paths: {core_paths:?}");
-        Self { pkg_paths, core_paths }
+        if zones.is_empty() {
+            warn!("RAPL found no zones of {:?} in {}", config.control_types, config.root.display());
+        }
+        trace!("RAPL zones: {zones:?}");
+        Self { zones }
     }
 
-    /// `read_current_energy`
-    ///
-    /// returns list of core energy values for all domains
+    /// Finds the package and dram energy registers of each package, for hosts without
+    /// the powercap driver. A package whose units can't be read is left out.
     #[must_use]
-    pub fn read_current_energy(&self) -> RAPL_Readings {
-        let mut readings: Vec<RAPL_Reading> = Vec::new();
-        for (pkg_core, label) in [(&self.core_paths, "core"), (&self.pkg_paths, "pkg")] {
-            for (domain_id, path) in pkg_core.iter() {
-                let energy = RAPL::read_energy(path);
-                let domain = format!("{label}{domain_id}");
-                readings.push(RAPL_Reading::new(&domain, energy));
+    pub fn msr(config: &MsrConfig) -> Self {
+        let dram_energy_units = config.dram_energy_units();
+        let mut zones = Vec::new();
+        for (package, device) in msr::package_devices(config) {
+            let units = match msr::read_msr(&device, MSR_RAPL_POWER_UNIT) {
+                Ok(units) => RaplUnits::decode(units),
+                Err(e) => {
+                    warn!("RAPL can't read the units of package {package} from {}: {e}", device.display());
+                    continue;
+                }
+            };
+            let dram_energy_unit_uj = dram_energy_units.map_or(units.energy_uj, msr::energy_unit_uj);
+            let mut registers = vec![(RaplDomainType::Package, MSR_PKG_ENERGY_STATUS, units.energy_uj)];
+            // Not every CPU counts dram energy
+            if msr::read_msr(&device, MSR_DRAM_ENERGY_STATUS).is_ok() {
+                registers.push((RaplDomainType::Dram, MSR_DRAM_ENERGY_STATUS, dram_energy_unit_uj));
+            }
+            for (domain_type, register, energy_unit_uj) in registers {
+                // The registers count in their own units, wrapping at 32 bits
+                zones.push(RaplZone {
+                    domain: RaplDomain::new(domain_type, Some(package)),
+                    counter: ZoneCounter::Msr { device: device.clone(), register, energy_unit_uj, time_unit_us: units.time_us },
+                    max_energy_uj: msr::counter_range(energy_unit_uj),
+                });
             }
         }
-        RAPL_Readings::new(readings)
+
+        if zones.is_empty() {
+            warn!("RAPL found no msr devices in {}", config.msr_root.display());
+        }
+        trace!("RAPL zones: {zones:?}");
+        Self { zones }
+    }
+
+    /// The zones of the backend chosen by `source`
+    #[must_use]
+    pub fn from_source(source: &RaplSource) -> Self {
+        match source {
+            RaplSource::Powercap(config) => RAPL::new(config),
+            RaplSource::Msr(config) => RAPL::msr(config),
+        }
+    }
+
+    /// Adds each top level zone followed by its subzones
+    fn add_zones(zones: &mut Vec<RaplZone>, top_zones: Vec<PathBuf>) {
+        for path in top_zones {
+            let Some(zone) = RAPL::zone(&path, None) else { continue };
+            let package = zone.domain.package;
+            zones.push(zone);
+
+            let subzone_glob = path.join(format!("{}:*", path.file_name().expect("Failed to get rapl dir").to_string_lossy()));
+            for glob_result in glob(&subzone_glob.to_string_lossy()).expect("RAPL failed to glob subzones") {
+                let subzone = glob_result.expect("RAPL failed to read rapl subzone");
+                zones.extend(RAPL::zone(&subzone, package));
+            }
+        }
+    }
+
+    /// Identifies a zone by its name. A package zone is numbered in its name, a
+    /// subzone belongs to the package of its parent. Zones of an unknown type, or
+    /// without a counter range, are left out.
+    fn zone(path: &Path, parent_package: Option<u64>) -> Option<RaplZone> {
+        let name = fs::read_to_string(path.join(ZONE_NAME_FILE))
+            .map_err(|e| warn!("RAPL can't read the name of {}: {e}", path.display()))
+            .ok()?;
+        let name = name.trim();
+        let Some(domain_type) = RaplDomainType::from_name(name) else {
+            warn!("RAPL zone {} has an unknown name '{name}'", path.display());
+            return None;
+        };
+        let package = match domain_type {
+            RaplDomainType::Package => name.strip_prefix(PACKAGE_ZONE_PREFIX).and_then(|id| id.parse().ok()),
+            _ => parent_package,
+        };
+        // The energy counters wrap-around on reaching max energy
+        // this happens sufficiently frequently (typically 5-10 minutes)
+        // that it has to be handled.
+        let max_energy = fs::read_to_string(path.join(ZONE_MAX_ENERGY_FILE)).ok();
+        let Some(max_energy_uj) = max_energy.and_then(|max_energy| max_energy.trim().parse().ok()) else {
+            warn!("RAPL can't read the counter range of {}", path.display());
+            return None;
+        };
+        Some(RaplZone {
+            domain: RaplDomain::new(domain_type, package),
+            counter: ZoneCounter::Powercap(PathBuf::from(path)),
+            max_energy_uj,
+        })
+    }
+
+    /// The domains read by `read_current_energy`, in the order of its readings
+    #[must_use]
+    pub fn domains(&self) -> Vec<RaplDomain> {
+        self.zones.iter().map(|zone| zone.domain).collect()
+    }
+
+    /// Each powercap domain and its zone directory
+    pub(crate) fn zone_paths(&self) -> impl Iterator<Item = (RaplDomain, &Path)> {
+        self.zones.iter().filter_map(|zone| match &zone.counter {
+            ZoneCounter::Powercap(path) => Some((zone.domain, path.as_path())),
+            ZoneCounter::Msr { .. } => None,
+        })
+    }
+
+    /// Each package read through an msr device, and the device
+    pub(crate) fn msr_packages(&self) -> impl Iterator<Item = (RaplDomain, &Path)> {
+        self.zones.iter().filter_map(|zone| match &zone.counter {
+            ZoneCounter::Msr { device, register: MSR_PKG_ENERGY_STATUS, .. } => Some((zone.domain, device.as_path())),
+            _ => None,
+        })
+    }
+
+    /// `read_current_energy`
+    ///
+    /// returns the energy counters of all domains, and the throttling counters of the
+    /// packages read through the msr devices
+    ///
+    /// # Errors
+    /// If an energy counter can't be read. A throttling counter that can't be read is
+    /// left out.
+    pub fn read_current_energy(&self) -> io::Result<RAPL_Readings> {
+        let readings = self.zones
+            .iter()
+            .map(|zone| match &zone.counter {
+                ZoneCounter::Powercap(path) => {
+                    Ok(RAPL_Reading::new(zone.domain, RAPL::read_energy(&path.join(ZONE_ENERGY_FILE))?, zone.max_energy_uj))
+                }
+                ZoneCounter::Msr { device, register, energy_unit_uj, time_unit_us } => {
+                    let reading = RAPL_Reading::new(zone.domain, msr::read_energy(device, *register, *energy_unit_uj)?, zone.max_energy_uj);
+                    if *register != MSR_PKG_ENERGY_STATUS {
+                        return Ok(reading);
+                    }
+                    match msr::read_throttled_us(device, *time_unit_us) {
+                        Ok(throttled_us) => Ok(reading.with_throttled(throttled_us, msr::counter_range(*time_unit_us))),
+                        Err(e) => {
+                            trace!("RAPL can't read the throttling of {}: {e}", zone.domain);
+                            Ok(reading)
+                        }
+                    }
+                }
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(RAPL_Readings::new(readings))
     }
 
     // class method
@@ -100,7 +372,6 @@ impl RAPL {
             .file_name()
             .expect("RAPL failed to get directory name")
             .to_string_lossy()
-            .to_string()
             .split(':')
             .nth(1)
             .expect("Didn't find a colon separator in path")
@@ -109,36 +380,29 @@ impl RAPL {
     }
 
     // 64-bits are enough - max energy typically in 36-bits
-    fn read_energy(path: &PathBuf) -> u64 {
-        fs::read_to_string(path)
-        .expect("Failed to read energy file")
-        .trim()
-        .parse()
-        .expect("Failed to parse energy reading")
-    }
-
-    pub fn max_energy() -> u64 {
-        // The energy counters wrap-around on reaching max energy
-        // this happens sufficiently frequently (typically 5-10 minutes)
-        // that it has to be handled. The wrap-around value is provided
-        // in the max-energy file. This routine reads and returns that value.
-
-        fs::read_to_string(MAX_ENERGY_PATH)
-            .expect("Failed to read max energy file")
+    fn read_energy(path: &Path) -> io::Result<u64> {
+        fs::read_to_string(path)?
             .trim()
             .parse()
-            .expect("Failed to parse max energy reading")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display())))
     }
 
-    pub fn domain_count() -> u64 {
-        glob(RAPL_GLOB).expect("RAPL failed to glob directory").count() as u64
+    #[must_use]
+    pub fn domain_count(&self) -> u64 {
+        self.zones.len() as u64
     }
 }
 
 impl RAPL_Reading {
     #[must_use]
-    pub fn new(domain: &str, reading: u64) -> Self {
-        Self { domain: String::from(domain), reading }
+    pub fn new(domain: RaplDomain, reading: u64, max_energy_uj: u64) -> Self {
+        Self { domain, reading, max_energy_uj, throttled: None }
+    }
+
+    #[must_use]
+    pub fn with_throttled(mut self, throttled_us: u64, max_throttled_us: u64) -> Self {
+        self.throttled = Some(ThrottledTime { throttled_us, max_throttled_us });
+        self
     }
 }
 
@@ -184,6 +448,10 @@ impl Display for RAPL_Readings {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rapl::msr_fake::FakeMsr;
+    use crate::rapl::powercap_fake::{FakePowercap, FAKE_MAX_ENERGY_UJ};
+    use crate::test_util::TempDir;
+    use std::time::Duration;
     #[test]
     fn test_domain0_from_path() {
         let rapl_filename = "/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0";
@@ -199,4 +467,116 @@ mod tests {
         rapl_path.push(rapl_filename);
         assert_eq!(RAPL::domain_from_path(&rapl_path), 16);
     }
+
+    #[test]
+    fn test_zone_names() {
+        assert_eq!(RaplDomainType::from_name("package-1"), Some(RaplDomainType::Package));
+        assert_eq!(RaplDomainType::from_name("dram"), Some(RaplDomainType::Dram));
+        assert_eq!(RaplDomainType::from_name("psys"), Some(RaplDomainType::Psys));
+        assert_eq!(RaplDomainType::from_name("mmio"), None);
+
+        assert_eq!(RaplDomain::new(RaplDomainType::Package, Some(1)).to_string(), "package-1");
+        assert_eq!(RaplDomain::new(RaplDomainType::Dram, Some(0)).to_string(), "dram-0");
+        assert_eq!(RaplDomain::new(RaplDomainType::Psys, None).to_string(), "psys");
+    }
+
+    #[test]
+    fn test_zone_from_name() {
+        // A Xeon package whose first subzone is dram rather than core
+        let dir = TempDir::new("rapl_zone");
+        let package = dir.join("intel-rapl:1");
+        let subzone = package.join("intel-rapl:1:0");
+        fs::create_dir_all(&subzone).unwrap();
+        fs::write(package.join(ZONE_NAME_FILE), "package-1\n").unwrap();
+        fs::write(subzone.join(ZONE_NAME_FILE), "dram\n").unwrap();
+        fs::write(package.join(ZONE_MAX_ENERGY_FILE), "262143328850\n").unwrap();
+        fs::write(subzone.join(ZONE_MAX_ENERGY_FILE), "65712999613\n").unwrap();
+
+        let zone = RAPL::zone(&package, None).unwrap();
+        assert_eq!(zone.domain, RaplDomain::new(RaplDomainType::Package, Some(1)));
+        let zone = RAPL::zone(&subzone, zone.domain.package).unwrap();
+        assert_eq!(zone.domain, RaplDomain::new(RaplDomainType::Dram, Some(1)));
+        assert_eq!(zone.max_energy_uj, 65_712_999_613);
+
+        fs::remove_file(subzone.join(ZONE_MAX_ENERGY_FILE)).unwrap();
+        assert!(RAPL::zone(&subzone, Some(1)).is_none());
+
+        fs::write(subzone.join(ZONE_MAX_ENERGY_FILE), "65712999613\n").unwrap();
+        fs::write(subzone.join(ZONE_NAME_FILE), "mmio\n").unwrap();
+        assert!(RAPL::zone(&subzone, Some(1)).is_none());
+        assert!(RAPL::zone(dir.path(), None).is_none());
+    }
+    #[test]
+    fn test_discover_fake_powercap() {
+        let fake = FakePowercap::xeon("rapl_discover", "intel-rapl", 200.0).unwrap();
+        fake.add_zone("intel-rapl", 2, "psys", 500.0, FAKE_MAX_ENERGY_UJ).unwrap();
+
+        let rapl = RAPL::new(&fake.config(&RAPL_CONTROL_TYPES));
+        let domains: Vec<String> = rapl.domains().iter().map(ToString::to_string).collect();
+        assert_eq!(domains, ["package-0", "dram-0", "core-0", "package-1", "dram-1", "core-1", "psys"]);
+
+        // 200W for half a second
+        fake.advance(Duration::from_millis(500)).unwrap();
+        let readings = rapl.read_current_energy().unwrap();
+        assert_eq!(readings.readings[0].reading, 100_000_000);
+        assert_eq!(readings.readings[0].max_energy_uj, FAKE_MAX_ENERGY_UJ);
+        assert_eq!(readings.readings[2].reading, 50_000_000);
+
+        // Only the control types asked for
+        assert_eq!(RAPL::new(&fake.config(&["amd-rapl"])).domain_count(), 0);
+    }
+
+    #[test]
+    fn test_discover_amd_rapl() {
+        let fake = FakePowercap::new("rapl_amd");
+        let package = fake.add_zone("amd-rapl", 0, "package-0", 150.0, FAKE_MAX_ENERGY_UJ).unwrap();
+        fake.add_subzone(&package, 0, "core", 100.0, FAKE_MAX_ENERGY_UJ).unwrap();
+
+        let rapl = RAPL::new(&fake.config(&RAPL_CONTROL_TYPES));
+        assert_eq!(
+            rapl.domains(),
+            [RaplDomain::new(RaplDomainType::Package, Some(0)), RaplDomain::new(RaplDomainType::Core, Some(0))]
+        );
+        assert_eq!(RAPL::new(&PowercapConfig::new(&fake.root().join("missing"), &RAPL_CONTROL_TYPES)).domain_count(), 0);
+    }
+
+    #[test]
+    fn test_discover_fake_msr() {
+        let fake = FakeMsr::xeon("rapl_msr").unwrap();
+        // 1J in the package's units and in the 2^-16 J units of a Xeon's dram
+        fake.write(0, MSR_PKG_ENERGY_STATUS, 16_384).unwrap();
+        fake.write(0, MSR_DRAM_ENERGY_STATUS, 65_536).unwrap();
+        // Only the first CPU of a package is read, and only the low 32 bits
+        fake.write(1, MSR_PKG_ENERGY_STATUS, 1).unwrap();
+        fake.write(2, MSR_PKG_ENERGY_STATUS, 0xabcd_0000_8000).unwrap();
+
+        // The dram units come from the CPU model
+        let rapl = RAPL::msr(&fake.config());
+        let domains: Vec<String> = rapl.domains().iter().map(ToString::to_string).collect();
+        assert_eq!(domains, ["package-0", "dram-0", "package-1"]);
+        let readings = rapl.read_current_energy().unwrap().readings;
+        assert_eq!((readings[0].reading, readings[0].max_energy_uj), (1_000_000, 262_144_000_000));
+        assert_eq!((readings[1].reading, readings[1].max_energy_uj), (1_000_000, 65_536_000_000));
+        assert_eq!(readings[2].reading, 2_000_000);
+        // Packages carry their throttling counter, dram doesn't
+        assert_eq!(readings[0].throttled, Some(ThrottledTime { throttled_us: 0, max_throttled_us: 4_194_304_000_000 }));
+        assert_eq!(readings[1].throttled, None);
+
+        // Other CPUs count dram in the package's units, unless told otherwise
+        fake.set_cpuinfo("vendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 158\n").unwrap();
+        let readings = RAPL::msr(&fake.config()).read_current_energy().unwrap().readings;
+        assert_eq!(readings[1].reading, 4_000_000);
+        let readings = RAPL::msr(&fake.config().with_dram_energy_status_units(Some(16))).read_current_energy().unwrap().readings;
+        assert_eq!(readings[1].reading, 1_000_000);
+
+        // A counter that can't be read fails the whole sample
+        fs::remove_file(fake.device(2)).unwrap();
+        assert!(rapl.read_current_energy().is_err());
+
+        // Nothing to set through powercap
+        assert!(rapl.power_limits().unwrap().is_empty());
+        let empty = TempDir::new("rapl_msr_empty");
+        let source = RaplSource::Msr(MsrConfig::new(&empty.join("missing"), &empty.join("missing")));
+        assert_eq!(RAPL::from_source(&source).domain_count(), 0);
+    }
 }
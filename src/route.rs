@@ -1,14 +1,114 @@
-use axum::{routing::{get, post}, Router};
+use axum::{extract::FromRef, routing::{get, post}, Router};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crate::bmc::controller::PowerCapController;
+use crate::firestarter::FIRESTARTER_PATH;
+use crate::rapl::power_limit::RaplPowerLimits;
+use crate::rapl::rapl::RaplSource;
 use crate::handlers::{
     system_info_handler::system_info_handler,
     run_test_handler::run_test_handler,
+    stop_test_handler::stop_test_handler,
+    bmc_handler::{
+        bmc_cap_handler, bmc_cap_settings_handler, bmc_capabilities_handler, bmc_enhanced_periods_handler,
+        bmc_enhanced_reading_handler, bmc_power_reading_handler, bmc_sel_handler, bmc_sensors_handler,
+    },
+    rapl_limit_handler::{rapl_limit_handler, rapl_limits_handler, rapl_msr_status_handler, rapl_restore_handler},
     fallback_handler::fallback
 };
 
+// The in-band BMC, see `AgentProxyBMC`
+pub const BMC_POWER_READING_PATH: &str = "/api/bmc/power_reading";
+pub const BMC_ENHANCED_PERIODS_PATH: &str = "/api/bmc/enhanced_power_periods";
+/// Followed by the period in seconds
+pub const BMC_ENHANCED_READING_PATH: &str = "/api/bmc/enhanced_power_reading";
+pub const BMC_CAP_SETTINGS_PATH: &str = "/api/bmc/cap_settings";
+pub const BMC_CAPABILITIES_PATH: &str = "/api/bmc/capabilities";
+pub const BMC_CAP_PATH: &str = "/api/bmc/cap";
+pub const BMC_SENSORS_PATH: &str = "/api/bmc/sensors";
+pub const BMC_SEL_PATH: &str = "/api/bmc/sel";
 
-pub fn create_router() -> Router {
-    Router::new()
+// The RAPL limits of the agent's host, see `RaplCapController`
+pub const RAPL_LIMITS_PATH: &str = "/api/rapl/limits";
+pub const RAPL_LIMIT_PATH: &str = "/api/rapl/limit";
+pub const RAPL_RESTORE_PATH: &str = "/api/rapl/restore";
+pub const RAPL_MSR_STATUS_PATH: &str = "/api/rapl/msr_status";
+
+
+/// The BMC of the agent's host, served to clients that can't reach it over the network
+#[derive(Clone)]
+pub struct BmcState {
+    pub bmc: Arc<dyn PowerCapController>,
+    /// Whether clients may set the exception action that powers the host off
+    pub allow_hard_power_off: bool,
+}
+
+/// What the handlers need to know about the agent's host
+#[derive(Clone)]
+pub struct AgentState {
+    /// Only served when the agent is started with `--serve-power-control`
+    pub bmc: Option<BmcState>,
+    /// Where RAPL is read from
+    pub rapl: Arc<RaplSource>,
+    pub firestarter: Arc<String>,
+    /// Whether clients may change the RAPL limits, only with `--serve-power-control`
+    pub serve_rapl_limits: bool,
+    /// The RAPL limits from before the first change, until they're restored
+    pub rapl_original_limits: Arc<Mutex<Option<Vec<RaplPowerLimits>>>>,
+    /// Where the original RAPL limits are kept meanwhile, if anywhere
+    pub rapl_limits_file: Option<Arc<PathBuf>>,
+}
+
+/// No BMC, the default RAPL zones and firestarter
+impl Default for AgentState {
+    fn default() -> Self {
+        Self {
+            bmc: None,
+            rapl: Arc::new(RaplSource::default()),
+            firestarter: Arc::new(String::from(FIRESTARTER_PATH)),
+            serve_rapl_limits: false,
+            rapl_original_limits: Arc::new(Mutex::new(None)),
+            rapl_limits_file: None,
+        }
+    }
+}
+
+impl FromRef<BmcState> for Arc<dyn PowerCapController> {
+    fn from_ref(state: &BmcState) -> Self {
+        state.bmc.clone()
+    }
+}
+
+
+pub fn create_router(state: AgentState) -> Router {
+    let mut router = Router::new()
         .route("/api/system_info", get(system_info_handler))
         .route("/api/run_test", post(run_test_handler))
-        .fallback(fallback)
+        .route("/api/stop_test", post(stop_test_handler))
+        .route(RAPL_LIMITS_PATH, get(rapl_limits_handler))
+        .route(RAPL_MSR_STATUS_PATH, get(rapl_msr_status_handler));
+    if state.serve_rapl_limits {
+        router = router
+            .route(RAPL_LIMIT_PATH, post(rapl_limit_handler))
+            .route(RAPL_RESTORE_PATH, post(rapl_restore_handler));
+    }
+    let router = router.fallback(fallback).with_state(state.clone());
+    match state.bmc {
+        Some(bmc) => router.merge(bmc_router(bmc)),
+        None => router,
+    }
+}
+
+/// The routes of the in-band BMC
+fn bmc_router(state: BmcState) -> Router {
+    Router::new()
+        .route(BMC_POWER_READING_PATH, get(bmc_power_reading_handler))
+        .route(BMC_ENHANCED_PERIODS_PATH, get(bmc_enhanced_periods_handler))
+        .route(&format!("{BMC_ENHANCED_READING_PATH}/:period_secs"), get(bmc_enhanced_reading_handler))
+        .route(BMC_CAP_SETTINGS_PATH, get(bmc_cap_settings_handler))
+        .route(BMC_CAPABILITIES_PATH, get(bmc_capabilities_handler))
+        .route(BMC_CAP_PATH, post(bmc_cap_handler))
+        .route(BMC_SENSORS_PATH, get(bmc_sensors_handler))
+        .route(BMC_SEL_PATH, get(bmc_sel_handler))
+        .with_state(state)
 }
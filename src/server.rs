@@ -1,11 +1,20 @@
-use crate::route::create_router;
+#![allow(clippy::empty_line_after_outer_attr)]
+
+use crate::bmc::controller::PowerCapController;
+use crate::rapl::power_limit::RaplLimitsSnapshot;
+use crate::rapl::rapl::RaplSource;
+use crate::route::{create_router, AgentState, BmcState};
 use axum;
+use log::{error, warn};
 use std::fmt;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 
 pub struct Server {
     pub listen_address: SocketAddr,
+    pub state: AgentState,
 }
 
 
@@ -16,13 +25,55 @@ impl Server {
                 .expect("Failed to parse listend address {listen_address}")
                 .next()
                 .expect("Failed to get first socket address"),
+            state: AgentState::default(),
+        }
+    }
+
+    /// Serves `bmc` to clients, letting them read and cap it. Only with
+    /// `allow_hard_power_off` may they make it power the host off.
+    #[must_use]
+    pub fn with_bmc(mut self, bmc: Arc<dyn PowerCapController>, allow_hard_power_off: bool) -> Self {
+        self.state.bmc = Some(BmcState { bmc, allow_hard_power_off });
+        self
+    }
+
+    /// Reads RAPL from other zones than the Intel and AMD ones in sysfs, or from the
+    /// msr devices
+    #[must_use]
+    pub fn with_rapl(mut self, rapl: RaplSource) -> Self {
+        self.state.rapl = Arc::new(rapl);
+        self
+    }
+
+    /// Lets clients change the RAPL limits. The limits from before the first change are
+    /// kept in `limits_file` until they're restored, and picked up from there should the
+    /// agent restart before then.
+    #[must_use]
+    pub fn with_rapl_limits(mut self, limits_file: &Path) -> Self {
+        self.state.serve_rapl_limits = true;
+        if limits_file.exists() {
+            match RaplLimitsSnapshot::load(limits_file) {
+                Ok(snapshot) => {
+                    warn!("RAPL limits from {} left to restore, saved {}", limits_file.display(), snapshot.timestamp);
+                    self.state.rapl_original_limits = Arc::new(Mutex::new(Some(snapshot.limits)));
+                }
+                Err(e) => error!("Ignoring the RAPL limits in {}: {e}", limits_file.display()),
+            }
         }
+        self.state.rapl_limits_file = Some(Arc::new(limits_file.to_path_buf()));
+        self
+    }
+
+    #[must_use]
+    pub fn with_firestarter(mut self, firestarter: &str) -> Self {
+        self.state.firestarter = Arc::new(String::from(firestarter));
+        self
     }
 
     pub async fn run(&self) {
         println!("🚀 Server starting on {}", self);
         axum::Server::bind(&self.to_string().parse().unwrap())
-            .serve(create_router().into_make_service())
+            .serve(create_router(self.state.clone()).into_make_service())
             .await
             .unwrap();
     }
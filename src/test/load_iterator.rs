@@ -2,7 +2,7 @@ use enum_iterator::{all, All};
 use itertools::{iproduct, ConsTuples, Itertools, Permutations, Product};
 use std::vec;
 
-use crate::test::{POWER_HIGH, POWER_LOW, CappingOrder, Operation, CapStep, Test};
+use crate::test::{CORRECTION_TIMES_MILLIS, CapLevel, CapMechanism, CappingOrder, Operation, CapStep, Test};
 
 impl LoadTestSuite {
     pub fn new() -> Self {
@@ -12,10 +12,12 @@ impl LoadTestSuite {
                 all::<CappingOrder>(),
                 all::<Operation>(),
                 all::<CapStep>(),
-                vec![POWER_LOW, POWER_HIGH].into_iter().permutations(2),
+                vec![CapLevel::Low, CapLevel::High].into_iter().permutations(2),
                 loads,
                 // vec![10_000, 1_000_000]
-                vec![10_000]
+                vec![10_000],
+                CORRECTION_TIMES_MILLIS.to_vec(),
+                all::<CapMechanism>()
             ),
         }
     }
@@ -31,18 +33,20 @@ impl Iterator for LoadTestSuite {
     type Item = Test;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((capping_order, operation, step, power_levels, load_pct, load_period)) =
+        if let Some((capping_order, operation, step, power_levels, load_pct, load_period, correction_time_millis, cap_mechanism)) =
             self.iter.next()
         {
             return Some(Test {
                 capping_order,
                 operation,
                 step,
-                cap_from: power_levels[0],
-                cap_to: power_levels[1],
+                cap_from: cap_mechanism.power(power_levels[0]),
+                cap_to: cap_mechanism.power(power_levels[1]),
                 load_pct,
                 load_period,
-                n_threads: 0
+                n_threads: 0,
+                correction_time_millis,
+                cap_mechanism,
             });
         }
         None
@@ -50,11 +54,13 @@ impl Iterator for LoadTestSuite {
 }
 
 type OrderOperationStepTuple = ((CappingOrder, Operation), CapStep);
-type OrderOperationStepPowerTuple = ((CappingOrder, Operation, CapStep), Vec<u64>);
-type OrderOperationStepPowerTupleLoadTuple = ((CappingOrder, Operation, CapStep, Vec<u64>), u64);
-type OrderOperationStepPowerTupleLoadPeriodTuple = ((CappingOrder, Operation, CapStep, Vec<u64>, u64), u64);
+type OrderOperationStepPowerTuple = ((CappingOrder, Operation, CapStep), Vec<CapLevel>);
+type OrderOperationStepPowerTupleLoadTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>), u64);
+type OrderOperationStepPowerTupleLoadPeriodTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>, u64), u64);
+type OrderOperationStepPowerTupleLoadPeriodCorrectionTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>, u64, u64), u64);
+type OrderOperationStepPowerTupleLoadPeriodCorrectionMechanismTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>, u64, u64, u64), CapMechanism);
 
-type PowerPermutations = Permutations<IterU64>;
+type PowerPermutations = Permutations<vec::IntoIter<CapLevel>>;
 type IterU64 = vec::IntoIter<u64>;
 
 type OrderOperation = Product<All<CappingOrder>, All<Operation>>;
@@ -62,14 +68,18 @@ type OrderOperationStep = Product<OrderOperation, All<CapStep>>;
 type OrderOperationStepPower = Product<OrderOperationStepIter, PowerPermutations>;
 type OrderOperationStepPowerLoad = Product<OrderOperationStepPowerIter, IterU64>;
 type OrderOperationStepPowerLoadPeriod = Product<OrderOperationsStepPowerLoadIter, IterU64>;
+type OrderOperationStepPowerLoadPeriodCorrection = Product<OrderOperationsStepPowerLoadPeriodIter, IterU64>;
+type OrderOperationStepPowerLoadPeriodCorrectionMechanism = Product<OrderOperationsStepPowerLoadPeriodCorrectionIter, All<CapMechanism>>;
 
 type OrderOperationStepIter = ConsTuples<OrderOperationStep, OrderOperationStepTuple>;
 type OrderOperationStepPowerIter = ConsTuples<OrderOperationStepPower, OrderOperationStepPowerTuple>;
 type OrderOperationsStepPowerLoadIter = ConsTuples<OrderOperationStepPowerLoad, OrderOperationStepPowerTupleLoadTuple>;
 type OrderOperationsStepPowerLoadPeriodIter = ConsTuples<OrderOperationStepPowerLoadPeriod, OrderOperationStepPowerTupleLoadPeriodTuple>;
+type OrderOperationsStepPowerLoadPeriodCorrectionIter = ConsTuples<OrderOperationStepPowerLoadPeriodCorrection, OrderOperationStepPowerTupleLoadPeriodCorrectionTuple>;
+type OrderOperationsStepPowerLoadPeriodCorrectionMechanismIter = ConsTuples<OrderOperationStepPowerLoadPeriodCorrectionMechanism, OrderOperationStepPowerTupleLoadPeriodCorrectionMechanismTuple>;
 
 
 pub struct LoadTestSuite {
-    pub iter: OrderOperationsStepPowerLoadPeriodIter,
+    pub iter: OrderOperationsStepPowerLoadPeriodCorrectionMechanismIter,
 }
 
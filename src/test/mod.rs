@@ -3,6 +3,9 @@ pub mod thread_iterator;
 
 use crate::Timestamps;
 use crate::model::ServerInfo;
+use crate::bmc::monitor_bmc::MonitorTicks;
+use crate::bmc::node_manager::{NmDomain, NmStatistics};
+use crate::bmc::verify::CapAcceptance;
 
 use enum_iterator::Sequence;
 use serde::{Serialize, Deserialize};
@@ -11,8 +14,19 @@ use chrono::{DateTime, Utc};
 // pub const LOAD_PERIODS_US: [u64; 2] = [10_000, 1_000_000];
 pub const LOAD_PERIODS_US: [u64; 1] = [10_000];
 
+/// Time the BMC is given to bring power under a new cap. Settling time depends on it directly.
+pub const CORRECTION_TIMES_MILLIS: [u64; 2] = [1_000, 5_000];
+
 pub const POWER_HIGH: u64 = 580;
 pub const POWER_LOW: u64 = 230;
+/// The Node Manager domains only draw part of the platform power, and are capped within their own range
+pub const NM_CPU_POWER_HIGH: u64 = 300;
+pub const NM_CPU_POWER_LOW: u64 = 150;
+pub const NM_MEMORY_POWER_HIGH: u64 = 60;
+pub const NM_MEMORY_POWER_LOW: u64 = 30;
+/// The RAPL limits are set on each package, within the range of its long term constraint
+pub const RAPL_PACKAGE_POWER_HIGH: u64 = 180;
+pub const RAPL_PACKAGE_POWER_LOW: u64 = 100;
 
 type Timestamp = DateTime<Utc>;
 
@@ -26,6 +40,8 @@ pub struct Test {
     pub load_pct: u64,
     pub load_period: u64,
     pub n_threads: u64,
+    pub correction_time_millis: u64,
+    pub cap_mechanism: CapMechanism,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -41,9 +57,29 @@ pub struct TestRun {
     pub load_pct: u64,
     pub load_period: u64,
     pub n_threads: u64,
+    pub correction_time_millis: u64,
+    /// Runs from before capping mechanisms were compared were all DCMI
+    #[serde(default)]
+    pub cap_mechanism: CapMechanism,
+    /// How long the BMC took to report each cap change, when read-back is enabled
+    #[serde(default)]
+    pub cap_acceptances: Vec<CapAcceptance>,
+    /// BMC clock minus host clock, to align the BMC stats with the RAPL stats
+    #[serde(default)]
+    pub bmc_clock_offset_millis: Option<i64>,
+    /// Sample period of the BMC stats and the ticks missed by slow reads
+    #[serde(default)]
+    pub bmc_ticks: MonitorTicks,
+    /// Sample period of the sensor records and the ticks missed by slow reads
+    #[serde(default)]
+    pub sensor_ticks: MonitorTicks,
+    /// Node Manager's own statistics of the policy under test
+    #[serde(default)]
+    pub nm_statistics: Option<NmStatistics>,
 }
 
 impl TestRun {
+    /// The run of `test`, without the measurements that come with it
     pub fn new(timestamps: Timestamps, test: Test) -> Self {
         Self {
             start_timestamp: timestamps.0,
@@ -57,6 +93,13 @@ impl TestRun {
             load_pct: test.load_pct,
             load_period: test.load_period,
             n_threads: test.n_threads,
+            correction_time_millis: test.correction_time_millis,
+            cap_mechanism: test.cap_mechanism,
+            cap_acceptances: Vec::new(),
+            bmc_clock_offset_millis: None,
+            bmc_ticks: MonitorTicks::default(),
+            sensor_ticks: MonitorTicks::default(),
+            nm_statistics: None,
         }
     }
 }
@@ -84,6 +127,52 @@ pub enum CappingOrder {
     LevelToLevelActivate,
 }
 
+/// What the cap is applied through: the DCMI platform limit, a Node Manager policy
+/// on one domain, or the RAPL limits of the packages, set by the agent
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Sequence, Serialize, Deserialize, clap::ValueEnum)]
+pub enum CapMechanism {
+    #[default]
+    Dcmi,
+    NmPlatform,
+    NmCpu,
+    NmMemory,
+    Rapl,
+}
+
+impl CapMechanism {
+    #[must_use]
+    pub fn nm_domain(self) -> Option<NmDomain> {
+        match self {
+            CapMechanism::Dcmi | CapMechanism::Rapl => None,
+            CapMechanism::NmPlatform => Some(NmDomain::Platform),
+            CapMechanism::NmCpu => Some(NmDomain::Cpu),
+            CapMechanism::NmMemory => Some(NmDomain::Memory),
+        }
+    }
+
+    /// The cap of `level` for what the mechanism limits
+    #[must_use]
+    pub fn power(self, level: CapLevel) -> u64 {
+        match (self, level) {
+            (CapMechanism::Dcmi | CapMechanism::NmPlatform, CapLevel::Low) => POWER_LOW,
+            (CapMechanism::Dcmi | CapMechanism::NmPlatform, CapLevel::High) => POWER_HIGH,
+            (CapMechanism::NmCpu, CapLevel::Low) => NM_CPU_POWER_LOW,
+            (CapMechanism::NmCpu, CapLevel::High) => NM_CPU_POWER_HIGH,
+            (CapMechanism::NmMemory, CapLevel::Low) => NM_MEMORY_POWER_LOW,
+            (CapMechanism::NmMemory, CapLevel::High) => NM_MEMORY_POWER_HIGH,
+            (CapMechanism::Rapl, CapLevel::Low) => RAPL_PACKAGE_POWER_LOW,
+            (CapMechanism::Rapl, CapLevel::High) => RAPL_PACKAGE_POWER_HIGH,
+        }
+    }
+}
+
+/// The two caps each test moves between, in the Watts of its mechanism
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CapLevel {
+    Low,
+    High,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Sequence, Serialize, Deserialize)]
 pub enum Operation {
     Activate,
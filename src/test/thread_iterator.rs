@@ -2,10 +2,10 @@ use enum_iterator::{all, All};
 use itertools::{iproduct, ConsTuples, Itertools, Permutations, Product};
 use std::vec;
 
-use crate::test::{POWER_HIGH, POWER_LOW, CappingOrder, Operation, CapStep, Test};
+use crate::test::{CORRECTION_TIMES_MILLIS, CapLevel, CapMechanism, CappingOrder, Operation, CapStep, Test};
 
 pub struct ThreadTestSuite {
-    pub iter: OrderOperationStepPowerThreadsIter,
+    pub iter: OrderOperationStepPowerThreadsCorrectionMechanismIter,
 }
 
 impl ThreadTestSuite {
@@ -16,8 +16,10 @@ impl ThreadTestSuite {
                 all::<CappingOrder>(),
                 all::<Operation>(),
                 all::<CapStep>(),
-                vec![POWER_LOW, POWER_HIGH].into_iter().permutations(2),
-                n_threads
+                vec![CapLevel::Low, CapLevel::High].into_iter().permutations(2),
+                n_threads,
+                CORRECTION_TIMES_MILLIS.to_vec(),
+                all::<CapMechanism>()
             ),
         }
     }
@@ -27,17 +29,19 @@ impl Iterator for ThreadTestSuite {
     type Item = Test;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((capping_order, operation, step, power_levels, n_threads)) =
+        if let Some((capping_order, operation, step, power_levels, n_threads, correction_time_millis, cap_mechanism)) =
             self.iter.next() {
                 return Some(Self::Item {
                     capping_order,
                     operation,
                     step,
-                    cap_from: power_levels[0],
-                    cap_to: power_levels[1],
+                    cap_from: cap_mechanism.power(power_levels[0]),
+                    cap_to: cap_mechanism.power(power_levels[1]),
                     load_pct: 100,
                     load_period: 0,
-                    n_threads
+                    n_threads,
+                    correction_time_millis,
+                    cap_mechanism,
                 }
             );
         }
@@ -60,19 +64,27 @@ impl Iterator for ThreadTestSuite {
 //
 // Rinse and repeat until you've got all the components of iproduct!()
 
-type PowerPermutations = Permutations<IterU64>;
+type PowerPermutations = Permutations<vec::IntoIter<CapLevel>>;
 type IterU64 = vec::IntoIter<u64>;
 
 type OrderOperation = Product<All<CappingOrder>, All<Operation>>;
 type OrderOperationStep = Product<OrderOperation, All<CapStep>>;
 type OrderOperationStepPower = Product<OrderOperationStepIter, PowerPermutations>;
 type OrderOperationStepPowerThreads = Product<OrderOperationStepPowerIter, IterU64>;
+type OrderOperationStepPowerThreadsCorrection = Product<OrderOperationStepPowerThreadsIter, IterU64>;
+type OrderOperationStepPowerThreadsCorrectionMechanism = Product<OrderOperationStepPowerThreadsCorrectionIter, All<CapMechanism>>;
 
 type OrderOperationStepTuple = ((CappingOrder, Operation), CapStep);
-type OrderOperationStepPowerTuple = ((CappingOrder, Operation, CapStep), Vec<u64>);
-type OrderOperationStepPowerThreadsTuple = ((CappingOrder, Operation, CapStep, Vec<u64>), u64);
+type OrderOperationStepPowerTuple = ((CappingOrder, Operation, CapStep), Vec<CapLevel>);
+type OrderOperationStepPowerThreadsTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>), u64);
+type OrderOperationStepPowerThreadsCorrectionTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>, u64), u64);
+type OrderOperationStepPowerThreadsCorrectionMechanismTuple = ((CappingOrder, Operation, CapStep, Vec<CapLevel>, u64, u64), CapMechanism);
 
 type OrderOperationStepIter = ConsTuples<OrderOperationStep, OrderOperationStepTuple>;
 type OrderOperationStepPowerIter = ConsTuples<OrderOperationStepPower, OrderOperationStepPowerTuple>;
 type OrderOperationStepPowerThreadsIter =
     ConsTuples<OrderOperationStepPowerThreads, OrderOperationStepPowerThreadsTuple>;
+type OrderOperationStepPowerThreadsCorrectionIter =
+    ConsTuples<OrderOperationStepPowerThreadsCorrection, OrderOperationStepPowerThreadsCorrectionTuple>;
+type OrderOperationStepPowerThreadsCorrectionMechanismIter =
+    ConsTuples<OrderOperationStepPowerThreadsCorrectionMechanism, OrderOperationStepPowerThreadsCorrectionMechanismTuple>;
@@ -0,0 +1,192 @@
+use crate::bmc::bmc::{BMC_CapSetting, BMC_PowerReading};
+use crate::bmc::controller::PowerCapController;
+use crate::bmc::error::{BMCError, BMCResult};
+use crate::route::{create_router, AgentState};
+use async_trait::async_trait;
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::sleep;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system temp dir, removed with everything in it when
+/// dropped - even when the test fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// `name` only tells the tests apart when one leaves something behind
+    pub fn new(name: &str) -> Self {
+        let unique = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("agent_{name}_{}_{unique}", std::process::id()));
+        // Left over by a killed run of a process with the same pid
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An executable `ipmitool` in `dir` running the shell `script`, which sees its
+/// arguments as `$*` and its own path as `$0`
+pub fn fake_ipmitool(dir: &TempDir, script: &str) -> PathBuf {
+    let path = dir.join("ipmitool");
+    fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+/// Serves the agent's routes for `state` on a free local port, returning its URL
+pub fn spawn_router(state: AgentState) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(create_router(state).into_make_service());
+    tokio::spawn(server);
+    format!("http://{address}")
+}
+
+/// A controller reporting a fixed power, with the quirks a test asks for. A cap change
+/// shows in the settings read back after `settings_lag` more reads.
+#[derive(Debug)]
+pub struct StubController {
+    power: u64,
+    read_delay: Duration,
+    flaky: bool,
+    power_reads: AtomicU64,
+    rolling_periods: Vec<Duration>,
+    failing_period: Option<Duration>,
+    settings_lag: u32,
+    locked_out: bool,
+    reads_left: AtomicU32,
+    requested: Mutex<BMC_CapSetting>,
+    reported: Mutex<BMC_CapSetting>,
+}
+
+impl StubController {
+    pub fn new(power: u64) -> Self {
+        Self {
+            power,
+            read_delay: Duration::ZERO,
+            flaky: false,
+            power_reads: AtomicU64::new(0),
+            rolling_periods: Vec::new(),
+            failing_period: None,
+            settings_lag: 0,
+            locked_out: false,
+            reads_left: AtomicU32::new(0),
+            requested: Mutex::new(BMC_CapSetting::default()),
+            reported: Mutex::new(BMC_CapSetting::default()),
+        }
+    }
+
+    pub fn with_cap_settings(self, settings: BMC_CapSetting) -> Self {
+        Self { requested: Mutex::new(settings), reported: Mutex::new(settings), ..self }
+    }
+
+    /// Every power reading, rolling averages included, takes `delay`
+    pub fn with_read_delay(self, delay: Duration) -> Self {
+        Self { read_delay: delay, ..self }
+    }
+
+    /// Every other power reading times out, starting with the first
+    pub fn flaky(self) -> Self {
+        Self { flaky: true, ..self }
+    }
+
+    /// Keeps rolling averages over `periods`, `failing_period` times out
+    pub fn with_rolling_averages(self, periods: &[Duration], failing_period: Option<Duration>) -> Self {
+        Self { rolling_periods: periods.to_vec(), failing_period, ..self }
+    }
+
+    pub fn with_settings_lag(self, lag: u32) -> Self {
+        Self { settings_lag: lag, ..self }
+    }
+
+    /// Reading the settings back fails with an authentication error
+    pub fn locked_out(self) -> Self {
+        Self { locked_out: true, ..self }
+    }
+
+    pub fn power_reads(&self) -> u64 {
+        self.power_reads.load(Ordering::SeqCst)
+    }
+
+    fn request(&self, change: impl FnOnce(&mut BMC_CapSetting)) -> BMCResult<()> {
+        change(&mut self.requested.lock().unwrap());
+        self.reads_left.store(self.settings_lag, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PowerCapController for StubController {
+    async fn current_power(&self) -> BMCResult<u64> {
+        sleep(self.read_delay).await;
+        let read = self.power_reads.fetch_add(1, Ordering::SeqCst);
+        if self.flaky && read % 2 == 0 {
+            return Err(BMCError::Timeout(String::from("no response")));
+        }
+        Ok(self.power)
+    }
+
+    async fn enhanced_power_periods(&self) -> BMCResult<Vec<Duration>> {
+        if self.rolling_periods.is_empty() {
+            return Err(BMCError::Unsupported(String::from("no enhanced power statistics")));
+        }
+        Ok(self.rolling_periods.clone())
+    }
+
+    async fn enhanced_power_reading(&self, period: Duration) -> BMCResult<BMC_PowerReading> {
+        sleep(self.read_delay).await;
+        if self.failing_period == Some(period) {
+            return Err(BMCError::Timeout(String::from("no response")));
+        }
+        Ok(BMC_PowerReading { average: 300, minimum: 250, maximum: 350, ..BMC_PowerReading::from_instant(self.power) })
+    }
+
+    async fn current_cap_settings(&self) -> BMCResult<BMC_CapSetting> {
+        if self.locked_out {
+            return Err(BMCError::Authentication(String::from("locked out")));
+        }
+        let reads_left = self.reads_left.load(Ordering::SeqCst);
+        if reads_left == 0 {
+            *self.reported.lock().unwrap() = *self.requested.lock().unwrap();
+        } else {
+            self.reads_left.store(reads_left - 1, Ordering::SeqCst);
+        }
+        Ok(*self.reported.lock().unwrap())
+    }
+
+    async fn set_cap_power_level(&self, cap: u64) -> BMCResult<()> {
+        self.request(|settings| settings.power_limit = cap)
+    }
+
+    async fn activate_power_cap(&self) -> BMCResult<()> {
+        self.request(|settings| settings.is_active = true)
+    }
+
+    async fn deactivate_power_cap(&self) -> BMCResult<()> {
+        self.request(|settings| settings.is_active = false)
+    }
+}